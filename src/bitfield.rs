@@ -0,0 +1,101 @@
+use miette::miette;
+
+/// The pieces a peer has, one bit per piece, high bit first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: u32,
+}
+
+impl Bitfield {
+    /// Returns an empty bitfield for the amount of pieces.
+    pub fn new(len: u32) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8) as usize],
+            len,
+        }
+    }
+
+    /// Returns a bitfield with all the pieces set.
+    pub fn full(len: u32) -> Self {
+        let mut bitfield = Self::new(len);
+        (0..len).for_each(|index| bitfield.set(index));
+        bitfield
+    }
+
+    /// Parses the payload of a bitfield message. The spare bits at the end
+    /// must be cleared.
+    pub fn from_bytes(bytes: Vec<u8>, len: u32) -> miette::Result<Self> {
+        let bitfield = Self { bytes, len };
+        if bitfield.bytes.len() != len.div_ceil(8) as usize {
+            return Err(miette!("invalid bitfield length {}", bitfield.bytes.len()));
+        }
+        if (len..len.div_ceil(8) * 8).any(|index| bitfield.get(index)) {
+            return Err(miette!("bitfield has spare bits set"));
+        }
+        Ok(bitfield)
+    }
+
+    /// Returns the payload of a bitfield message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns true if the piece is set.
+    pub fn has(&self, index: u32) -> bool {
+        index < self.len && self.get(index)
+    }
+
+    /// Sets the piece, out of range indexes are ignored.
+    pub fn set(&mut self, index: u32) {
+        if index < self.len {
+            self.bytes[index as usize / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Returns true if no piece is set.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.iter().all(|byte| *byte == 0)
+    }
+
+    /// Returns true if all the pieces are set.
+    pub fn is_full(&self) -> bool {
+        (0..self.len).all(|index| self.get(index))
+    }
+
+    fn get(&self, index: u32) -> bool {
+        self.bytes[index as usize / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitfield() {
+        let mut bitfield = Bitfield::new(10);
+        assert!(bitfield.is_empty());
+
+        bitfield.set(0);
+        bitfield.set(9);
+        bitfield.set(10);
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert!(!bitfield.is_full());
+
+        (0..10).for_each(|index| bitfield.set(index));
+        assert!(bitfield.is_full());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        assert!(Bitfield::from_bytes(vec![0xff, 0xc0], 10)
+            .unwrap()
+            .is_full());
+        // Wrong length, then a spare bit set
+        assert!(Bitfield::from_bytes(vec![0xff], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff, 0xe0], 10).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The interval between two rechokes.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// The optimistic unchoke rotates every third rechoke, so every 30 seconds.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+/// A peer which sent us no block for this long snubs us.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What the choker knows about a peer.
+struct PeerStats {
+    connected_at: Instant,
+    interested: bool,
+    /// The bytes received from the peer since the last rechoke.
+    downloaded: u64,
+    /// The bytes sent to the peer since the last rechoke.
+    uploaded: u64,
+    last_block: Instant,
+    /// The last time the peer got the optimistic slot.
+    last_optimistic: Option<Instant>,
+}
+
+/// Decides which peers we upload to, with tit-for-tat: the peers we
+/// download the fastest from get the regular slots, and one optimistic slot
+/// rotates between the other peers to find better ones. While seeding, the
+/// peers we upload the fastest to get the regular slots.
+///
+/// Time is always passed in, so the choker can run on a simulated clock.
+pub struct Choker {
+    slots: usize,
+    peers: HashMap<SocketAddr, PeerStats>,
+    unchoked: HashSet<SocketAddr>,
+    optimistic: Option<SocketAddr>,
+    rounds: u32,
+}
+
+impl Choker {
+    /// Returns a new [`Choker`] unchoking up to `slots` peers, the
+    /// optimistic one included.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            peers: HashMap::new(),
+            unchoked: HashSet::new(),
+            optimistic: None,
+            rounds: 0,
+        }
+    }
+
+    /// Returns the peers we upload to.
+    pub fn unchoked(&self) -> &HashSet<SocketAddr> {
+        &self.unchoked
+    }
+
+    pub fn add_peer(&mut self, address: SocketAddr, now: Instant) {
+        self.peers.insert(
+            address,
+            PeerStats {
+                connected_at: now,
+                interested: false,
+                downloaded: 0,
+                uploaded: 0,
+                last_block: now,
+                last_optimistic: None,
+            },
+        );
+    }
+
+    pub fn remove_peer(&mut self, address: &SocketAddr) {
+        self.peers.remove(address);
+        self.unchoked.remove(address);
+        if self.optimistic == Some(*address) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Records the interest of the peer. An interested peer is unchoked
+    /// right away while there are free slots, instead of waiting for the
+    /// next rechoke.
+    pub fn set_interested(&mut self, address: SocketAddr, interested: bool) {
+        let Some(peer) = self.peers.get_mut(&address) else {
+            return;
+        };
+        peer.interested = interested;
+        if !interested {
+            self.unchoked.remove(&address);
+        } else if self.unchoked.len() < self.slots {
+            self.unchoked.insert(address);
+        }
+    }
+
+    pub fn record_download(&mut self, address: SocketAddr, len: u64, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(&address) {
+            peer.downloaded += len;
+            peer.last_block = now;
+        }
+    }
+
+    pub fn record_upload(&mut self, address: SocketAddr, len: u64) {
+        if let Some(peer) = self.peers.get_mut(&address) {
+            peer.uploaded += len;
+        }
+    }
+
+    /// Picks the unchoked peers, to be called every [`RECHOKE_INTERVAL`].
+    /// Snubbing peers lose their regular slot while we download.
+    pub fn rechoke(&mut self, now: Instant, seeding: bool) {
+        let rate = |peer: &PeerStats| match seeding {
+            true => peer.uploaded,
+            false => peer.downloaded,
+        };
+        let snubbed =
+            |peer: &PeerStats| !seeding && now.duration_since(peer.last_block) >= SNUB_TIMEOUT;
+
+        // Faster first, then the oldest connections
+        let mut candidates = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.interested && !snubbed(peer))
+            .collect::<Vec<_>>();
+        candidates
+            .sort_by_key(|(address, peer)| (Reverse(rate(peer)), peer.connected_at, **address));
+        let regular = candidates
+            .iter()
+            .take(self.slots.saturating_sub(1))
+            .map(|(address, _)| **address)
+            .collect::<HashSet<_>>();
+
+        // The optimistic slot rotates every few rounds, or as soon as its
+        // peer loses interest
+        let optimistic_valid = self
+            .optimistic
+            .and_then(|address| self.peers.get(&address))
+            .is_some_and(|peer| peer.interested);
+        if self.rounds.is_multiple_of(OPTIMISTIC_ROUNDS) || !optimistic_valid {
+            self.optimistic = self.pick_optimistic(&regular, now);
+        }
+        if self
+            .optimistic
+            .is_some_and(|address| regular.contains(&address))
+        {
+            self.optimistic = self.pick_optimistic(&regular, now);
+        }
+
+        self.unchoked = regular;
+        self.unchoked.extend(self.optimistic);
+        self.rounds += 1;
+        for peer in self.peers.values_mut() {
+            peer.downloaded = 0;
+            peer.uploaded = 0;
+        }
+    }
+
+    /// Picks the interested peer out of the regular slots which waited the
+    /// longest for the optimistic slot. Peers which never had it come
+    /// first, the newest ones before the others so they get pieces to
+    /// trade.
+    fn pick_optimistic(
+        &mut self,
+        regular: &HashSet<SocketAddr>,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        let (address, peer) = self
+            .peers
+            .iter_mut()
+            .filter(|(address, peer)| peer.interested && !regular.contains(address))
+            .min_by_key(|(address, peer)| {
+                (peer.last_optimistic, Reverse(peer.connected_at), **address)
+            })?;
+        peer.last_optimistic = Some(now);
+        Some(*address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// A choker with 3 slots and interested peers 1 to `count`, connected
+    /// one second apart.
+    fn choker(count: u16, start: Instant) -> Choker {
+        let mut choker = Choker::new(3);
+        for port in 1..=count {
+            choker.add_peer(peer(port), start + Duration::from_secs(port as u64));
+            choker.set_interested(peer(port), true);
+        }
+        choker
+    }
+
+    fn unchoked(choker: &Choker) -> Vec<u16> {
+        let mut ports = choker
+            .unchoked()
+            .iter()
+            .map(|a| a.port())
+            .collect::<Vec<_>>();
+        ports.sort();
+        ports
+    }
+
+    #[test]
+    fn test_free_slots() {
+        let start = Instant::now();
+        let mut choker = choker(4, start);
+
+        // The first peers take the free slots before any rechoke
+        assert_eq!(unchoked(&choker), vec![1, 2, 3]);
+
+        choker.set_interested(peer(2), false);
+        assert_eq!(unchoked(&choker), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_tit_for_tat() {
+        let start = Instant::now();
+        let mut choker = choker(5, start);
+        let now = start + RECHOKE_INTERVAL;
+        choker.record_download(peer(4), 3000, now);
+        choker.record_download(peer(2), 2000, now);
+        choker.record_download(peer(1), 1000, now);
+
+        choker.rechoke(now, false);
+
+        // 4 and 2 are the fastest, the newest peer gets the optimistic slot
+        assert_eq!(unchoked(&choker), vec![2, 4, 5]);
+    }
+
+    #[test]
+    fn test_seeding_uses_upload_rate() {
+        let start = Instant::now();
+        let mut choker = choker(4, start);
+        choker.record_download(peer(1), 5000, start);
+        choker.record_upload(peer(3), 2000);
+        choker.record_upload(peer(2), 1000);
+
+        choker.rechoke(start + RECHOKE_INTERVAL, true);
+
+        assert_eq!(unchoked(&choker), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_optimistic_rotation() {
+        let start = Instant::now();
+        let mut choker = choker(5, start);
+        let mut optimistic = vec![];
+
+        for round in 1..=9 {
+            let now = start + RECHOKE_INTERVAL * round;
+            // 1 and 2 keep the regular slots
+            choker.record_download(peer(1), 2000, now);
+            choker.record_download(peer(2), 1000, now);
+            choker.rechoke(now, false);
+            optimistic.push(choker.optimistic.unwrap().port());
+        }
+
+        // Rotates every 30 seconds, newest peers first
+        assert_eq!(optimistic, vec![5, 5, 5, 4, 4, 4, 3, 3, 3]);
+    }
+
+    #[test]
+    fn test_anti_snubbing() {
+        let start = Instant::now();
+        let mut choker = choker(4, start);
+        let now = start + SNUB_TIMEOUT + RECHOKE_INTERVAL;
+        // Peer 1 was the fastest, but sent nothing for a minute
+        choker.record_download(peer(1), 9000, start + Duration::from_secs(1));
+        choker.record_download(peer(2), 100, now);
+        choker.record_download(peer(3), 100, now);
+
+        choker.rechoke(now, false);
+
+        assert_eq!(choker.optimistic, Some(peer(4)));
+        assert_eq!(unchoked(&choker), vec![2, 3, 4]);
+    }
+}
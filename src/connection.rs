@@ -0,0 +1,807 @@
+use crate::bitfield::Bitfield;
+use crate::download::Download;
+use crate::fast::{allowed_fast_set, ALLOWED_FAST_COUNT};
+use crate::handshake::Extensions;
+use crate::message::{read_message, write_message, BlockRequest, Message};
+use crate::protocol::{BitTorrentStream, PeerWriter, SIXTEEN_KILO_BYTES};
+use crate::rate_limit::{self, RateLimits};
+use miette::miette;
+use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{sleep_until, Instant};
+
+/// The maximum amount of blocks requested and not received yet.
+const MAX_PENDING_REQUESTS: usize = 10;
+
+/// The timeouts of the connections to the peers and web seeds of a
+/// download.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// A keep-alive is sent when nothing else was for this long.
+    pub keep_alive: Duration,
+    /// A peer which sent nothing for this long is dropped.
+    pub idle: Duration,
+    /// A peer which sent none of the requested blocks for this long is
+    /// dropped.
+    pub request: Duration,
+    /// The delay before dialing a failed peer again, doubled on each
+    /// failure.
+    pub retry_backoff: Duration,
+    /// The delay before fetching from a failing web seed again, doubled on
+    /// each failure.
+    pub web_seed_backoff: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            keep_alive: Duration::from_secs(120),
+            idle: Duration::from_secs(180),
+            request: Duration::from_secs(60),
+            retry_backoff: Duration::from_secs(15),
+            web_seed_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A piece being downloaded from the peer.
+struct PieceDownload {
+    index: u32,
+    data: Vec<u8>,
+    /// The blocks left to request, in order.
+    blocks: VecDeque<BlockRequest>,
+    /// The blocks requested and not received yet.
+    requested: Vec<BlockRequest>,
+    /// The amount of bytes received.
+    received: u32,
+    /// When the last block was received, or the piece started.
+    last_block: Instant,
+}
+
+/// A connection to a peer, after the handshake. Pieces are downloaded from
+/// the peer and requests of the peer are served, both for the same
+/// download.
+struct Connection {
+    download: Arc<Download>,
+    address: SocketAddr,
+    writer: PeerWriter,
+    /// The pieces the peer has.
+    bitfield: Bitfield,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    piece: Option<PieceDownload>,
+    /// The requests of the peer, served in order.
+    uploads: VecDeque<BlockRequest>,
+    /// True when both sides support the Fast Extension.
+    fast: bool,
+    /// The pieces the peer may request while choked.
+    allowed_fast: Vec<u32>,
+    /// The pieces we may request while choked.
+    peer_allowed_fast: HashSet<u32>,
+    /// The pieces the peer suggested we download.
+    suggested: VecDeque<u32>,
+    timeouts: Timeouts,
+    last_sent: Instant,
+    last_received: Instant,
+    /// Set once a piece of the peer is verified or a block is sent to it.
+    progress: bool,
+}
+
+/// Runs the connection until the download stops, both sides have all the
+/// pieces or the peer misbehaves. We upload to the peer while the choker of
+/// the download unchokes it. The extensions are the ones the peer
+/// advertised in its handshake. `progress` is set if the peer was useful,
+/// whatever the result.
+pub async fn run(
+    download: Arc<Download>,
+    address: SocketAddr,
+    stream: BitTorrentStream,
+    extensions: Extensions,
+    progress: &mut bool,
+) -> miette::Result<()> {
+    // Messages are read in their own task, a partially read message would
+    // be lost when another branch of the select completes first
+    let (mut reader, writer) = stream.into_split();
+    let (sender, mut messages) = mpsc::channel(32);
+    let limits = download.peer_limits(address);
+    let reading = download.clone();
+    let reader = tokio::spawn(async move {
+        while let Ok(message) = read_message(&mut reader).await {
+            // Reading stops while over the download rate, which slows the
+            // peer down
+            if let Message::Piece { block, .. } = &message {
+                let limiters = [
+                    &RateLimits::global().download,
+                    &reading.limits().download,
+                    &limits.download,
+                ];
+                rate_limit::acquire(&limiters, block.len() as u64).await;
+            }
+            if sender.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut haves = download.haves();
+    let mut stopped = download.stopped();
+    let mut unchoked = download.unchoked();
+    let timeouts = download.timeouts();
+    let mut connection = Connection {
+        bitfield: Bitfield::new(download.torrent().info.pieces_count()),
+        download,
+        address,
+        writer,
+        am_choking: true,
+        am_interested: false,
+        peer_choking: true,
+        piece: None,
+        uploads: VecDeque::new(),
+        fast: extensions.fast,
+        allowed_fast: vec![],
+        peer_allowed_fast: HashSet::new(),
+        suggested: VecDeque::new(),
+        timeouts,
+        last_sent: Instant::now(),
+        last_received: Instant::now(),
+        progress: false,
+    };
+
+    let res = async {
+        connection.send_pieces().await?;
+        connection.send_allowed_fast().await?;
+
+        loop {
+            if connection.download.is_complete() && connection.bitfield.is_full() {
+                return Ok(());
+            }
+            connection.update_interest().await?;
+            connection.request_blocks().await?;
+
+            tokio::select! {
+                message = messages.recv() => {
+                    let message = message.ok_or(miette!("connection closed"))?;
+                    connection.last_received = Instant::now();
+                    connection.handle(message).await?;
+                }
+                _ = sleep_until(connection.deadline()) => connection.check_timeouts().await?,
+                index = haves.recv() => match index {
+                    Ok(index) => connection.send(Message::Have(index)).await?,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = wait_stopped(&mut stopped) => return Ok(()),
+                res = unchoked.changed() => {
+                    if res.is_ok() {
+                        let choke = !unchoked.borrow_and_update().contains(&address);
+                        connection.set_choking(choke).await?;
+                    }
+                }
+                _ = std::future::ready(()), if !connection.uploads.is_empty() => {
+                    connection.serve().await?;
+                }
+            }
+        }
+    }
+    .await;
+
+    reader.abort();
+    *progress = connection.progress;
+    if let Some(piece) = connection.piece.take() {
+        connection.download.release_piece(piece.index);
+    }
+    res
+}
+
+/// Waits until the download is stopped, or dropped.
+pub async fn wait_stopped(stopped: &mut watch::Receiver<bool>) {
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+}
+
+impl Connection {
+    async fn send(&mut self, message: Message) -> miette::Result<()> {
+        self.last_sent = Instant::now();
+        write_message(&mut self.writer, &message).await
+    }
+
+    /// Returns when the next keep-alive or timeout is due.
+    fn deadline(&self) -> Instant {
+        let request_deadline = self
+            .piece
+            .as_ref()
+            .filter(|piece| !piece.requested.is_empty())
+            .map(|piece| piece.last_block + self.timeouts.request);
+        [
+            Some(self.last_sent + self.timeouts.keep_alive),
+            Some(self.last_received + self.timeouts.idle),
+            request_deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .expect("deadlines aren't empty")
+    }
+
+    /// Drops idle peers and peers ignoring our requests, and keeps the
+    /// connection alive.
+    async fn check_timeouts(&mut self) -> miette::Result<()> {
+        let now = Instant::now();
+        if now >= self.last_received + self.timeouts.idle {
+            return Err(miette!("peer is idle"));
+        }
+        if let Some(piece) = &self.piece {
+            if !piece.requested.is_empty() && now >= piece.last_block + self.timeouts.request {
+                return Err(miette!("requests for piece {} timed out", piece.index));
+            }
+        }
+        if now >= self.last_sent + self.timeouts.keep_alive {
+            self.send(Message::KeepAlive).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, message: Message) -> miette::Result<()> {
+        match message {
+            Message::KeepAlive | Message::Unknown(..) => {}
+            Message::Choke => {
+                // Pending requests are dropped by the peer, or rejected one
+                // by one with the Fast Extension
+                self.peer_choking = true;
+                if !self.fast {
+                    if let Some(piece) = self.piece.take() {
+                        self.download.release_piece(piece.index);
+                    }
+                }
+            }
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.download.peer_interested(self.address, true),
+            Message::NotInterested => self.download.peer_interested(self.address, false),
+            Message::Have(index) => self.bitfield.set(index),
+            Message::Bitfield(bytes) => {
+                self.bitfield = Bitfield::from_bytes(bytes, self.bitfield_len())?;
+            }
+            Message::Request(request) => {
+                // Requests while choked are dropped, or rejected with the
+                // Fast Extension, except for the allowed fast pieces
+                let allowed =
+                    !self.am_choking || (self.fast && self.allowed_fast.contains(&request.index));
+                if allowed && !self.uploads.contains(&request) {
+                    self.uploads.push_back(request);
+                } else if !allowed && self.fast {
+                    self.send(Message::RejectRequest(request)).await?;
+                }
+            }
+            Message::Cancel(request) => {
+                let position = self.uploads.iter().position(|upload| *upload == request);
+                if let Some(position) = position {
+                    self.uploads.remove(position);
+                    // A cancelled request is rejected with the Fast Extension
+                    if self.fast {
+                        self.send(Message::RejectRequest(request)).await?;
+                    }
+                }
+            }
+            Message::HaveAll => {
+                self.require_fast()?;
+                self.bitfield = Bitfield::full(self.bitfield_len());
+            }
+            Message::HaveNone => {
+                self.require_fast()?;
+                self.bitfield = Bitfield::new(self.bitfield_len());
+            }
+            Message::SuggestPiece(index) => {
+                self.require_fast()?;
+                if !self.suggested.contains(&index) {
+                    self.suggested.push_back(index);
+                }
+            }
+            Message::AllowedFast(index) => {
+                self.require_fast()?;
+                self.peer_allowed_fast.insert(index);
+            }
+            Message::RejectRequest(request) => {
+                self.require_fast()?;
+                self.reject_block(request);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => self.receive_block(index, begin, block)?,
+            // The piece layers come with the torrent, we never ask for hashes
+            Message::HashRequest(request) => {
+                let message = match self.download.torrent().info.hashes(&request) {
+                    Some(hashes) => Message::Hashes(request, hashes),
+                    None => Message::HashReject(request),
+                };
+                self.send(message).await?;
+            }
+            Message::Hashes(..) | Message::HashReject(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Errors for a Fast Extension message when it wasn't negotiated.
+    fn require_fast(&self) -> miette::Result<()> {
+        match self.fast {
+            true => Ok(()),
+            false => Err(miette!("fast extension message without the extension")),
+        }
+    }
+
+    /// Tells the peer the pieces we have. With the Fast Extension, the
+    /// common cases of all or no pieces have their own messages.
+    async fn send_pieces(&mut self) -> miette::Result<()> {
+        let bitfield = self.download.bitfield();
+        if self.fast && bitfield.is_full() {
+            self.send(Message::HaveAll).await
+        } else if bitfield.is_empty() {
+            match self.fast {
+                true => self.send(Message::HaveNone).await,
+                false => Ok(()),
+            }
+        } else {
+            self.send(Message::Bitfield(bitfield.as_bytes().to_vec()))
+                .await
+        }
+    }
+
+    /// Sends the pieces the peer may request while choked, so new peers
+    /// get their first pieces quickly. The set is only defined for IPv4.
+    async fn send_allowed_fast(&mut self) -> miette::Result<()> {
+        let IpAddr::V4(ip) = self.address.ip().to_canonical() else {
+            return Ok(());
+        };
+        if !self.fast {
+            return Ok(());
+        }
+        let info_hash = self.download.torrent().raw_info_hash();
+        self.allowed_fast =
+            allowed_fast_set(ip, &info_hash, self.bitfield_len(), ALLOWED_FAST_COUNT);
+        for index in self.allowed_fast.clone() {
+            self.send(Message::AllowedFast(index)).await?;
+        }
+        Ok(())
+    }
+
+    /// Chokes or unchokes the peer, dropping its requests on choke. With
+    /// the Fast Extension, the requests are rejected instead and the ones
+    /// for allowed fast pieces are kept.
+    async fn set_choking(&mut self, choke: bool) -> miette::Result<()> {
+        if choke == self.am_choking {
+            return Ok(());
+        }
+        self.am_choking = choke;
+        if !choke {
+            return self.send(Message::Unchoke).await;
+        }
+
+        self.send(Message::Choke).await?;
+        let uploads = std::mem::take(&mut self.uploads);
+        for request in uploads {
+            if !self.fast {
+                continue;
+            }
+            if self.allowed_fast.contains(&request.index) {
+                self.uploads.push_back(request);
+            } else {
+                self.send(Message::RejectRequest(request)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn bitfield_len(&self) -> u32 {
+        self.download.torrent().info.pieces_count()
+    }
+
+    /// Tells the peer whether it has pieces we want.
+    async fn update_interest(&mut self) -> miette::Result<()> {
+        let interested = self.piece.is_some() || self.download.wants_any(&self.bitfield);
+        if interested != self.am_interested {
+            self.am_interested = interested;
+            let message = match interested {
+                true => Message::Interested,
+                false => Message::NotInterested,
+            };
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Requests blocks until the pipeline is full. While choked, only the
+    /// allowed fast pieces are requested.
+    async fn request_blocks(&mut self) -> miette::Result<()> {
+        if self.piece.is_none() {
+            let Some(index) = self.pick_piece() else {
+                return Ok(());
+            };
+            let len = self.download.torrent().info.piece_len(index);
+            let blocks = (0..len)
+                .step_by(SIXTEEN_KILO_BYTES as usize)
+                .map(|begin| BlockRequest {
+                    index,
+                    begin,
+                    length: SIXTEEN_KILO_BYTES.min(len - begin),
+                })
+                .collect();
+            self.piece = Some(PieceDownload {
+                index,
+                data: vec![0; len as usize],
+                blocks,
+                requested: vec![],
+                received: 0,
+                last_block: Instant::now(),
+            });
+        }
+
+        let piece = self.piece.as_mut().expect("piece is set");
+        if self.peer_choking && !self.peer_allowed_fast.contains(&piece.index) {
+            return Ok(());
+        }
+        let mut requests = vec![];
+        while piece.requested.len() < MAX_PENDING_REQUESTS {
+            let Some(request) = piece.blocks.pop_front() else {
+                break;
+            };
+            piece.requested.push(request);
+            requests.push(request);
+        }
+        for request in requests {
+            self.send(Message::Request(request)).await?;
+        }
+        Ok(())
+    }
+
+    /// Takes the next piece to download from the peer, the suggested ones
+    /// first.
+    fn pick_piece(&mut self) -> Option<u32> {
+        let mut available = self.bitfield.clone();
+        if self.peer_choking {
+            available = Bitfield::new(self.bitfield_len());
+            for index in &self.peer_allowed_fast {
+                if self.bitfield.has(*index) {
+                    available.set(*index);
+                }
+            }
+        }
+
+        while let Some(index) = self.suggested.pop_front() {
+            if available.has(index) && self.download.take_piece(index) {
+                return Some(index);
+            }
+        }
+        self.download.next_piece(&available)
+    }
+
+    /// Puts a rejected block back to be requested again. A piece which
+    /// can't be requested anymore while choked is released.
+    fn reject_block(&mut self, request: BlockRequest) {
+        let Some(piece) = self.piece.as_mut() else {
+            return;
+        };
+        let Some(position) = piece.requested.iter().position(|r| *r == request) else {
+            return;
+        };
+        piece.requested.swap_remove(position);
+        piece.blocks.push_front(request);
+
+        let blocked = self.peer_choking && !self.peer_allowed_fast.contains(&piece.index);
+        if blocked && piece.requested.is_empty() {
+            let piece = self.piece.take().expect("piece is set");
+            self.download.release_piece(piece.index);
+        }
+    }
+
+    /// Stores a requested block, completing the piece once all the blocks
+    /// are received.
+    fn receive_block(&mut self, index: u32, begin: u32, block: Vec<u8>) -> miette::Result<()> {
+        let Some(piece) = self.piece.as_mut() else {
+            return Ok(());
+        };
+        let request = BlockRequest {
+            index,
+            begin,
+            length: block.len() as u32,
+        };
+        // Blocks that weren't requested, or arrive after a choke, are dropped
+        let Some(position) = piece.requested.iter().position(|r| *r == request) else {
+            return Ok(());
+        };
+        piece.requested.swap_remove(position);
+        piece.last_block = Instant::now();
+        self.download
+            .block_received(self.address, request.length as u64);
+        piece.data[begin as usize..][..block.len()].copy_from_slice(&block);
+        piece.received += request.length;
+
+        if piece.received == piece.data.len() as u32 {
+            let piece = self.piece.take().expect("piece is set");
+            if !self.download.verify(piece.index, &piece.data) {
+                self.download.release_piece(piece.index);
+                return Err(miette!("piece {} failed verification", piece.index));
+            }
+            self.download.complete_piece(piece.index, piece.data);
+            self.progress = true;
+        }
+        Ok(())
+    }
+
+    /// Sends the first requested block.
+    async fn serve(&mut self) -> miette::Result<()> {
+        let request = self.uploads.pop_front().expect("uploads aren't empty");
+        if request.length > SIXTEEN_KILO_BYTES {
+            return Err(miette!("requested block of {} bytes", request.length));
+        }
+        // Pieces we don't have yet are not served
+        let Some(block) = self.download.read_block(&request)? else {
+            if self.fast {
+                self.send(Message::RejectRequest(request)).await?;
+            }
+            return Ok(());
+        };
+        let limits = self.download.peer_limits(self.address);
+        let limiters = [
+            &RateLimits::global().upload,
+            &self.download.limits().upload,
+            &limits.upload,
+        ];
+        rate_limit::acquire(&limiters, block.len() as u64).await;
+        self.send(Message::Piece {
+            index: request.index,
+            begin: request.begin,
+            block,
+        })
+        .await?;
+        self.download
+            .block_sent(self.address, request.length as u64);
+        self.progress = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peers::Peers;
+    use crate::torrent::Torrent;
+    use tokio::net::TcpListener;
+
+    const PIECE_LENGTH: usize = 40000;
+    const TIMEOUTS: Timeouts = Timeouts {
+        keep_alive: Duration::from_millis(100),
+        idle: Duration::from_millis(2000),
+        request: Duration::from_millis(1000),
+        retry_backoff: Duration::from_millis(10),
+        web_seed_backoff: Duration::from_millis(10),
+    };
+
+    /// Returns a seeder of the data accepting one connection, and its
+    /// address.
+    async fn seeder(torrent: Arc<Torrent>, data: &[u8]) -> (Arc<Download>, SocketAddr) {
+        let seeder = Download::new(torrent, [1u8; 20]);
+        for (index, piece) in data.chunks(PIECE_LENGTH).enumerate() {
+            seeder.complete_piece(index as u32, piece.to_vec());
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let serving = seeder.clone();
+        tokio::spawn(async move {
+            let (stream, address) = listener.accept().await.unwrap();
+            let mut stream = BitTorrentStream::from_transport(Box::new(stream));
+            let handshake = stream
+                .handshake(serving.torrent(), [1u8; 20])
+                .await
+                .unwrap();
+            serving.add_incoming(address, stream, handshake.extensions());
+        });
+        (seeder, address)
+    }
+
+    #[tokio::test]
+    async fn test_seed_to_leecher() {
+        let data = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let torrent = Arc::new(Torrent::with_files(&data, PIECE_LENGTH as u32, vec![]));
+        let (seeder, address) = seeder(torrent.clone(), &data).await;
+
+        let leecher = Download::new(torrent, [2u8; 20]);
+        leecher.add_peers(&Peers(vec![address]));
+        tokio::time::timeout(Duration::from_secs(5), leecher.wait_complete())
+            .await
+            .unwrap();
+
+        assert_eq!(leecher.data().unwrap(), data);
+        assert_eq!(leecher.downloaded(), data.len() as u64);
+        // The seeder counts a block once it is sent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(seeder.uploaded(), data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_upload_rate_limit() {
+        let data = vec![3u8; 100_000];
+        let torrent = Arc::new(Torrent::with_files(&data, PIECE_LENGTH as u32, vec![]));
+        let (seeder, address) = seeder(torrent.clone(), &data).await;
+        // The bucket starts empty, so the data takes a second
+        seeder.limits().upload.set_rate(Some(100_000));
+
+        let start = std::time::Instant::now();
+        let leecher = Download::new(torrent, [2u8; 20]);
+        leecher.add_peers(&Peers(vec![address]));
+        tokio::time::timeout(Duration::from_secs(5), leecher.wait_complete())
+            .await
+            .unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(800));
+    }
+
+    /// Runs a connection with a fake peer which sends the messages, then
+    /// keeps sending keep-alives if asked, and closes the connection after
+    /// the duration. Returns the result of the connection and the messages
+    /// we sent.
+    async fn fake_peer(
+        extensions: Extensions,
+        messages: Vec<Message>,
+        keep_alive: bool,
+        duration: Duration,
+    ) -> (miette::Result<()>, Vec<Message>) {
+        let data = vec![5u8; 1000];
+        let download = Download::new(
+            Arc::new(Torrent::with_files(&data, PIECE_LENGTH as u32, vec![])),
+            [1u8; 20],
+        );
+        download.set_timeouts(TIMEOUTS);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let peer = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            for message in messages {
+                write_message(&mut writer, &message).await.unwrap();
+            }
+            let keep_alives = tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    if keep_alive {
+                        let _ = write_message(&mut writer, &Message::KeepAlive).await;
+                    }
+                }
+            });
+            let mut received = vec![];
+            let _ = tokio::time::timeout(duration, async {
+                while let Ok(message) = read_message(&mut reader).await {
+                    received.push(message);
+                }
+            })
+            .await;
+            keep_alives.abort();
+            received
+        });
+
+        let stream = BitTorrentStream::new(address).await.unwrap();
+        let res = run(download, address, stream, extensions, &mut false).await;
+        (res, peer.await.unwrap())
+    }
+
+    const FAST: Extensions = Extensions {
+        dht: false,
+        fast: true,
+        extension_protocol: false,
+    };
+
+    #[tokio::test]
+    async fn test_idle_peer() {
+        let messages = vec![Message::Bitfield(vec![0x80])];
+        let (res, received) = fake_peer(
+            Extensions::default(),
+            messages,
+            false,
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert_eq!(res.unwrap_err().to_string(), "peer is idle");
+        // Interested, then keep-alives until the peer is dropped
+        assert_eq!(received[0], Message::Interested);
+        assert!(received.len() > 5);
+        assert!(received[1..].iter().all(|m| *m == Message::KeepAlive));
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let messages = vec![Message::Bitfield(vec![0x80]), Message::Unchoke];
+        let (res, received) = fake_peer(
+            Extensions::default(),
+            messages,
+            true,
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "requests for piece 0 timed out"
+        );
+        assert!(received.contains(&Message::Request(BlockRequest {
+            index: 0,
+            begin: 0,
+            length: 1000
+        })));
+    }
+
+    #[tokio::test]
+    async fn test_fast_messages_need_the_extension() {
+        let (res, _) = fake_peer(
+            Extensions::default(),
+            vec![Message::HaveAll],
+            true,
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_allowed_fast_while_choked() {
+        let messages = vec![Message::HaveAll, Message::AllowedFast(0)];
+        let (_, received) = fake_peer(FAST, messages, true, Duration::from_millis(300)).await;
+
+        // We have nothing, and the peer gets its allowed fast set
+        assert_eq!(received[0], Message::HaveNone);
+        assert!(received.contains(&Message::AllowedFast(0)));
+        // The piece is requested without waiting for an unchoke
+        assert!(received.contains(&Message::Request(BlockRequest {
+            index: 0,
+            begin: 0,
+            length: 1000
+        })));
+    }
+
+    #[tokio::test]
+    async fn test_reject_while_choking() {
+        let request = BlockRequest {
+            index: 0,
+            begin: 0,
+            length: 1000,
+        };
+        let messages = vec![
+            Message::HaveNone,
+            Message::Interested,
+            Message::Request(request),
+        ];
+        let (_, received) = fake_peer(FAST, messages, true, Duration::from_millis(300)).await;
+
+        // Piece 0 is in the allowed fast set of a torrent with one piece,
+        // but we don't have it
+        assert!(received.contains(&Message::RejectRequest(request)));
+    }
+
+    #[test]
+    fn test_read_block() {
+        let data = vec![7u8; 50_000];
+        let download = Download::new(
+            Arc::new(Torrent::with_files(&data, PIECE_LENGTH as u32, vec![])),
+            [1u8; 20],
+        );
+        download.complete_piece(1, data[PIECE_LENGTH..].to_vec());
+
+        let request = |index, begin, length| BlockRequest {
+            index,
+            begin,
+            length,
+        };
+        assert_eq!(download.read_block(&request(0, 0, 16384)).unwrap(), None);
+        assert_eq!(
+            download.read_block(&request(1, 0, 10_000)).unwrap(),
+            Some(vec![7u8; 10_000])
+        );
+        assert!(download.read_block(&request(1, 1, 10_000)).is_err());
+        assert!(download.read_block(&request(2, 0, 1)).is_err());
+    }
+}
@@ -118,6 +118,24 @@ impl<'a> Decoder<'a> {
         }
     }
 
+    /// Returns the value of the key in the dictionary at the cursor, as it
+    /// was encoded. Hashes are computed over the original bytes.
+    pub fn raw_value(&mut self, key: &str) -> Option<&'a [u8]> {
+        if !self.cursor.starts_with(b"d") {
+            return None;
+        }
+        self.advance_one();
+        while let Ok(found) = self.decode() {
+            let start = self.full.len() - self.cursor.len();
+            self.decode().ok()?;
+            if found.as_str() == Some(key) {
+                let end = self.full.len() - self.cursor.len();
+                return Some(&self.full[start..end]);
+            }
+        }
+        None
+    }
+
     /// Returns an error if the char at the cursor isn't an 'e'.
     fn assert_next_terminator(&mut self) -> Result<()> {
         if !self.cursor.starts_with(b"e") {
@@ -259,4 +277,15 @@ mod tests {
         );
         assert_eq!(decoder.cursor, b"");
     }
+
+    #[test]
+    fn test_raw_value() {
+        let input = b"d8:announce3:url4:infod6:lengthi5e4:name1:aee";
+        assert_eq!(
+            Decoder::new(input).raw_value("info"),
+            Some(&b"d6:lengthi5e4:name1:ae"[..])
+        );
+        assert_eq!(Decoder::new(input).raw_value("nodes"), None);
+        assert_eq!(Decoder::new(b"l4:infoe").raw_value("info"), None);
+    }
 }
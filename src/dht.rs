@@ -0,0 +1,572 @@
+mod krpc;
+mod routing;
+mod token;
+
+use crate::dht::krpc::{
+    as_v4, decode_nodes, decode_peer, encode_nodes, encode_peer, Arguments, Message, Query,
+    Response,
+};
+use crate::dht::routing::{RoutingTable, K};
+use crate::dht::token::Tokens;
+use crate::utp::{Datagram, UtpSocket};
+use miette::miette;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+pub use crate::dht::routing::NodeId;
+
+/// Well known nodes used to join the DHT.
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+/// The amount of concurrent queries during a lookup.
+const ALPHA: usize = 3;
+/// The time after which a query is considered failed.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Announced peers are forgotten after this duration.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// The maximum amount of peers returned in a `get_peers` response.
+const MAX_VALUES: usize = 50;
+
+/// A node of the mainline DHT (BEP 5). Answers the queries of other
+/// nodes in the background and performs lookups for peers.
+pub struct Dht {
+    state: Arc<State>,
+    task: JoinHandle<()>,
+}
+
+/// The state of the node, shared with the task answering queries.
+struct State {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    routing: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<Vec<u8>, oneshot::Sender<Message>>>,
+    peers: Mutex<HashMap<NodeId, Vec<(SocketAddrV4, Instant)>>>,
+    tokens: Mutex<Tokens>,
+    next_transaction: AtomicU16,
+}
+
+/// Where the datagrams of the node come from.
+enum Datagrams {
+    /// The node reads its own socket.
+    Socket,
+    /// The socket is shared, its other datagrams are handed to the node.
+    Shared(mpsc::Receiver<Datagram>),
+}
+
+/// The result of an iterative lookup.
+struct Lookup {
+    peers: HashSet<SocketAddrV4>,
+    /// The closest nodes that responded, with the token they handed out.
+    closest: Vec<(NodeId, SocketAddrV4, Option<ByteBuf>)>,
+}
+
+impl Dht {
+    /// Binds a new DHT node with a random id to the provided address.
+    pub async fn bind(addr: impl ToSocketAddrs) -> miette::Result<Self> {
+        let socket = UdpSocket::bind(addr).await.map_err(|err| miette!(err))?;
+        Ok(Self::with_socket(Arc::new(socket), Datagrams::Socket))
+    }
+
+    /// Returns a new DHT node with a random id on the socket.
+    fn with_socket(socket: Arc<UdpSocket>, datagrams: Datagrams) -> Self {
+        let id = NodeId::random();
+        let state = Arc::new(State {
+            id,
+            socket,
+            routing: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(Tokens::new()),
+            next_transaction: AtomicU16::new(0),
+        });
+        let task = tokio::spawn(state.clone().run(datagrams));
+        Self { state, task }
+    }
+
+    /// Binds a node on any port and joins the DHT through the node cache,
+    /// the default bootstrap nodes and the provided extra nodes.
+    pub async fn start(extra_nodes: &[String]) -> miette::Result<Self> {
+        let dht = Self::bind("0.0.0.0:0").await?;
+        dht.join(extra_nodes).await?;
+        Ok(dht)
+    }
+
+    /// Starts a node sharing the socket of the uTP connections, then joins
+    /// the DHT as [`Dht::start`] does.
+    pub async fn start_shared(utp: &UtpSocket, extra_nodes: &[String]) -> miette::Result<Self> {
+        let datagrams = utp
+            .take_datagrams()
+            .ok_or(miette!("the socket is already shared"))?;
+        let dht = Self::with_socket(utp.socket(), Datagrams::Shared(datagrams));
+        dht.join(extra_nodes).await?;
+        Ok(dht)
+    }
+
+    async fn join(&self, extra_nodes: &[String]) -> miette::Result<()> {
+        let cache = Self::node_cache_path();
+        let _ = self.load_nodes(&cache).await;
+
+        let mut routers = BOOTSTRAP_NODES.map(String::from).to_vec();
+        routers.extend_from_slice(extra_nodes);
+        self.bootstrap(&routers).await?;
+        let _ = self.save_nodes(&cache);
+        Ok(())
+    }
+
+    /// Returns the default path of the node cache.
+    pub fn node_cache_path() -> PathBuf {
+        std::env::temp_dir().join("bittorrent-dht-nodes.dat")
+    }
+
+    /// Returns the amount of nodes in the routing table.
+    pub fn nodes_count(&self) -> usize {
+        self.state.routing.lock().expect("poisoned lock").len()
+    }
+
+    /// Joins the DHT by querying the provided nodes for our own id, then
+    /// looking up the nodes closest to us.
+    pub async fn bootstrap(&self, routers: &[String]) -> miette::Result<()> {
+        let mut addrs = vec![];
+        for router in routers {
+            if let Ok(resolved) = lookup_host(router.as_str()).await {
+                addrs.extend(resolved.filter_map(as_v4));
+            }
+        }
+
+        let arguments = Arguments {
+            id: ByteBuf::from(self.state.id.0),
+            target: Some(ByteBuf::from(self.state.id.0)),
+            ..Default::default()
+        };
+        let futs = addrs
+            .iter()
+            .map(|addr| self.state.query(*addr, Query::FindNode, arguments.clone()));
+        for response in futures::future::join_all(futs).await.into_iter().flatten() {
+            self.state.add_nodes(&response);
+        }
+
+        self.state.lookup(self.state.id, Query::FindNode).await;
+        if self.nodes_count() == 0 {
+            return Err(miette!("failed to reach any DHT node"));
+        }
+        Ok(())
+    }
+
+    /// Pings the nodes saved in the cache at the provided path. The nodes
+    /// that respond are added to the routing table.
+    pub async fn load_nodes(&self, path: &Path) -> miette::Result<()> {
+        let nodes = RoutingTable::load(path).map_err(|err| miette!(err))?;
+        let arguments = Arguments {
+            id: ByteBuf::from(self.state.id.0),
+            ..Default::default()
+        };
+        let futs = nodes
+            .iter()
+            .map(|(_, addr)| self.state.query(*addr, Query::Ping, arguments.clone()));
+        futures::future::join_all(futs).await;
+        Ok(())
+    }
+
+    /// Saves the nodes of the routing table to the provided path.
+    pub fn save_nodes(&self, path: &Path) -> miette::Result<()> {
+        self.state
+            .routing
+            .lock()
+            .expect("poisoned lock")
+            .save(path)
+            .map_err(|err| miette!(err))
+    }
+
+    /// Looks up the peers of the provided info hash and announces that we
+    /// are a peer on the provided port to the closest nodes that handed out
+    /// a token. If no port is provided, the nodes will use the port the
+    /// query came from.
+    pub async fn announce(
+        &self,
+        info_hash: &[u8],
+        port: Option<u16>,
+    ) -> miette::Result<Vec<SocketAddr>> {
+        let target = NodeId::from_slice(info_hash).ok_or(miette!("invalid info hash"))?;
+        let lookup = self.state.lookup(target, Query::GetPeers).await;
+
+        let futs = lookup.closest.iter().filter_map(|(_, addr, token)| {
+            let arguments = Arguments {
+                id: ByteBuf::from(self.state.id.0),
+                info_hash: Some(ByteBuf::from(target.0)),
+                port: Some(port.unwrap_or_default()),
+                token: token.clone(),
+                implied_port: Some(port.is_none() as u8),
+                ..Default::default()
+            };
+            token.as_ref()?;
+            Some(self.state.query(*addr, Query::AnnouncePeer, arguments))
+        });
+        futures::future::join_all(futs).await;
+
+        Ok(lookup.peers.into_iter().map(SocketAddr::V4).collect())
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl State {
+    /// Receives the messages of the socket. Responses are routed to the
+    /// pending queries, queries are answered.
+    async fn run(self: Arc<Self>, mut datagrams: Datagrams) {
+        let mut buffer = [0u8; 2048];
+        loop {
+            let (bytes, from) = match &mut datagrams {
+                Datagrams::Socket => match self.socket.recv_from(&mut buffer).await {
+                    Ok((len, from)) => (buffer[..len].to_vec(), from),
+                    Err(_) => continue,
+                },
+                Datagrams::Shared(receiver) => match receiver.recv().await {
+                    Some(datagram) => datagram,
+                    None => return,
+                },
+            };
+            let (Ok(message), Some(from)) = (Message::from_bytes(&bytes), as_v4(from)) else {
+                continue;
+            };
+
+            if let Some(id) = message.sender_id() {
+                self.routing.lock().expect("poisoned lock").insert(id, from);
+            }
+
+            match message.y.as_str() {
+                "q" => {
+                    let response = self.handle_query(&message, from);
+                    if let Ok(bytes) = response.to_bytes() {
+                        let _ = self.socket.send_to(&bytes, from).await;
+                    }
+                }
+                "r" | "e" => {
+                    let sender = self
+                        .pending
+                        .lock()
+                        .expect("poisoned lock")
+                        .remove(message.t.as_ref());
+                    if let Some(sender) = sender {
+                        let _ = sender.send(message);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a query to the node and waits for its response.
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        query: Query,
+        arguments: Arguments,
+    ) -> miette::Result<Response> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("poisoned lock")
+            .insert(transaction.to_vec(), sender);
+
+        let bytes = Message::query(&transaction, query, arguments).to_bytes()?;
+        let res = match self.socket.send_to(&bytes, addr).await {
+            Ok(_) => tokio::time::timeout(QUERY_TIMEOUT, receiver).await.ok(),
+            Err(_) => None,
+        };
+        self.pending
+            .lock()
+            .expect("poisoned lock")
+            .remove(transaction.as_slice());
+
+        let message = res
+            .and_then(Result::ok)
+            .ok_or(miette!("{} query to {addr} timed out", query.as_str()))?;
+        if let Some((code, msg)) = message.error_details() {
+            return Err(miette!("DHT error {code}: {msg}"));
+        }
+        message.r.ok_or(miette!("invalid DHT response"))
+    }
+
+    /// Adds the nodes of a response to the routing table.
+    fn add_nodes(&self, response: &Response) {
+        let Some(nodes) = &response.nodes else {
+            return;
+        };
+        let mut routing = self.routing.lock().expect("poisoned lock");
+        for (id, addr) in decode_nodes(nodes) {
+            routing.insert(id, addr);
+        }
+    }
+
+    /// Performs an iterative lookup of the target, querying the closest
+    /// known nodes until no closer node is found.
+    async fn lookup(&self, target: NodeId, query: Query) -> Lookup {
+        let mut candidates = self
+            .routing
+            .lock()
+            .expect("poisoned lock")
+            .closest(&target, K)
+            .into_iter()
+            .map(|n| (n.id.distance(&target), (n.id, n.addr)))
+            .collect::<BTreeMap<_, _>>();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut peers = HashSet::new();
+
+        let arguments = Arguments {
+            id: ByteBuf::from(self.id.0),
+            target: (query == Query::FindNode).then(|| ByteBuf::from(target.0)),
+            info_hash: (query == Query::GetPeers).then(|| ByteBuf::from(target.0)),
+            ..Default::default()
+        };
+
+        loop {
+            // Query the closest candidates that weren't queried yet, the
+            // lookup ends once the K closest candidates were all queried
+            let batch = candidates
+                .values()
+                .take(K)
+                .filter(|(id, _)| !queried.contains(id))
+                .take(ALPHA)
+                .copied()
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|(id, _)| *id));
+
+            let futs = batch.iter().map(|(id, addr)| {
+                let arguments = arguments.clone();
+                async move { (*id, *addr, self.query(*addr, query, arguments).await) }
+            });
+            for (id, addr, res) in futures::future::join_all(futs).await {
+                let Ok(response) = res else {
+                    candidates.remove(&id.distance(&target));
+                    self.routing.lock().expect("poisoned lock").failed(&id);
+                    continue;
+                };
+
+                let nodes = response.nodes.as_deref().map(|b| decode_nodes(b));
+                for (node_id, node_addr) in nodes.unwrap_or_default() {
+                    if node_id != self.id {
+                        candidates.insert(node_id.distance(&target), (node_id, node_addr));
+                    }
+                }
+                let values = response.values.unwrap_or_default();
+                peers.extend(values.iter().filter_map(|v| decode_peer(v)));
+                responded.insert(id.distance(&target), (id, addr, response.token));
+            }
+        }
+
+        Lookup {
+            peers,
+            closest: responded.into_values().take(K).collect(),
+        }
+    }
+
+    /// Returns the response to a query.
+    fn handle_query(&self, message: &Message, from: SocketAddrV4) -> Message {
+        let (Some(method), Some(arguments)) = (&message.q, &message.a) else {
+            return Message::error(&message.t, 203, "Protocol Error");
+        };
+        let Some(query) = Query::parse(method) else {
+            return Message::error(&message.t, 204, "Method Unknown");
+        };
+
+        let mut response = Response {
+            id: ByteBuf::from(self.id.0),
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode => {
+                let Some(target) = arguments
+                    .target
+                    .as_deref()
+                    .and_then(|b| NodeId::from_slice(b))
+                else {
+                    return Message::error(&message.t, 203, "Protocol Error");
+                };
+                response.nodes = Some(self.closest_nodes(&target));
+            }
+            Query::GetPeers => {
+                let Some(info_hash) = arguments
+                    .info_hash
+                    .as_deref()
+                    .and_then(|b| NodeId::from_slice(b))
+                else {
+                    return Message::error(&message.t, 203, "Protocol Error");
+                };
+                let token = self
+                    .tokens
+                    .lock()
+                    .expect("poisoned lock")
+                    .generate(from.ip());
+                response.token = Some(ByteBuf::from(token));
+
+                let values = self.stored_peers(&info_hash);
+                if values.is_empty() {
+                    response.nodes = Some(self.closest_nodes(&info_hash));
+                } else {
+                    response.values = Some(values);
+                }
+            }
+            Query::AnnouncePeer => {
+                let Some(info_hash) = arguments
+                    .info_hash
+                    .as_deref()
+                    .and_then(|b| NodeId::from_slice(b))
+                else {
+                    return Message::error(&message.t, 203, "Protocol Error");
+                };
+                let valid = arguments.token.as_ref().is_some_and(|token| {
+                    self.tokens
+                        .lock()
+                        .expect("poisoned lock")
+                        .verify(from.ip(), token)
+                });
+                if !valid {
+                    return Message::error(&message.t, 203, "Bad token");
+                }
+
+                let port = match (arguments.implied_port, arguments.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return Message::error(&message.t, 203, "Protocol Error"),
+                };
+                self.store_peer(info_hash, SocketAddrV4::new(*from.ip(), port));
+            }
+        }
+
+        Message::response(&message.t, response)
+    }
+
+    /// Returns the compact node info of the nodes closest to the target.
+    fn closest_nodes(&self, target: &NodeId) -> ByteBuf {
+        let nodes = self
+            .routing
+            .lock()
+            .expect("poisoned lock")
+            .closest(target, K)
+            .into_iter()
+            .map(|n| (n.id, n.addr))
+            .collect::<Vec<_>>();
+        ByteBuf::from(encode_nodes(&nodes))
+    }
+
+    /// Returns the compact peer info of the peers announced for the info
+    /// hash, dropping the expired ones.
+    fn stored_peers(&self, info_hash: &NodeId) -> Vec<ByteBuf> {
+        let mut peers = self.peers.lock().expect("poisoned lock");
+        let Some(stored) = peers.get_mut(info_hash) else {
+            return vec![];
+        };
+        stored.retain(|(_, announced_at)| announced_at.elapsed() < PEER_TTL);
+        stored
+            .iter()
+            .take(MAX_VALUES)
+            .map(|(addr, _)| ByteBuf::from(encode_peer(addr)))
+            .collect()
+    }
+
+    /// Stores a peer announced for the info hash.
+    fn store_peer(&self, info_hash: NodeId, addr: SocketAddrV4) {
+        let mut peers = self.peers.lock().expect("poisoned lock");
+        let stored = peers.entry(info_hash).or_default();
+        stored.retain(|(a, _)| a != &addr);
+        stored.push((addr, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn local_node() -> (Dht, String) {
+        let dht = Dht::bind("127.0.0.1:0").await.unwrap();
+        let addr = dht.state.socket.local_addr().unwrap().to_string();
+        (dht, addr)
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap() {
+        let (router, router_addr) = local_node().await;
+        let (a, _) = local_node().await;
+        let (b, _) = local_node().await;
+
+        a.bootstrap(std::slice::from_ref(&router_addr))
+            .await
+            .unwrap();
+        b.bootstrap(&[router_addr]).await.unwrap();
+
+        // The router learnt about both nodes, b learnt about a through it
+        assert_eq!(router.nodes_count(), 2);
+        assert_eq!(b.nodes_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_get_peers() {
+        let (_router, router_addr) = local_node().await;
+        let (seeder, _) = local_node().await;
+        let (leecher, _) = local_node().await;
+        seeder
+            .bootstrap(std::slice::from_ref(&router_addr))
+            .await
+            .unwrap();
+        leecher.bootstrap(&[router_addr]).await.unwrap();
+        let info_hash = [7u8; 20];
+
+        // The seeder announces with an explicit port
+        seeder.announce(&info_hash, Some(51413)).await.unwrap();
+        let peers = leecher.announce(&info_hash, Some(51414)).await.unwrap();
+
+        assert_eq!(peers, vec!["127.0.0.1:51413".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_announce_implied_port() {
+        let (_router, router_addr) = local_node().await;
+        let (seeder, seeder_addr) = local_node().await;
+        seeder.bootstrap(&[router_addr]).await.unwrap();
+        let info_hash = [9u8; 20];
+
+        seeder.announce(&info_hash, None).await.unwrap();
+        let peers = seeder.announce(&info_hash, None).await.unwrap();
+
+        assert_eq!(peers, vec![seeder_addr.parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_node_cache() {
+        let (_router, router_addr) = local_node().await;
+        let (a, _) = local_node().await;
+        a.bootstrap(&[router_addr]).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nodes.dat");
+
+        a.save_nodes(&path).unwrap();
+        let (b, _) = local_node().await;
+        b.load_nodes(&path).await.unwrap();
+
+        assert_eq!(b.nodes_count(), 1);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::dht::routing::NodeId;
+use miette::miette;
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value as BencodeValue;
+use serde_bytes::ByteBuf;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+/// Length of a compact node info: 20 bytes id, 4 bytes ip, 2 bytes port.
+pub const COMPACT_NODE_LEN: usize = 26;
+/// Length of a compact peer info: 4 bytes ip, 2 bytes port.
+pub const COMPACT_PEER_LEN: usize = 6;
+
+/// A KRPC message, as described in BEP 5. Queries, responses and
+/// errors share the same bencoded dictionary, keyed by `y`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub t: ByteBuf,
+    pub y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Arguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Response>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<Vec<BencodeValue>>,
+}
+
+/// The arguments of a KRPC query.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Arguments {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+/// The return values of a KRPC response.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+/// The queries supported by the DHT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode,
+    GetPeers,
+    AnnouncePeer,
+}
+
+impl Query {
+    /// Returns the method name of the query on the wire.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode => "find_node",
+            Query::GetPeers => "get_peers",
+            Query::AnnouncePeer => "announce_peer",
+        }
+    }
+
+    /// Parses the method name of a query.
+    pub fn parse(method: &str) -> Option<Self> {
+        match method {
+            "ping" => Some(Query::Ping),
+            "find_node" => Some(Query::FindNode),
+            "get_peers" => Some(Query::GetPeers),
+            "announce_peer" => Some(Query::AnnouncePeer),
+            _ => None,
+        }
+    }
+}
+
+impl Message {
+    /// Returns a query message.
+    pub fn query(transaction: &[u8], query: Query, arguments: Arguments) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "q".into(),
+            q: Some(query.as_str().into()),
+            a: Some(arguments),
+            r: None,
+            e: None,
+        }
+    }
+
+    /// Returns a response message.
+    pub fn response(transaction: &[u8], response: Response) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "r".into(),
+            q: None,
+            a: None,
+            r: Some(response),
+            e: None,
+        }
+    }
+
+    /// Returns an error message.
+    pub fn error(transaction: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "e".into(),
+            q: None,
+            a: None,
+            r: None,
+            e: Some(vec![
+                BencodeValue::Int(code),
+                BencodeValue::Bytes(message.as_bytes().to_vec()),
+            ]),
+        }
+    }
+
+    /// Encodes the message to bencode.
+    pub fn to_bytes(&self) -> miette::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).map_err(|err| miette!(err))
+    }
+
+    /// Decodes a message from bencode.
+    pub fn from_bytes(bytes: &[u8]) -> miette::Result<Self> {
+        serde_bencode::from_bytes(bytes).map_err(|err| miette!(err))
+    }
+
+    /// Returns the code and message of an error message.
+    pub fn error_details(&self) -> Option<(i64, String)> {
+        match self.e.as_deref()? {
+            [BencodeValue::Int(code), BencodeValue::Bytes(message), ..] => {
+                Some((*code, String::from_utf8_lossy(message).to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the id of the node that sent the message, if any.
+    pub fn sender_id(&self) -> Option<NodeId> {
+        let id = match (&self.a, &self.r) {
+            (Some(a), _) => &a.id,
+            (_, Some(r)) => &r.id,
+            _ => return None,
+        };
+        NodeId::from_slice(id)
+    }
+}
+
+/// Encodes a list of nodes to the compact node info format.
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddrV4)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for (id, addr) in nodes {
+        bytes.extend(id.0);
+        bytes.extend(encode_peer(addr));
+    }
+    bytes
+}
+
+/// Decodes a list of nodes from the compact node info format. Trailing
+/// bytes that do not form a full entry are ignored.
+pub fn decode_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddrV4)> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|chunk| {
+            Some((
+                NodeId::from_slice(&chunk[..20])?,
+                decode_peer(&chunk[20..])?,
+            ))
+        })
+        .collect()
+}
+
+/// Encodes an address to the compact peer info format.
+pub fn encode_peer(addr: &SocketAddrV4) -> [u8; COMPACT_PEER_LEN] {
+    let mut bytes = [0u8; COMPACT_PEER_LEN];
+    bytes[..4].copy_from_slice(&addr.ip().octets());
+    bytes[4..].copy_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+/// Decodes an address from the compact peer info format.
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    if bytes.len() != COMPACT_PEER_LEN {
+        return None;
+    }
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+    Some(SocketAddrV4::new(ip, port))
+}
+
+/// Returns the IPv4 address of a socket address. The DHT only speaks
+/// IPv4, IPv4-mapped IPv6 addresses are converted.
+pub fn as_v4(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) => Some(addr),
+        SocketAddr::V6(addr) => Some(SocketAddrV4::new(addr.ip().to_ipv4_mapped()?, addr.port())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_ping_query() {
+        // Example from BEP 5
+        let query = Message::query(
+            b"aa",
+            Query::Ping,
+            Arguments {
+                id: ByteBuf::from(b"abcdefghij0123456789".to_vec()),
+                ..Default::default()
+            },
+        );
+
+        let bytes = query.to_bytes().unwrap();
+
+        assert_eq!(
+            bytes,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+    }
+
+    #[test]
+    fn test_decode_get_peers_response() {
+        // Example from BEP 5
+        let bytes = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+
+        let message = Message::from_bytes(bytes).unwrap();
+
+        let response = message.r.unwrap();
+        assert_eq!(message.y, "r");
+        assert_eq!(response.token.unwrap().as_ref(), b"aoeusnth");
+        let values = response.values.unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].as_ref(), b"axje.u");
+    }
+
+    #[test]
+    fn test_decode_error() {
+        // Example from BEP 5
+        let bytes = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+
+        let message = Message::from_bytes(bytes).unwrap();
+
+        assert_eq!(
+            message.error_details(),
+            Some((201, "A Generic Error Ocurred".into()))
+        );
+    }
+
+    #[test]
+    fn test_compact_nodes_round_trip() {
+        let nodes = vec![
+            (NodeId([1u8; 20]), "127.0.0.1:6881".parse().unwrap()),
+            (NodeId([2u8; 20]), "10.0.0.2:51413".parse().unwrap()),
+        ];
+
+        let bytes = encode_nodes(&nodes);
+
+        assert_eq!(bytes.len(), 2 * COMPACT_NODE_LEN);
+        assert_eq!(decode_nodes(&bytes), nodes);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::dht::krpc::{decode_nodes, encode_nodes};
+use crate::random::random_bytes;
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The maximum amount of nodes held by a bucket.
+pub const K: usize = 8;
+/// A node that hasn't answered for this long is questionable and can be
+/// replaced by a new node.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+/// The amount of failed queries after which a node is evicted.
+const MAX_FAILURES: u8 = 2;
+
+/// A 160 bits identifier, used for nodes and info hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    /// Returns a random [`NodeId`].
+    pub fn random() -> Self {
+        Self(random_bytes())
+    }
+
+    /// Returns a [`NodeId`] from a slice, if it is 20 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    /// Returns the xor distance between the two ids.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0u8; 20];
+        for (i, d) in distance.iter_mut().enumerate() {
+            *d = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+
+    /// Returns the amount of leading bits the two ids have in common.
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        distance
+            .iter()
+            .position(|b| *b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+            .unwrap_or(160)
+    }
+}
+
+/// A node known by the routing table.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+    last_seen: Instant,
+    failures: u8,
+}
+
+/// The routing table of the DHT. Holds one bucket of [`K`] nodes for
+/// each possible length of the common prefix with our own id.
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<VecDeque<Node>>,
+}
+
+impl RoutingTable {
+    /// Returns an empty [`RoutingTable`] for the provided id.
+    pub fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![VecDeque::with_capacity(K); 160],
+        }
+    }
+
+    /// Returns the amount of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(VecDeque::len).sum()
+    }
+
+    /// Inserts or refreshes a node that we heard from. Returns false if
+    /// the node didn't fit in its bucket.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) -> bool {
+        let prefix = self.own.common_prefix(&id);
+        if prefix == 160 {
+            return false;
+        }
+        let bucket = &mut self.buckets[prefix];
+
+        // A known node is moved to the tail of the bucket
+        if let Some(pos) = bucket.iter().position(|n| n.id == id) {
+            let mut node = bucket.remove(pos).expect("node position is valid");
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            bucket.push_back(node);
+            return true;
+        }
+
+        let node = Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push_back(node);
+            return true;
+        }
+
+        // The bucket is full, replace the least recently seen node
+        // only if it became questionable
+        let questionable = bucket
+            .front()
+            .map(|n| n.last_seen.elapsed() > QUESTIONABLE_AFTER)
+            .unwrap_or_default();
+        if questionable {
+            bucket.pop_front();
+            bucket.push_back(node);
+        }
+        questionable
+    }
+
+    /// Records a failed query to the node, evicting it after too many
+    /// failures.
+    pub fn failed(&mut self, id: &NodeId) {
+        let prefix = self.own.common_prefix(id);
+        if prefix == 160 {
+            return;
+        }
+        let bucket = &mut self.buckets[prefix];
+        if let Some(pos) = bucket.iter().position(|n| &n.id == id) {
+            bucket[pos].failures += 1;
+            if bucket[pos].failures >= MAX_FAILURES {
+                bucket.remove(pos);
+            }
+        }
+    }
+
+    /// Returns the n closest nodes to the target.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes = self.buckets.iter().flatten().cloned().collect::<Vec<_>>();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    /// Saves the nodes of the table to the provided path, using the
+    /// compact node info format.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|n| (n.id, n.addr))
+            .collect::<Vec<_>>();
+        std::fs::write(path, encode_nodes(&nodes))
+    }
+
+    /// Loads the nodes saved at the provided path. The nodes aren't
+    /// inserted, as they need to be checked first.
+    pub fn load(path: &Path) -> std::io::Result<Vec<(NodeId, SocketAddrV4)>> {
+        Ok(decode_nodes(&std::fs::read(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id_with_first_byte(byte: u8) -> NodeId {
+        let mut id = [0u8; 20];
+        id[0] = byte;
+        NodeId(id)
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let own = NodeId([0u8; 20]);
+
+        assert_eq!(own.common_prefix(&own), 160);
+        assert_eq!(own.common_prefix(&id_with_first_byte(0x80)), 0);
+        assert_eq!(own.common_prefix(&id_with_first_byte(0x01)), 7);
+    }
+
+    #[test]
+    fn test_full_bucket_rejects_new_nodes() {
+        let mut table = RoutingTable::new(NodeId([0u8; 20]));
+        let addr = "127.0.0.1:6881".parse().unwrap();
+
+        // All ids starting with a 1 bit land in the same bucket
+        for i in 0..K as u8 {
+            assert!(table.insert(id_with_first_byte(0x80 + i), addr));
+        }
+
+        assert!(!table.insert(id_with_first_byte(0xff), addr));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new(NodeId([0u8; 20]));
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        for byte in [0x80, 0x40, 0x20, 0x10] {
+            table.insert(id_with_first_byte(byte), addr);
+        }
+
+        let closest = table.closest(&id_with_first_byte(0x30), 2);
+
+        assert_eq!(closest[0].id, id_with_first_byte(0x20));
+        assert_eq!(closest[1].id, id_with_first_byte(0x10));
+    }
+}
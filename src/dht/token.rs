@@ -0,0 +1,57 @@
+use crate::random::random_bytes;
+use sha1::{Digest, Sha1};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// The secret used to generate tokens is rotated at this interval. Tokens
+/// generated with the previous secret are still accepted.
+const ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Generates and checks the tokens handed out in `get_peers` responses.
+/// A token is the SHA-1 hash of the requester's ip and a secret.
+pub struct Tokens {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+impl Tokens {
+    /// Returns a new [`Tokens`] with a random secret.
+    pub fn new() -> Self {
+        let secret = random_bytes();
+        Self {
+            current: secret,
+            previous: secret,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    /// Returns the token for the provided ip.
+    pub fn generate(&mut self, ip: &Ipv4Addr) -> Vec<u8> {
+        self.rotate();
+        Self::hash(ip, &self.current)
+    }
+
+    /// Returns true if the token was handed out to the ip in the last
+    /// two rotation intervals.
+    pub fn verify(&mut self, ip: &Ipv4Addr, token: &[u8]) -> bool {
+        self.rotate();
+        token == Self::hash(ip, &self.current) || token == Self::hash(ip, &self.previous)
+    }
+
+    /// Rotates the secret if the interval elapsed.
+    fn rotate(&mut self) {
+        if self.rotated_at.elapsed() >= ROTATION_INTERVAL {
+            self.previous = self.current;
+            self.current = random_bytes();
+            self.rotated_at = Instant::now();
+        }
+    }
+
+    fn hash(ip: &Ipv4Addr, secret: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(ip.octets());
+        hasher.update(secret);
+        hasher.finalize().to_vec()
+    }
+}
@@ -0,0 +1,820 @@
+use crate::bitfield::Bitfield;
+use crate::choker::{Choker, RECHOKE_INTERVAL};
+use crate::connection::{self, Timeouts};
+use crate::handshake::Extensions;
+use crate::message::BlockRequest;
+use crate::mse::EncryptionPolicy;
+use crate::peers::Peers;
+use crate::priority::Priority;
+use crate::protocol::BitTorrentStream;
+use crate::rate_limit::RateLimits;
+use crate::torrent::{Info, Torrent};
+use crate::utp::UtpSocket;
+use crate::webseed;
+use miette::miette;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, Notify};
+
+/// The maximum amount of peers downloaded from at the same time.
+const MAX_PEERS: usize = 50;
+/// The amount of peers we upload to at the same time.
+const UPLOAD_SLOTS: usize = 4;
+/// Peers failing this many times in a row are banned.
+const MAX_FAILURES: u32 = 5;
+
+/// A running download. Peers can be added at any time, each peer gets its
+/// own connection which takes pieces from a shared queue until none are
+/// left, and serves the verified pieces to the peer until the download is
+/// stopped.
+pub struct Download {
+    torrent: Arc<Torrent>,
+    peer_id: [u8; 20],
+    state: Mutex<State>,
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    completed: Notify,
+    /// The indexes of the pieces verified, to send `have` to the peers.
+    haves: broadcast::Sender<u32>,
+    stopped: watch::Sender<bool>,
+    choker: Mutex<Choker>,
+    /// The peers the choker unchoked, watched by the connections.
+    unchoked: watch::Sender<HashSet<SocketAddr>>,
+    /// Set once the rechoke task is spawned, with the first peer.
+    choking: AtomicBool,
+    /// The rate limits of the torrent.
+    limits: RateLimits,
+    /// The download and upload rates of each new peer.
+    peer_rates: Mutex<(Option<u64>, Option<u64>)>,
+    peer_limits: Mutex<HashMap<SocketAddr, Arc<RateLimits>>>,
+    encryption: Mutex<EncryptionPolicy>,
+    timeouts: Mutex<Timeouts>,
+    /// The socket to dial peers over uTP, if any.
+    utp: Mutex<Option<Arc<UtpSocket>>>,
+}
+
+struct State {
+    /// The indexes of the pieces nobody is downloading.
+    pending: VecDeque<u32>,
+    /// The verified pieces.
+    pieces: Vec<Option<Vec<u8>>>,
+    /// The priority of each file.
+    priorities: Vec<Priority>,
+    /// The priority of each piece, the highest of its files.
+    piece_priorities: Vec<Priority>,
+    /// The bytes of the wanted pieces left to verify.
+    left: u64,
+    /// The addresses of the peers with a running connection.
+    peers: HashSet<SocketAddr>,
+    /// The peers which failed too many times, never dialed again.
+    banned: HashSet<SocketAddr>,
+}
+
+impl Download {
+    /// Returns a new [`Download`] of the torrent, with no peers. The peer id
+    /// is sent in the handshakes.
+    pub fn new(torrent: Arc<Torrent>, peer_id: [u8; 20]) -> Arc<Self> {
+        let count = torrent.info.pieces_count();
+        let priorities = vec![Priority::Normal; torrent.info.file_offsets().len()];
+        let pieces = vec![None; count as usize];
+        let piece_priorities = piece_priorities(&torrent.info, &priorities);
+        let state = State {
+            pending: (0..count).collect(),
+            left: left(&torrent.info, &pieces, &piece_priorities),
+            pieces,
+            piece_priorities,
+            priorities,
+            peers: HashSet::new(),
+            banned: HashSet::new(),
+        };
+        // Each piece is verified once, so the channel never lags
+        let (haves, _) = broadcast::channel(count.max(1) as usize);
+        Arc::new(Self {
+            torrent,
+            peer_id,
+            state: Mutex::new(state),
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            completed: Notify::new(),
+            haves,
+            stopped: watch::channel(false).0,
+            choker: Mutex::new(Choker::new(UPLOAD_SLOTS)),
+            unchoked: watch::channel(HashSet::new()).0,
+            choking: AtomicBool::new(false),
+            limits: RateLimits::new(None, None),
+            peer_rates: Mutex::new((None, None)),
+            peer_limits: Mutex::new(HashMap::new()),
+            encryption: Mutex::new(EncryptionPolicy::default()),
+            timeouts: Mutex::new(Timeouts::default()),
+            utp: Mutex::new(None),
+        })
+    }
+
+    /// Returns the torrent being downloaded.
+    pub fn torrent(&self) -> &Arc<Torrent> {
+        &self.torrent
+    }
+
+    /// Returns the info hash of the torrent.
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.torrent
+            .raw_info_hash()
+            .try_into()
+            .expect("info hash is 20 bytes")
+    }
+
+    /// Returns the info hashes of the torrent, two for a hybrid torrent.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        self.torrent.info_hashes()
+    }
+
+    /// Returns our peer id for the download.
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// Returns the amount of bytes downloaded from peers.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the amount of bytes uploaded to peers.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the amount of bytes left to download.
+    pub fn left(&self) -> u64 {
+        self.state.lock().expect("poisoned lock").left
+    }
+
+    /// Returns true once all the wanted pieces are verified.
+    pub fn is_complete(&self) -> bool {
+        self.left() == 0
+    }
+
+    /// Sets the priority of each file of the torrent, in the order of
+    /// [`Info::file_offsets`]. The files past the priorities are normal.
+    pub fn set_file_priorities(&self, mut priorities: Vec<Priority>) {
+        let info = &self.torrent.info;
+        priorities.resize(info.file_offsets().len(), Priority::Normal);
+        let mut state = self.state.lock().expect("poisoned lock");
+        state.piece_priorities = piece_priorities(info, &priorities);
+        state.priorities = priorities;
+        state.left = left(info, &state.pieces, &state.piece_priorities);
+
+        if state.left == 0 {
+            self.completed.notify_waiters();
+        }
+    }
+
+    /// Waits until all the wanted pieces are verified.
+    pub async fn wait_complete(&self) {
+        loop {
+            let notified = self.completed.notified();
+            if self.is_complete() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Returns the amount of bytes of the wanted pieces, verified or not.
+    pub fn wanted(&self) -> u64 {
+        let state = self.state.lock().expect("poisoned lock");
+        let info = &self.torrent.info;
+        (0..info.pieces_count())
+            .filter(|index| state.piece_priorities[*index as usize] != Priority::Skip)
+            .map(|index| info.piece_len(index) as u64)
+            .sum()
+    }
+
+    /// Seeds until the upload ratio of the wanted bytes or the seeding time
+    /// is reached, whichever comes first. Returns right away without limits.
+    pub async fn seed(&self, ratio: Option<f64>, time: Option<Duration>) {
+        if ratio.is_none() && time.is_none() {
+            return;
+        }
+        let target = ratio.map(|ratio| (ratio * self.wanted() as f64) as u64);
+        let ratio_reached = async {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                if target.is_some_and(|target| self.uploaded() >= target) {
+                    return;
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = ratio_reached => {}
+            _ = tokio::time::sleep(time.unwrap_or(Duration::MAX)) => {}
+        }
+    }
+
+    /// Stops the download, closing all the connections.
+    pub fn stop(&self) {
+        self.stopped.send_replace(true);
+    }
+
+    /// Returns a receiver of the pieces verified from now on.
+    pub fn haves(&self) -> broadcast::Receiver<u32> {
+        self.haves.subscribe()
+    }
+
+    /// Returns a receiver set to true once the download is stopped.
+    pub fn stopped(&self) -> watch::Receiver<bool> {
+        self.stopped.subscribe()
+    }
+
+    /// Returns the rate limits of the torrent, adjustable at any time.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Sets the download and upload rates of every peer, the connected ones
+    /// included.
+    pub fn set_peer_rates(&self, download: Option<u64>, upload: Option<u64>) {
+        *self.peer_rates.lock().expect("poisoned lock") = (download, upload);
+        for limits in self.peer_limits.lock().expect("poisoned lock").values() {
+            limits.download.set_rate(download);
+            limits.upload.set_rate(upload);
+        }
+    }
+
+    /// Returns whether the connections of the download are encrypted.
+    pub fn encryption(&self) -> EncryptionPolicy {
+        *self.encryption.lock().expect("poisoned lock")
+    }
+
+    /// Sets whether the new connections of the download are encrypted.
+    pub fn set_encryption(&self, policy: EncryptionPolicy) {
+        *self.encryption.lock().expect("poisoned lock") = policy;
+    }
+
+    /// Returns the timeouts of the connections to the peers.
+    pub fn timeouts(&self) -> Timeouts {
+        *self.timeouts.lock().expect("poisoned lock")
+    }
+
+    /// Sets the timeouts of the new connections to the peers.
+    #[cfg(test)]
+    pub fn set_timeouts(&self, timeouts: Timeouts) {
+        *self.timeouts.lock().expect("poisoned lock") = timeouts;
+    }
+
+    /// Dials the peers over uTP with the socket first, then over TCP.
+    pub fn set_utp(&self, utp: Arc<UtpSocket>) {
+        *self.utp.lock().expect("poisoned lock") = Some(utp);
+    }
+
+    /// Returns the rate limits of the peer.
+    pub fn peer_limits(&self, address: SocketAddr) -> Arc<RateLimits> {
+        let (download, upload) = *self.peer_rates.lock().expect("poisoned lock");
+        self.peer_limits
+            .lock()
+            .expect("poisoned lock")
+            .entry(address)
+            .or_insert_with(|| Arc::new(RateLimits::new(download, upload)))
+            .clone()
+    }
+
+    /// Returns a receiver of the peers we upload to.
+    pub fn unchoked(&self) -> watch::Receiver<HashSet<SocketAddr>> {
+        self.unchoked.subscribe()
+    }
+
+    /// Records a change of interest of the peer in our pieces.
+    pub fn peer_interested(&self, address: SocketAddr, interested: bool) {
+        self.update_choker(|choker| choker.set_interested(address, interested));
+    }
+
+    /// Records a block received from the peer, for its download rate.
+    pub fn block_received(&self, address: SocketAddr, len: u64) {
+        self.update_choker(|choker| choker.record_download(address, len, Instant::now()));
+    }
+
+    /// Records a block sent to the peer, for its upload rate.
+    pub fn block_sent(&self, address: SocketAddr, len: u64) {
+        self.uploaded.fetch_add(len, Ordering::Relaxed);
+        self.update_choker(|choker| choker.record_upload(address, len));
+    }
+
+    /// Applies a change to the choker, then publishes the unchoked peers
+    /// if they changed.
+    fn update_choker(&self, update: impl FnOnce(&mut Choker)) {
+        let mut choker = self.choker.lock().expect("poisoned lock");
+        update(&mut choker);
+        self.unchoked.send_if_modified(|unchoked| {
+            let changed = unchoked != choker.unchoked();
+            if changed {
+                unchoked.clone_from(choker.unchoked());
+            }
+            changed
+        });
+    }
+
+    /// Rechokes the peers every [`RECHOKE_INTERVAL`] until the download is
+    /// stopped or dropped.
+    async fn rechoke(download: Weak<Self>) {
+        loop {
+            tokio::time::sleep(RECHOKE_INTERVAL).await;
+            let Some(download) = download.upgrade() else {
+                return;
+            };
+            if *download.stopped.borrow() {
+                return;
+            }
+            let seeding = download.is_complete();
+            download.update_choker(|choker| choker.rechoke(Instant::now(), seeding));
+        }
+    }
+
+    /// Returns the pieces verified.
+    pub fn bitfield(&self) -> Bitfield {
+        let state = self.state.lock().expect("poisoned lock");
+        let mut bitfield = Bitfield::new(self.torrent.info.pieces_count());
+        for (index, piece) in state.pieces.iter().enumerate() {
+            if piece.is_some() {
+                bitfield.set(index as u32);
+            }
+        }
+        bitfield
+    }
+
+    /// Returns the content of the torrent, once complete. The skipped
+    /// pieces which weren't downloaded are zeros.
+    pub fn data(&self) -> miette::Result<Vec<u8>> {
+        let state = self.state.lock().expect("poisoned lock");
+        let mut data = Vec::with_capacity(self.torrent.info.length as usize);
+        for (index, piece) in state.pieces.iter().enumerate() {
+            match piece {
+                Some(piece) => data.extend(piece),
+                None if state.piece_priorities[index] == Priority::Skip => {
+                    let len = self.torrent.info.piece_len(index as u32) as usize;
+                    data.resize(data.len() + len, 0);
+                }
+                None => return Err(miette!("download isn't complete")),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Writes the wanted files to the path, once complete. The verified
+    /// pieces shared with skipped files are kept whole in the partial-piece
+    /// file next to it, see [`Download::parts_path`].
+    pub fn write_content(&self, path: &Path) -> miette::Result<()> {
+        let data = self.data()?;
+        let info = &self.torrent.info;
+        let state = self.state.lock().expect("poisoned lock");
+        info.write_content(&data, path, &state.priorities)?;
+
+        // Each piece is stored as its index followed by its bytes
+        let mut parts = Vec::new();
+        let mut stored = HashSet::new();
+        let files = info.file_offsets().into_iter().zip(info.file_pieces());
+        for (((_, file), pieces), priority) in files.zip(&state.priorities) {
+            if file.is_padding() || *priority != Priority::Skip {
+                continue;
+            }
+            for index in pieces {
+                let Some(piece) = &state.pieces[index as usize] else {
+                    continue;
+                };
+                if stored.insert(index) {
+                    parts.extend(index.to_be_bytes());
+                    parts.extend(piece);
+                }
+            }
+        }
+        if parts.is_empty() {
+            return Ok(());
+        }
+        std::fs::write(Self::parts_path(path), parts).map_err(|err| miette!(err))
+    }
+
+    /// Returns the path of the partial-piece file of the content written to
+    /// the path, with a `.parts` suffix.
+    pub fn parts_path(path: &Path) -> PathBuf {
+        let mut parts = path.as_os_str().to_owned();
+        parts.push(".parts");
+        parts.into()
+    }
+
+    /// Starts downloading from the provided peers. Peers which already
+    /// have a connection are skipped.
+    pub fn add_peers(self: &Arc<Self>, peers: &Peers) {
+        let mut state = self.state.lock().expect("poisoned lock");
+        for address in peers.0.iter().copied() {
+            if state.peers.len() >= MAX_PEERS || state.left == 0 {
+                return;
+            }
+            if !state.banned.contains(&address) && state.peers.insert(address) {
+                self.spawn_peer(address, None);
+            }
+        }
+    }
+
+    /// Starts downloading from the web seeds, along with the peers.
+    pub fn add_web_seeds(self: &Arc<Self>, urls: &[String]) {
+        for url in urls {
+            tokio::spawn(webseed::run(self.clone(), url.clone()));
+        }
+    }
+
+    /// Starts downloading from the HTTP seeds, along with the peers.
+    pub fn add_http_seeds(self: &Arc<Self>, urls: &[String]) {
+        for url in urls {
+            tokio::spawn(webseed::run_http_seed(self.clone(), url.clone()));
+        }
+    }
+
+    /// Exchanges pieces with a peer which connected to us, once handshaked.
+    /// The connection is dropped if the peer already has one or too many
+    /// peers are connected.
+    pub fn add_incoming(
+        self: &Arc<Self>,
+        address: SocketAddr,
+        stream: BitTorrentStream,
+        extensions: Extensions,
+    ) {
+        let mut state = self.state.lock().expect("poisoned lock");
+        if state.peers.len() < MAX_PEERS && state.peers.insert(address) {
+            self.spawn_peer(address, Some((stream, extensions)));
+        }
+    }
+
+    /// Spawns the connection to the peer, dialing it without a stream.
+    fn spawn_peer(
+        self: &Arc<Self>,
+        address: SocketAddr,
+        stream: Option<(BitTorrentStream, Extensions)>,
+    ) {
+        if !self.choking.swap(true, Ordering::Relaxed) {
+            tokio::spawn(Self::rechoke(Arc::downgrade(self)));
+        }
+
+        let download = self.clone();
+        tokio::spawn(async move {
+            let incoming = stream.is_some();
+            let mut stream = stream;
+            let mut failures = 0;
+            let mut stopped = download.stopped();
+            while let Err(err) = download
+                .clone()
+                .run_peer(address, stream.take(), &mut failures)
+                .await
+            {
+                // Peers which connected to us can't be dialed back
+                failures += 1;
+                if incoming {
+                    break;
+                }
+                if failures >= MAX_FAILURES {
+                    eprintln!("Banning {address}: {err}");
+                    download
+                        .state
+                        .lock()
+                        .expect("poisoned lock")
+                        .banned
+                        .insert(address);
+                    break;
+                }
+
+                let backoff = download.timeouts().retry_backoff * 2u32.pow(failures - 1);
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = async { let _ = stopped.wait_for(|stopped| *stopped).await; } => break,
+                }
+                if download.is_complete() {
+                    break;
+                }
+            }
+
+            download
+                .peer_limits
+                .lock()
+                .expect("poisoned lock")
+                .remove(&address);
+            download
+                .state
+                .lock()
+                .expect("poisoned lock")
+                .peers
+                .remove(&address);
+        });
+    }
+
+    /// Exchanges pieces with the peer, connecting to it first when there
+    /// is no stream. The failures are reset once the peer is useful, a
+    /// handshake alone isn't enough.
+    async fn run_peer(
+        self: Arc<Self>,
+        address: SocketAddr,
+        stream: Option<(BitTorrentStream, Extensions)>,
+        failures: &mut u32,
+    ) -> miette::Result<()> {
+        let (stream, extensions) = match stream {
+            Some(stream) => stream,
+            None => {
+                let info_hash = self.info_hash();
+                let utp = self.utp.lock().expect("poisoned lock").clone();
+                let mut stream =
+                    BitTorrentStream::open(address, &info_hash, self.encryption(), utp.as_deref())
+                        .await?;
+                let handshake = stream.handshake(&self.torrent, self.peer_id).await?;
+                (stream, handshake.extensions())
+            }
+        };
+        self.update_choker(|choker| choker.add_peer(address, Instant::now()));
+        let mut progress = false;
+        let res = connection::run(self.clone(), address, stream, extensions, &mut progress).await;
+        self.update_choker(|choker| choker.remove_peer(&address));
+        if progress {
+            *failures = 0;
+        }
+        res
+    }
+
+    /// Returns true if the peer has a wanted piece nobody is downloading.
+    pub fn wants_any(&self, available: &Bitfield) -> bool {
+        let state = self.state.lock().expect("poisoned lock");
+        state
+            .pending
+            .iter()
+            .any(|index| state.wants(*index, available))
+    }
+
+    /// Takes the next piece to download among the available pieces, the
+    /// first one of the highest priority.
+    pub fn next_piece(&self, available: &Bitfield) -> Option<u32> {
+        let mut state = self.state.lock().expect("poisoned lock");
+        let (position, _) = state
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, index)| state.wants(**index, available))
+            .min_by_key(|(_, index)| Reverse(state.piece_priorities[**index as usize]))?;
+        state.pending.remove(position)
+    }
+
+    /// Takes a specific piece to download, if it is wanted and nobody is
+    /// downloading it.
+    pub fn take_piece(&self, index: u32) -> bool {
+        let mut state = self.state.lock().expect("poisoned lock");
+        if state.piece_priorities.get(index as usize) == Some(&Priority::Skip) {
+            return false;
+        }
+        let Some(position) = state.pending.iter().position(|i| *i == index) else {
+            return false;
+        };
+        state.pending.remove(position);
+        true
+    }
+
+    /// Puts back a piece that couldn't be downloaded in the queue.
+    pub fn release_piece(&self, index: u32) {
+        self.state
+            .lock()
+            .expect("poisoned lock")
+            .pending
+            .push_back(index);
+    }
+
+    /// Returns true if the piece matches its hash.
+    pub fn verify(&self, index: u32, piece: &[u8]) -> bool {
+        self.torrent.info.verify_piece(index, piece)
+    }
+
+    /// Stores a verified piece, with its padding as zeros as it was hashed.
+    pub fn complete_piece(&self, index: u32, piece: Vec<u8>) {
+        let piece = self.torrent.info.zero_padding(index, piece).into_owned();
+        let mut state = self.state.lock().expect("poisoned lock");
+        let slot = &mut state.pieces[index as usize];
+        if slot.is_some() {
+            return;
+        }
+        let len = piece.len() as u64;
+        *slot = Some(piece);
+        // A piece skipped while downloading isn't part of the bytes left
+        if state.piece_priorities[index as usize] != Priority::Skip {
+            state.left -= len;
+        }
+        self.downloaded.fetch_add(len, Ordering::Relaxed);
+
+        if state.left == 0 {
+            self.completed.notify_waiters();
+        }
+        let _ = self.haves.send(index);
+    }
+
+    /// Reads a block of a verified piece. Returns None for a piece we don't
+    /// have, and an error for a block out of the piece.
+    pub fn read_block(&self, request: &BlockRequest) -> miette::Result<Option<Vec<u8>>> {
+        let state = self.state.lock().expect("poisoned lock");
+        let piece = state
+            .pieces
+            .get(request.index as usize)
+            .ok_or(miette!("no piece {}", request.index))?;
+        let Some(piece) = piece else {
+            return Ok(None);
+        };
+        let end = request.begin as u64 + request.length as u64;
+        if end > piece.len() as u64 {
+            return Err(miette!(
+                "block {}..{end} is out of piece {}",
+                request.begin,
+                request.index
+            ));
+        }
+        Ok(Some(piece[request.begin as usize..end as usize].to_vec()))
+    }
+}
+
+impl State {
+    /// Returns true if the piece is available and isn't skipped.
+    fn wants(&self, index: u32, available: &Bitfield) -> bool {
+        available.has(index) && self.piece_priorities[index as usize] != Priority::Skip
+    }
+}
+
+/// Returns the priority of each piece, the highest of the files it holds.
+/// The pieces of padding files only are skipped.
+fn piece_priorities(info: &Info, priorities: &[Priority]) -> Vec<Priority> {
+    let mut pieces = vec![Priority::Skip; info.pieces_count() as usize];
+    let files = info.file_offsets().into_iter().zip(info.file_pieces());
+    for (((_, file), range), priority) in files.zip(priorities) {
+        if file.is_padding() {
+            continue;
+        }
+        for index in range {
+            let piece = &mut pieces[index as usize];
+            *piece = (*piece).max(*priority);
+        }
+    }
+    pieces
+}
+
+/// Returns the bytes of the pieces left to verify, the skipped ones aside.
+fn left(info: &Info, pieces: &[Option<Vec<u8>>], priorities: &[Priority]) -> u64 {
+    (0..info.pieces_count())
+        .filter(|index| {
+            pieces[*index as usize].is_none() && priorities[*index as usize] != Priority::Skip
+        })
+        .map(|index| info.piece_len(index) as u64)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::File;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_ban_after_failures() {
+        let torrent = Torrent::single_piece(&[0]);
+        let download = Download::new(Arc::new(torrent), [1u8; 20]);
+        download.set_timeouts(Timeouts {
+            retry_backoff: Duration::from_millis(10),
+            ..Timeouts::default()
+        });
+        // Nothing listens on the port anymore
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        download.add_peers(&Peers(vec![address]));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Still backing off
+        assert!(download.state.lock().unwrap().peers.contains(&address));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+
+        let state = download.state.lock().unwrap();
+        assert!(state.banned.contains(&address));
+        assert!(!state.peers.contains(&address));
+        drop(state);
+        download.add_peers(&Peers(vec![address]));
+        assert!(download.state.lock().unwrap().peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ban_after_handshakes() {
+        let torrent = Arc::new(Torrent::single_piece(&[0]));
+        let download = Download::new(torrent.clone(), [1u8; 20]);
+        download.set_timeouts(Timeouts {
+            retry_backoff: Duration::from_millis(10),
+            ..Timeouts::default()
+        });
+        // The peer handshakes, then closes the connection every time
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BitTorrentStream::from_transport(Box::new(stream));
+                let _ = stream.handshake(&torrent, [2u8; 20]).await;
+            }
+        });
+
+        download.add_peers(&Peers(vec![address]));
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+
+        assert!(download.state.lock().unwrap().banned.contains(&address));
+    }
+
+    fn file(name: &str, length: u32) -> File {
+        File {
+            attr: String::new(),
+            length,
+            path: vec![name.to_string()],
+            symlink_path: None,
+            sha1: None,
+        }
+    }
+
+    #[test]
+    fn test_padding_piece() {
+        // The last piece only holds the trailing padding file
+        let mut pad = file(".pad", 4);
+        pad.attr = "p".into();
+        let content = [&b"data"[..], &[0; 4]].concat();
+        let torrent = Torrent::with_files(&content, 4, vec![file("a", 4), pad]);
+        let download = Download::new(Arc::new(torrent), [1u8; 20]);
+
+        assert_eq!(download.left(), 4);
+        assert_eq!(download.next_piece(&Bitfield::full(2)), Some(0));
+        download.complete_piece(0, b"data".to_vec());
+        assert!(download.is_complete());
+    }
+
+    #[test]
+    fn test_padding_is_stored_as_zeros() {
+        let mut pad = file(".pad", 2);
+        pad.attr = "p".into();
+        let content = [&b"ab"[..], &[0; 2], b"cdef"].concat();
+        let torrent = Torrent::with_files(&content, 4, vec![file("a", 2), pad, file("b", 4)]);
+        let download = Download::new(Arc::new(torrent), [1u8; 20]);
+
+        download.complete_piece(0, b"ab\xff\xff".to_vec());
+        let request = BlockRequest {
+            index: 0,
+            begin: 0,
+            length: 4,
+        };
+        assert_eq!(download.read_block(&request).unwrap().unwrap(), b"ab\0\0");
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        // The middle file shares its pieces with the others
+        let content: Vec<u8> = (1..=16).collect();
+        let files = vec![file("a", 6), file("b", 4), file("c", 6)];
+        let torrent = Torrent::with_files(&content, 4, files);
+        let download = Download::new(Arc::new(torrent), [1u8; 20]);
+        let all = Bitfield::full(4);
+
+        // The pieces of the high file come first
+        download.set_file_priorities(vec![Priority::Normal, Priority::Skip, Priority::High]);
+        let order: Vec<_> = std::iter::from_fn(|| download.next_piece(&all)).collect();
+        assert_eq!(order, vec![2, 3, 0, 1]);
+        for index in order {
+            download.release_piece(index);
+        }
+
+        // Only the pieces of the first file are left, one shared with the second
+        download.set_file_priorities(vec![Priority::Normal, Priority::Skip, Priority::Skip]);
+        assert_eq!(download.left(), 8);
+        assert_eq!(download.wanted(), 8);
+        assert!(!download.take_piece(3));
+        for index in [0, 1] {
+            assert_eq!(download.next_piece(&all), Some(index));
+            download.complete_piece(index, content[index as usize * 4..][..4].to_vec());
+        }
+        assert!(download.is_complete());
+        assert!(!download.wants_any(&all));
+        assert_eq!(download.data().unwrap(), [&content[..8], &[0; 8]].concat());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test");
+        download.write_content(&path).unwrap();
+        assert_eq!(std::fs::read(path.join("a")).unwrap(), &content[..6]);
+        assert!(!path.join("b").exists());
+        assert!(!path.join("c").exists());
+        // The shared piece is kept for the skipped file
+        let parts = std::fs::read(Download::parts_path(&path)).unwrap();
+        assert_eq!(parts, [&1u32.to_be_bytes()[..], &content[4..8]].concat());
+
+        // Wanting the skipped files again
+        download.set_file_priorities(vec![]);
+        assert_eq!(download.left(), 8);
+        assert!(download.data().is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use sha1::{Digest, Sha1};
+use std::net::Ipv4Addr;
+
+/// The amount of pieces a choked peer may request from us.
+pub const ALLOWED_FAST_COUNT: u32 = 10;
+
+/// Returns the allowed fast set of a peer, with the canonical algorithm of
+/// BEP 6: the pieces come from repeated SHA-1 hashes of the /24 of the peer
+/// and the info hash, so every client computes the same set for a peer.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8], pieces: u32, count: u32) -> Vec<u32> {
+    let count = count.min(pieces);
+    let mut set = Vec::with_capacity(count as usize);
+
+    let mut x = (u32::from(ip) & 0xffffff00).to_be_bytes().to_vec();
+    x.extend(info_hash);
+    while (set.len() as u32) < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks(4) {
+            if set.len() as u32 == count {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().expect("4 bytes"));
+            let index = y % pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bep_vectors() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    #[test]
+    fn test_small_torrent() {
+        let set = allowed_fast_set(Ipv4Addr::LOCALHOST, &[1; 20], 3, ALLOWED_FAST_COUNT);
+        let mut sorted = set.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec![0, 1, 2]);
+    }
+}
@@ -1,6 +1,17 @@
+use miette::miette;
+
+/// The protocol string sent at the start of every handshake.
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// The length of a handshake, in bytes.
+pub const HANDSHAKE_LEN: usize = 68;
+/// The length of the handshake before the peer id. Enough to know which
+/// torrent an incoming connection is for.
+pub const HANDSHAKE_PREFIX_LEN: usize = 48;
+
 /// The handshake data for the TCP connection
 /// with the bit torrent protocol.
-#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HandShake {
     length: u8,
     protocol: [u8; 19],
@@ -9,15 +20,154 @@ pub struct HandShake {
     peer_id: [u8; 20],
 }
 
+/// The extensions a peer supports, advertised in the reserved bytes of its
+/// handshake.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    /// BEP 5, bit 0x01 of the last byte.
+    pub dht: bool,
+    /// BEP 6, bit 0x04 of the last byte.
+    pub fast: bool,
+    /// BEP 10, bit 0x10 of the sixth byte.
+    pub extension_protocol: bool,
+}
+
 impl HandShake {
-    /// Construct a [`HandShake`]
+    /// Construct a [`HandShake`], advertising the extensions we support.
     pub fn new(info_hash: &[u8], peer_id: [u8; 20]) -> Self {
         Self {
             length: 19,
-            protocol: *b"BitTorrent protocol",
-            reserved: [0u8; 8],
+            protocol: *PROTOCOL,
+            reserved: [0, 0, 0, 0, 0, 0, 0, 0x04],
             info_hash: info_hash.try_into().expect("failed to convert info hash"),
             peer_id,
         }
     }
+
+    /// Serializes the handshake as sent on the wire.
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        bytes[0] = self.length;
+        bytes[1..20].copy_from_slice(&self.protocol);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    /// Parses a handshake, or only its prefix before the peer id in which
+    /// case the peer id is zeroed until set with [`HandShake::set_peer_id`].
+    pub fn from_bytes(bytes: &[u8]) -> miette::Result<Self> {
+        if bytes.len() != HANDSHAKE_LEN && bytes.len() != HANDSHAKE_PREFIX_LEN {
+            return Err(miette!("invalid handshake length {}", bytes.len()));
+        }
+        let mut peer_id = [0u8; 20];
+        if bytes.len() == HANDSHAKE_LEN {
+            peer_id.copy_from_slice(&bytes[48..]);
+        }
+        Ok(Self {
+            length: bytes[0],
+            protocol: bytes[1..20].try_into().expect("protocol is 19 bytes"),
+            reserved: bytes[20..28].try_into().expect("reserved is 8 bytes"),
+            info_hash: bytes[28..48].try_into().expect("info hash is 20 bytes"),
+            peer_id,
+        })
+    }
+
+    /// Sets the peer id of a handshake parsed from its prefix.
+    pub fn set_peer_id(&mut self, peer_id: [u8; 20]) {
+        self.peer_id = peer_id;
+    }
+
+    /// Returns the info hash of the torrent.
+    pub fn info_hash(&self) -> &[u8; 20] {
+        &self.info_hash
+    }
+
+    /// Returns the id of the peer which sent the handshake.
+    pub fn peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    /// Returns the extensions supported by the peer.
+    pub fn extensions(&self) -> Extensions {
+        Extensions {
+            dht: self.reserved[7] & 0x01 != 0,
+            fast: self.reserved[7] & 0x04 != 0,
+            extension_protocol: self.reserved[5] & 0x10 != 0,
+        }
+    }
+
+    /// Checks the handshake received from a peer is for the bit torrent
+    /// protocol and for the provided info hash.
+    pub fn validate(&self, info_hash: &[u8]) -> miette::Result<()> {
+        if self.length != 19 || &self.protocol != PROTOCOL {
+            return Err(miette!("peer doesn't speak the bit torrent protocol"));
+        }
+        if self.info_hash != info_hash {
+            return Err(miette!(
+                "peer answered for info hash {}",
+                hex::encode(self.info_hash)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes() {
+        let mut handshake = HandShake::new(&[1u8; 20], [2u8; 20]);
+        handshake.reserved[7] = 0x05;
+        let bytes = handshake.to_bytes();
+
+        assert_eq!(bytes[0], 19);
+        assert_eq!(&bytes[1..20], b"BitTorrent protocol");
+        assert_eq!(HandShake::from_bytes(&bytes).unwrap(), handshake);
+
+        // The prefix has everything but the peer id
+        let mut prefix = HandShake::from_bytes(&bytes[..HANDSHAKE_PREFIX_LEN]).unwrap();
+        assert_eq!(prefix.peer_id(), &[0u8; 20]);
+        prefix.set_peer_id([2u8; 20]);
+        assert_eq!(prefix, handshake);
+
+        assert!(HandShake::from_bytes(&bytes[..67]).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let handshake = HandShake::new(&[1u8; 20], [2u8; 20]);
+        assert!(handshake.validate(&[1u8; 20]).is_ok());
+        assert!(handshake.validate(&[3u8; 20]).is_err());
+
+        let mut handshake = HandShake::new(&[1u8; 20], [2u8; 20]);
+        handshake.protocol = *b"BitTorrent protocoL";
+        assert!(handshake.validate(&[1u8; 20]).is_err());
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut handshake = HandShake::new(&[1u8; 20], [2u8; 20]);
+        // We support the Fast Extension
+        assert_eq!(
+            handshake.extensions(),
+            Extensions {
+                fast: true,
+                ..Extensions::default()
+            }
+        );
+
+        handshake.reserved = [0, 0, 0, 0, 0, 0x10, 0, 0x05];
+        assert_eq!(
+            handshake.extensions(),
+            Extensions {
+                dht: true,
+                fast: true,
+                extension_protocol: true
+            }
+        );
+    }
 }
@@ -0,0 +1,296 @@
+use crate::download::Download;
+use crate::handshake::PROTOCOL;
+use crate::mse::{self, EncryptionPolicy};
+use crate::protocol::{with_timeout, BitTorrentStream, Transport, HANDSHAKE_TIMEOUT};
+use crate::utp::UtpSocket;
+use miette::miette;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// The length of the protocol string with its length prefix.
+const PROTOCOL_START_LEN: usize = 20;
+/// The delay before accepting again after a failure, doubled on each
+/// failure in a row.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+type Downloads = Arc<Mutex<HashMap<[u8; 20], Arc<Download>>>>;
+
+/// Accepts the connections of peers in the background, over TCP and
+/// optionally uTP. Each connection is routed to the download of the info
+/// hash in its handshake.
+pub struct Listener {
+    downloads: Downloads,
+    local_addr: SocketAddr,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Listener {
+    /// Binds the listener to the address and starts accepting connections.
+    pub async fn bind(address: SocketAddr) -> miette::Result<Self> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map(Arc::new)
+            .map_err(|err| miette!("failed to listen on {address}: {err}"))?;
+        let local_addr = listener.local_addr().map_err(|err| miette!(err))?;
+        let downloads = Downloads::default();
+        let task = tokio::spawn(accept(
+            move || {
+                let listener = listener.clone();
+                async move { listener.accept().await.map_err(|err| miette!(err)) }
+            },
+            downloads.clone(),
+        ));
+        Ok(Self {
+            downloads,
+            local_addr,
+            tasks: vec![task],
+        })
+    }
+
+    /// Also accepts the uTP connections of the socket.
+    pub fn accept_utp(&mut self, utp: Arc<UtpSocket>) {
+        self.tasks.push(tokio::spawn(accept(
+            move || {
+                let utp = utp.clone();
+                async move { utp.accept().await }
+            },
+            self.downloads.clone(),
+        )));
+    }
+
+    /// Returns the address the listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Routes the connections for the torrent of the download to it, by any
+    /// of its info hashes.
+    pub fn add(&self, download: Arc<Download>) {
+        let mut downloads = self.downloads.lock().expect("poisoned lock");
+        for info_hash in download.info_hashes() {
+            downloads.insert(info_hash, download.clone());
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Accepts connections with the function until the listener is dropped.
+/// Failures, such as running out of file descriptors, are retried after
+/// a backoff.
+async fn accept<F, Fut, T>(mut accept: F, downloads: Downloads)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = miette::Result<(T, SocketAddr)>>,
+    T: Transport,
+{
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        match accept().await {
+            Ok((stream, address)) => {
+                backoff = ACCEPT_BACKOFF;
+                let downloads = downloads.clone();
+                tokio::spawn(async move {
+                    let _ = handshake(Box::new(stream), address, downloads).await;
+                });
+            }
+            Err(err) => {
+                eprintln!("Failed to accept a connection: {err}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Answers the handshake of the peer if it is for one of our downloads,
+/// then hands the connection to the download. Encrypted connections are
+/// told apart by their first bytes, which aren't the protocol string.
+async fn handshake(
+    mut stream: Box<dyn Transport>,
+    address: SocketAddr,
+    downloads: Downloads,
+) -> miette::Result<()> {
+    let mut start = [0u8; PROTOCOL_START_LEN];
+    with_timeout(HANDSHAKE_TIMEOUT, "handshake", async {
+        stream
+            .read_exact(&mut start)
+            .await
+            .map_err(|err| miette!(err))
+    })
+    .await?;
+
+    let plaintext = start[0] as usize == PROTOCOL.len() && &start[1..] == PROTOCOL;
+    let (mut stream, encrypted_for) = if plaintext {
+        let stream = BitTorrentStream::with_negotiated(stream, None, start.to_vec());
+        (stream, None)
+    } else {
+        let torrents: Vec<_> = downloads
+            .lock()
+            .expect("poisoned lock")
+            .iter()
+            .map(|(info_hash, download)| (*info_hash, download.encryption()))
+            .collect();
+        let negotiated = with_timeout(
+            HANDSHAKE_TIMEOUT,
+            "encrypted handshake",
+            mse::receive(&mut stream, &start, &torrents),
+        )
+        .await?;
+        let info_hash = negotiated.info_hash;
+        let stream = BitTorrentStream::with_negotiated(
+            stream,
+            negotiated.ciphers,
+            negotiated.initial_payload,
+        );
+        (stream, Some(info_hash))
+    };
+
+    let prefix = stream.read_handshake_prefix().await?;
+    let download = downloads
+        .lock()
+        .expect("poisoned lock")
+        .get(prefix.info_hash())
+        .cloned()
+        .ok_or(miette!("unknown info hash"))?;
+    // The info hash is known, only the protocol is left to check
+    prefix.validate(prefix.info_hash())?;
+    match encrypted_for {
+        Some(info_hash) if !download.info_hashes().contains(&info_hash) => {
+            return Err(miette!(
+                "handshake for another torrent than the encrypted one"
+            ));
+        }
+        None if download.encryption() == EncryptionPolicy::Require => {
+            return Err(miette!("plaintext connection while encryption is required"));
+        }
+        _ => {}
+    }
+
+    let handshake = stream.answer_handshake(prefix, download.peer_id()).await?;
+    download.add_incoming(address, stream, handshake.extensions());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_incoming_connections() {
+        let data = b"hello world".to_vec();
+        let torrent = Arc::new(Torrent::single_piece(&data));
+        let seeder = Download::new(torrent.clone(), [1u8; 20]);
+        seeder.complete_piece(0, data.clone());
+
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        listener.add(seeder.clone());
+
+        // A handshake for another torrent is dropped
+        let mut stream = BitTorrentStream::new(listener.local_addr()).await.unwrap();
+        let other = Torrent::single_piece(b"other");
+        assert!(stream.handshake(&other, [2u8; 20]).await.is_err());
+
+        let leecher = Download::new(torrent, [2u8; 20]);
+        leecher.add_peers(&crate::peers::Peers(vec![listener.local_addr()]));
+        tokio::time::timeout(Duration::from_secs(5), leecher.wait_complete())
+            .await
+            .unwrap();
+        assert_eq!(leecher.data().unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_connections() {
+        let data = b"hello world".to_vec();
+        let torrent = Arc::new(Torrent::single_piece(&data));
+        let seeder = Download::new(torrent.clone(), [1u8; 20]);
+        seeder.set_encryption(EncryptionPolicy::Require);
+        seeder.complete_piece(0, data.clone());
+
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        listener.add(seeder.clone());
+
+        // Plaintext connections are refused
+        let mut stream = BitTorrentStream::new(listener.local_addr()).await.unwrap();
+        assert!(stream.handshake(&torrent, [2u8; 20]).await.is_err());
+
+        // Preferring encryption is enough
+        let leecher = Download::new(torrent, [2u8; 20]);
+        leecher.set_encryption(EncryptionPolicy::Prefer);
+        leecher.add_peers(&crate::peers::Peers(vec![listener.local_addr()]));
+        tokio::time::timeout(Duration::from_secs(5), leecher.wait_complete())
+            .await
+            .unwrap();
+        assert_eq!(leecher.data().unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_utp_connections() {
+        let data = b"hello world".to_vec();
+        let torrent = Arc::new(Torrent::single_piece(&data));
+        let seeder = Download::new(torrent.clone(), [1u8; 20]);
+        seeder.set_encryption(EncryptionPolicy::Require);
+        seeder.complete_piece(0, data.clone());
+
+        let mut listener = Listener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        listener.add(seeder.clone());
+        // Nothing listens over TCP on the port of the uTP socket
+        let utp = Arc::new(
+            UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap(),
+        );
+        listener.accept_utp(utp.clone());
+
+        let leecher = Download::new(torrent, [2u8; 20]);
+        leecher.set_encryption(EncryptionPolicy::Require);
+        leecher.set_utp(Arc::new(
+            UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap(),
+        ));
+        leecher.add_peers(&crate::peers::Peers(vec![utp.local_addr()]));
+        tokio::time::timeout(Duration::from_secs(5), leecher.wait_complete())
+            .await
+            .unwrap();
+        assert_eq!(leecher.data().unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_accept_backs_off() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let failing = move || {
+            counted.fetch_add(1, Ordering::Relaxed);
+            async { Err::<(tokio::net::TcpStream, _), _>(miette!("too many open files")) }
+        };
+
+        let accepting = accept(failing, Downloads::default());
+        let _ = tokio::time::timeout(Duration::from_millis(250), accepting).await;
+
+        // Attempts at 0 and 100 ms, then 300 ms, instead of spinning
+        let attempts = attempts.load(Ordering::Relaxed);
+        assert!((1..=3).contains(&attempts));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::download::Download;
+use crate::peers::Peers;
+use crate::random::random_bytes;
+use miette::miette;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// The port of the multicast groups.
+const LSD_PORT: u16 = 6771;
+const LSD_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+/// The interval between the announces of a torrent.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// The info hashes sent in a single announce, keeping it in one packet.
+const MAX_INFO_HASHES: usize = 20;
+
+type Downloads = Arc<Mutex<HashMap<[u8; 20], Arc<Download>>>>;
+
+/// A `BT-SEARCH` message, telling the local peers we have the torrents of
+/// the info hashes.
+#[derive(Debug, PartialEq, Eq)]
+struct Announce {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+impl Announce {
+    fn to_bytes(&self, host: &str) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {host}\r\nPort: {}\r\n",
+            self.port
+        );
+        for info_hash in &self.info_hashes {
+            message += &format!("Infohash: {}\r\n", hex::encode(info_hash));
+        }
+        if let Some(cookie) = &self.cookie {
+            message += &format!("cookie: {cookie}\r\n");
+        }
+        message += "\r\n\r\n";
+        message.into_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> miette::Result<Self> {
+        let message = std::str::from_utf8(bytes).map_err(|err| miette!(err))?;
+        let mut lines = message.lines();
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(miette!("not a BT-SEARCH message"));
+        }
+
+        let mut port = None;
+        let mut info_hashes = vec![];
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse().map_err(|_| miette!("invalid port"))?),
+                "infohash" => {
+                    let mut info_hash = [0u8; 20];
+                    hex::decode_to_slice(value, &mut info_hash)
+                        .map_err(|_| miette!("invalid info hash {value}"))?;
+                    info_hashes.push(info_hash);
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            port: port.ok_or(miette!("missing port"))?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Local Service Discovery (BEP 14). Announces our torrents to the
+/// multicast groups of the local network and adds the local peers
+/// announcing the same torrents to their download.
+pub struct Lsd {
+    downloads: Downloads,
+    sender: Arc<Sender>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// Sends the announces to the groups joined.
+struct Sender {
+    port: u16,
+    cookie: String,
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+}
+
+impl Lsd {
+    /// Joins the multicast groups the system supports, announcing the
+    /// connections are accepted on the port.
+    pub async fn start(port: u16) -> miette::Result<Self> {
+        let downloads = Downloads::default();
+        let cookie = hex::encode(random_bytes::<8>());
+        let mut sockets = vec![];
+        let mut tasks = vec![];
+
+        let groups = [
+            (Ipv4Addr::UNSPECIFIED.into(), LSD_GROUP_V4.into()),
+            (Ipv6Addr::UNSPECIFIED.into(), LSD_GROUP_V6.into()),
+        ];
+        for (unspecified, group) in groups {
+            let group = SocketAddr::new(group, LSD_PORT);
+            let Ok(receiver) = join(group).await else {
+                continue;
+            };
+            // Announces are sent from any port, the group port is taken
+            let Ok(socket) = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await else {
+                continue;
+            };
+            tasks.push(tokio::spawn(receive(
+                receiver,
+                downloads.clone(),
+                cookie.clone(),
+            )));
+            sockets.push((socket, group));
+        }
+        if sockets.is_empty() {
+            return Err(miette!("failed to join the local discovery groups"));
+        }
+
+        let sender = Arc::new(Sender {
+            port,
+            cookie,
+            sockets,
+        });
+        tasks.push(tokio::spawn(announce(sender.clone(), downloads.clone())));
+        Ok(Self {
+            downloads,
+            sender,
+            tasks,
+        })
+    }
+
+    /// Announces the torrent of the download, by each of its info hashes,
+    /// and adds the local peers of the torrent to it.
+    pub fn add(&self, download: Arc<Download>) {
+        let info_hashes = download.info_hashes();
+        let mut downloads = self.downloads.lock().expect("poisoned lock");
+        for info_hash in &info_hashes {
+            downloads.insert(*info_hash, download.clone());
+        }
+        let sender = self.sender.clone();
+        tokio::spawn(async move { sender.send(&info_hashes).await });
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Sender {
+    async fn send(&self, info_hashes: &[[u8; 20]]) {
+        for chunk in info_hashes.chunks(MAX_INFO_HASHES) {
+            let announce = Announce {
+                port: self.port,
+                info_hashes: chunk.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+            for (socket, group) in &self.sockets {
+                let _ = socket
+                    .send_to(&announce.to_bytes(&group.to_string()), group)
+                    .await;
+            }
+        }
+    }
+}
+
+/// Binds a socket to the group and joins it. Binding the group address
+/// rather than the wildcard one leaves the port free for the other family.
+async fn join(group: SocketAddr) -> miette::Result<UdpSocket> {
+    let socket = UdpSocket::bind(group)
+        .await
+        .map_err(|err| miette!("failed to bind {group}: {err}"))?;
+    match group {
+        SocketAddr::V4(group) => socket.join_multicast_v4(*group.ip(), Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(group) => socket.join_multicast_v6(group.ip(), 0),
+    }
+    .map_err(|err| miette!("failed to join {group}: {err}"))?;
+    Ok(socket)
+}
+
+/// Announces every torrent at each interval.
+async fn announce(sender: Arc<Sender>, downloads: Downloads) {
+    loop {
+        tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+        let info_hashes: Vec<_> = downloads
+            .lock()
+            .expect("poisoned lock")
+            .keys()
+            .copied()
+            .collect();
+        sender.send(&info_hashes).await;
+    }
+}
+
+/// Adds the peers of the announces received to the downloads.
+async fn receive(socket: UdpSocket, downloads: Downloads, cookie: String) {
+    let mut buffer = [0u8; 1500];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Some((info_hashes, peer)) = local_peer(&buffer[..len], from, &cookie) else {
+            continue;
+        };
+        let downloads = downloads.lock().expect("poisoned lock");
+        for download in info_hashes
+            .iter()
+            .filter_map(|info_hash| downloads.get(info_hash))
+        {
+            download.add_peers(&Peers(vec![peer]));
+        }
+    }
+}
+
+/// Returns the info hashes announced by a local peer, with the address it
+/// accepts connections on. Our own announces are ignored.
+fn local_peer(bytes: &[u8], from: SocketAddr, cookie: &str) -> Option<(Vec<[u8; 20]>, SocketAddr)> {
+    let announce = Announce::from_bytes(bytes).ok()?;
+    if announce.cookie.as_deref() == Some(cookie) || announce.port == 0 {
+        return None;
+    }
+    Some((
+        announce.info_hashes,
+        SocketAddr::new(from.ip(), announce.port),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announce() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("c00k1e".into()),
+        };
+        let bytes = announce.to_bytes("239.192.152.143:6771");
+        let expected = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: {}\r\nInfohash: {}\r\ncookie: c00k1e\r\n\r\n\r\n",
+            "ab".repeat(20),
+            "01".repeat(20)
+        );
+        assert_eq!(String::from_utf8(bytes.clone()).unwrap(), expected);
+        assert_eq!(Announce::from_bytes(&bytes).unwrap(), announce);
+
+        // Headers are case insensitive, the cookie optional
+        let message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHOST: [ff15::efc0:988f]:6771\r\nport:51413\r\nINFOHASH: {}\r\n\r\n\r\n",
+            "AB".repeat(20)
+        );
+        let announce = Announce::from_bytes(message.as_bytes()).unwrap();
+        assert_eq!(announce.port, 51413);
+        assert_eq!(announce.info_hashes, vec![[0xab; 20]]);
+        assert_eq!(announce.cookie, None);
+    }
+
+    #[test]
+    fn test_invalid_announce() {
+        assert!(Announce::from_bytes(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+        assert!(Announce::from_bytes(b"BT-SEARCH * HTTP/1.1\r\nInfohash: 00\r\n\r\n").is_err());
+        assert!(Announce::from_bytes(b"BT-SEARCH * HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_local_peer() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20]],
+            cookie: Some("theirs".into()),
+        };
+        let bytes = announce.to_bytes("239.192.152.143:6771");
+        let from = "192.168.1.20:6771".parse().unwrap();
+        let (info_hashes, peer) = local_peer(&bytes, from, "ours").unwrap();
+        assert_eq!(info_hashes, vec![[0xab; 20]]);
+        assert_eq!(peer, "192.168.1.20:6881".parse().unwrap());
+
+        // Our own announces come back through the loopback
+        assert!(local_peer(&bytes, from, "theirs").is_none());
+    }
+}
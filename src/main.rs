@@ -1,15 +1,50 @@
+mod bitfield;
+mod choker;
+mod connection;
 mod decode;
+mod dht;
+mod download;
+mod fast;
 mod handshake;
+mod listener;
+mod lsd;
+mod merkle;
+mod message;
+mod mse;
+mod peer_id;
 mod peers;
+mod priority;
 mod protocol;
+mod random;
+mod rate_limit;
+mod sha256;
 mod torrent;
+mod tracker;
+mod utp;
+mod webseed;
 
+use crate::dht::Dht;
+use crate::download::Download;
+use crate::listener::Listener;
+use crate::lsd::Lsd;
+use crate::mse::EncryptionPolicy;
+use crate::peer_id::{our_peer_id, Client};
 use crate::peers::Peers;
+use crate::priority::Priority;
 use crate::protocol::BitTorrentStream;
+use crate::rate_limit::RateLimits;
 use crate::torrent::Torrent;
+use crate::tracker::{Announcer, Trackers};
+use crate::utp::UtpSocket;
 use clap::{Parser, Subcommand};
 use decode::Decoder;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The port announced when not told otherwise.
+const DEFAULT_PORT: u16 = 6881;
 
 #[derive(Parser)]
 pub struct Cli {
@@ -28,6 +63,11 @@ pub enum Command {
     Peers {
         path: PathBuf,
     },
+    /// Prints the swarm stats of the torrents, as reported by their tracker.
+    Scrape {
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
     Handshake {
         path: PathBuf,
         peer_address: String,
@@ -43,6 +83,45 @@ pub enum Command {
         #[clap(short)]
         output: Option<PathBuf>,
         input: PathBuf,
+        /// Keeps seeding until this upload ratio is reached.
+        #[clap(long)]
+        seed_ratio: Option<f64>,
+        /// Keeps seeding for at most this amount of seconds.
+        #[clap(long)]
+        seed_time: Option<u64>,
+        /// The port to accept peer connections on.
+        #[clap(long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// The maximum download rate, in bytes per second.
+        #[clap(long)]
+        max_download_rate: Option<u64>,
+        /// The maximum upload rate, in bytes per second.
+        #[clap(long)]
+        max_upload_rate: Option<u64>,
+        /// The maximum download rate from each peer, in bytes per second.
+        #[clap(long)]
+        max_peer_download_rate: Option<u64>,
+        /// The maximum upload rate to each peer, in bytes per second.
+        #[clap(long)]
+        max_peer_upload_rate: Option<u64>,
+        /// Whether peer connections are encrypted, falling back to plaintext
+        /// with `prefer`.
+        #[clap(long, value_enum, default_value_t = EncryptionPolicy::Plaintext)]
+        encryption: EncryptionPolicy,
+        /// Connect to peers over uTP first, falling back to TCP.
+        #[clap(long)]
+        utp: bool,
+        /// Downloads only the files whose path matches the glob.
+        #[clap(long, conflicts_with = "files")]
+        only: Vec<String>,
+        /// Downloads only the files at these indexes, such as `1,3,5` or
+        /// `0,4-6` as the `so` parameter of magnet links.
+        #[clap(long)]
+        files: Option<String>,
+        /// Gives a priority to the files whose path matches the glob, as
+        /// `GLOB=PRIORITY` with skip, low, normal or high.
+        #[clap(long)]
+        priority: Vec<String>,
     },
 }
 
@@ -62,15 +141,43 @@ async fn main() {
         }
         Command::Peers { path } => {
             let torrent = Torrent::read_from_file(&path).expect("failed to read torrent");
-            let peers = Peers::get_peers(&torrent)
+            let peers = Peers::get_peers(&torrent, DEFAULT_PORT)
                 .await
                 .expect("failed to get peers");
             println!("{}", peers);
         }
+        Command::Scrape { paths } => {
+            let torrents = paths
+                .iter()
+                .map(|path| Torrent::read_from_file(path).expect("failed to read torrent"))
+                .collect::<Vec<_>>();
+            let results = tracker::scrape_torrents(&torrents).await;
+            for (torrent, res) in torrents.iter().zip(results) {
+                match res {
+                    Ok(stats) => println!(
+                        "{} {}: {} seeders, {} leechers, {} completed",
+                        torrent.info_hash(),
+                        torrent.info.name,
+                        stats.seeders,
+                        stats.leechers,
+                        stats.completed
+                    ),
+                    Err(err) => println!("{} {}: {err}", torrent.info_hash(), torrent.info.name),
+                }
+            }
+        }
         Command::Handshake { path, peer_address } => {
             let torrent = Torrent::read_from_file(&path).expect("failed to read torrent");
-            let mut stream = BitTorrentStream::new(&peer_address).await;
-            stream.handshake(&torrent).await.unwrap();
+            let mut stream = BitTorrentStream::new(&peer_address)
+                .await
+                .expect("failed to connect to peer");
+            let handshake = stream.handshake(&torrent, our_peer_id()).await.unwrap();
+            let peer_id = handshake.peer_id();
+            println!("Peer ID: {}", hex::encode(peer_id));
+            if let Some(client) = Client::identify(peer_id) {
+                eprintln!("Client: {client}");
+            }
+            eprintln!("Extensions: {:?}", handshake.extensions());
         }
         Command::DownloadPiece {
             input,
@@ -79,12 +186,12 @@ async fn main() {
         } => {
             // Get peers
             let torrent = Torrent::read_from_file(&input).expect("failed to read torrent");
-            let peers = Peers::get_peers(&torrent)
+            let peers = Peers::get_peers(&torrent, DEFAULT_PORT)
                 .await
                 .expect("failed to get peers");
             let peer = peers.0.first().expect("no peers");
 
-            let file = BitTorrentStream::connect_and_request_piece(peer, &torrent, index)
+            let file = BitTorrentStream::connect_and_request_piece(*peer, &torrent, index)
                 .await
                 .expect("failed to get piece");
 
@@ -93,43 +200,133 @@ async fn main() {
                 println!("Piece {index} downloaded to {path:?}");
             }
         }
-        Command::Download { input, output } => {
-            // Get peers
+        Command::Download {
+            input,
+            output,
+            seed_ratio,
+            seed_time,
+            port,
+            max_download_rate,
+            max_upload_rate,
+            max_peer_download_rate,
+            max_peer_upload_rate,
+            encryption,
+            utp,
+            only,
+            files,
+            priority: rules,
+        } => {
             let torrent = Torrent::read_from_file(&input).expect("failed to read torrent");
-            let peers = Peers::get_peers(&torrent)
-                .await
-                .expect("failed to get peers");
-            let full = torrent.info.length / torrent.info.piece_length;
+            let paths: Vec<_> = torrent
+                .info
+                .file_offsets()
+                .into_iter()
+                .map(|(_, file)| file.path.join("/"))
+                .collect();
+            let mut priorities = match files {
+                Some(files) => priority::select(paths.len(), &files).expect("invalid file indexes"),
+                None if !only.is_empty() => paths
+                    .iter()
+                    .map(|path| {
+                        if only.iter().any(|glob| priority::glob_match(glob, path)) {
+                            Priority::Normal
+                        } else {
+                            Priority::Skip
+                        }
+                    })
+                    .collect(),
+                None => vec![Priority::Normal; paths.len()],
+            };
+            // The rules apply after the selection, the last matching one wins
+            for rule in &rules {
+                let (glob, rule) = priority::parse_rule(rule).expect("invalid file priority");
+                for (path, slot) in paths.iter().zip(&mut priorities) {
+                    if priority::glob_match(&glob, path) {
+                        *slot = rule;
+                    }
+                }
+            }
 
-            // Split the indexes in chunks of peers, otherwise you risk
-            // hitting a "Peer connection reset" issue.
-            let indexes: Vec<u32> = (0..=full).collect();
-            let peers_len = peers.0.len();
-            let indexes = indexes.chunks(peers_len).collect::<Vec<_>>();
-            let mut file = Vec::with_capacity(torrent.info.length as usize);
+            let download = Download::new(Arc::new(torrent), our_peer_id());
+            download.set_file_priorities(priorities);
+            RateLimits::global().download.set_rate(max_download_rate);
+            RateLimits::global().upload.set_rate(max_upload_rate);
+            download.set_peer_rates(max_peer_download_rate, max_peer_upload_rate);
+            download.set_encryption(encryption);
 
-            // Create an iterator of futures which poll all available peers
-            // for the torrent file.
-            for group in indexes {
-                let futs = group
-                    .iter()
-                    .zip(peers.0.iter().cycle())
-                    .map(|(index, peer)| {
-                        BitTorrentStream::connect_and_request_piece(peer, &torrent, *index)
-                    });
-                let pieces = futures::future::join_all(futs)
-                    .await
-                    .into_iter()
-                    .collect::<Result<Vec<Vec<u8>>, _>>()
-                    .expect("failed to collect pieces");
+            // Listen on both families when the system allows it
+            let mut listener = match Listener::bind((Ipv6Addr::UNSPECIFIED, port).into()).await {
+                Ok(listener) => Ok(listener),
+                Err(_) => Listener::bind((Ipv4Addr::UNSPECIFIED, port).into()).await,
+            };
+            // The port actually bound is the one announced and used for uTP,
+            // the asked one may be 0
+            let port = listener
+                .as_ref()
+                .map_or(port, |listener| listener.local_addr().port());
+            // The uTP socket is shared with the DHT, which only speaks IPv4
+            let utp_socket = UtpSocket::bind((Ipv4Addr::UNSPECIFIED, port).into())
+                .await
+                .map(Arc::new);
+            match &mut listener {
+                Ok(listener) => {
+                    eprintln!("Listening on {}", listener.local_addr());
+                    listener.add(download.clone());
+                    if let Ok(utp_socket) = &utp_socket {
+                        listener.accept_utp(utp_socket.clone());
+                    }
+                }
+                Err(err) => eprintln!("Not accepting connections: {err}"),
+            }
+            match &utp_socket {
+                Ok(utp_socket) if utp => download.set_utp(utp_socket.clone()),
+                Err(err) => eprintln!("Not using uTP: {err}"),
+                _ => {}
+            }
 
-                pieces.into_iter().for_each(|mut p| file.append(&mut p));
+            // Local peers are found even when the trackers can't be reached
+            let lsd = Lsd::start(port).await;
+            match &lsd {
+                Ok(lsd) => lsd.add(download.clone()),
+                Err(err) => eprintln!("Not discovering local peers: {err}"),
             }
 
+            download.add_web_seeds(&download.torrent().url_list);
+            download.add_http_seeds(&download.torrent().http_seeds);
+
+            // Peers come from the trackers for the whole download, or once
+            // from the DHT for trackerless torrents
+            let announcer = if Trackers::new(download.torrent()).is_empty() {
+                let torrent = download.torrent();
+                let peers = match &utp_socket {
+                    Ok(utp_socket) => {
+                        let dht = Dht::start_shared(utp_socket, &torrent.nodes)
+                            .await
+                            .expect("failed to start the DHT");
+                        Peers::get_peers_from_dht(torrent, &dht, None).await
+                    }
+                    Err(_) => Peers::get_peers(torrent, port).await,
+                }
+                .expect("failed to get peers");
+                download.add_peers(&peers);
+                None
+            } else {
+                Some(Announcer::spawn(download.clone(), our_peer_id(), port))
+            };
+
+            download.wait_complete().await;
             if let Some(path) = output {
-                std::fs::write(&path, file).expect("failed to write file");
+                download.write_content(&path).expect("failed to write file");
                 println!("Downloaded {input:?} to {path:?}.");
             }
+
+            download
+                .seed(seed_ratio, seed_time.map(Duration::from_secs))
+                .await;
+            download.stop();
+            if let Some(announcer) = announcer {
+                announcer.stop().await;
+            }
         }
     }
 }
@@ -0,0 +1,162 @@
+use crate::sha256::sha256;
+
+/// The length of the blocks hashed as the leaves of the merkle trees.
+pub const BLOCK_LEN: usize = 16 * 1024;
+
+/// A node of a merkle tree.
+pub type Hash = [u8; 32];
+
+/// Returns the parent of two nodes.
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut pair = [0u8; 64];
+    pair[..32].copy_from_slice(left);
+    pair[32..].copy_from_slice(right);
+    sha256(&pair)
+}
+
+/// Returns the root of a subtree of the height with only zero leaves, used
+/// to pad the layers above the leaves.
+pub fn pad_hash(height: u32) -> Hash {
+    (0..height).fold([0u8; 32], |hash, _| parent(&hash, &hash))
+}
+
+/// Returns the layers of the tree over the hashes, from the hashes up to
+/// the root. The hashes are padded to the width, a power of two, with the
+/// pad hash.
+pub fn layers(hashes: &[Hash], width: usize, pad: Hash) -> Vec<Vec<Hash>> {
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(1), pad);
+    let mut layers = vec![layer];
+    while let [.., layer] = layers.as_slice() {
+        if layer.len() == 1 {
+            break;
+        }
+        let next = layer
+            .chunks_exact(2)
+            .map(|pair| parent(&pair[0], &pair[1]))
+            .collect();
+        layers.push(next);
+    }
+    layers
+}
+
+/// Returns the root of the tree over the hashes, padded to the width.
+pub fn root(hashes: &[Hash], width: usize, pad: Hash) -> Hash {
+    layers(hashes, width, pad)
+        .pop()
+        .and_then(|root| root.first().copied())
+        .expect("a tree has a root")
+}
+
+/// Returns the hashes of the blocks of the data, the last one can be
+/// shorter.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_LEN).map(sha256).collect()
+}
+
+/// Returns the hash of a piece in the piece layer. The blocks of the last
+/// piece of a file are padded with zero leaves to a whole piece.
+pub fn piece_hash(piece: &[u8], piece_length: u32) -> Hash {
+    root(
+        &block_hashes(piece),
+        piece_length as usize / BLOCK_LEN,
+        [0u8; 32],
+    )
+}
+
+/// Returns the pieces root of a file of a single piece, the tree over its
+/// blocks padded to a power of two.
+pub fn file_root(data: &[u8]) -> Hash {
+    let blocks = block_hashes(data);
+    root(&blocks, blocks.len().next_power_of_two(), [0u8; 32])
+}
+
+/// Returns the pieces root of a file from its piece layer, padded with the
+/// roots of pieces of zeros.
+pub fn layer_root(layer: &[Hash], piece_length: u32) -> Hash {
+    root(
+        layer,
+        layer.len().next_power_of_two(),
+        pad_hash(piece_height(piece_length)),
+    )
+}
+
+/// Returns the height of the subtree of a piece, its layer above the leaves.
+pub fn piece_height(piece_length: u32) -> u32 {
+    (piece_length as usize / BLOCK_LEN).trailing_zeros()
+}
+
+/// Returns the hashes of the layer from the index, followed by the uncle
+/// hashes proving them, from the bottom up. At most `proof_layers` uncles
+/// are sent, never the root. Returns None for a range out of the layer.
+pub fn hashes(
+    layers: &[Vec<Hash>],
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash>> {
+    let base = layers.first()?;
+    if !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > base.len() {
+        return None;
+    }
+    let mut hashes = base[index..index + length].to_vec();
+    // The uncles start at the layer of the root of the hashes
+    let height = length.trailing_zeros() as usize;
+    for (level, layer) in layers.iter().enumerate().skip(height).take(proof_layers) {
+        if layer.len() == 1 {
+            break;
+        }
+        hashes.push(layer[(index >> level) ^ 1]);
+    }
+    Some(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pad_hash() {
+        assert_eq!(pad_hash(0), [0u8; 32]);
+        assert_eq!(pad_hash(1), parent(&[0u8; 32], &[0u8; 32]));
+        // The subtree of a whole piece past the end of a file
+        assert_eq!(pad_hash(2), root(&[], 4, [0u8; 32]));
+    }
+
+    #[test]
+    fn test_layer_root() {
+        // The root of a file is the same from its blocks or its piece layer
+        let piece_length = 2 * BLOCK_LEN as u32;
+        let data: Vec<u8> = (0..5 * BLOCK_LEN + 100).map(|i| i as u8).collect();
+        let blocks = block_hashes(&data);
+        assert_eq!(blocks.len(), 6);
+
+        let layer: Vec<_> = data
+            .chunks(piece_length as usize)
+            .map(|piece| piece_hash(piece, piece_length))
+            .collect();
+        assert_eq!(layer.len(), 3);
+        assert_eq!(layer_root(&layer, piece_length), file_root(&data));
+    }
+
+    #[test]
+    fn test_hashes() {
+        let leaves: Vec<Hash> = (0..6u8).map(|i| sha256(&[i])).collect();
+        let layers = layers(&leaves, 8, [0u8; 32]);
+        let root = layers[3][0];
+
+        // Two hashes with their uncles prove the root
+        let proof = hashes(&layers, 2, 2, 8).unwrap();
+        assert_eq!(proof.len(), 4);
+        assert_eq!(&proof[..2], &leaves[2..4]);
+        let node = parent(&proof[0], &proof[1]);
+        let node = parent(&proof[2], &node);
+        assert_eq!(parent(&node, &proof[3]), root);
+
+        // Fewer proof layers, then invalid ranges
+        assert_eq!(hashes(&layers, 4, 4, 1).unwrap().len(), 5);
+        assert!(hashes(&layers, 1, 2, 0).is_none());
+        assert!(hashes(&layers, 0, 3, 0).is_none());
+        assert!(hashes(&layers, 8, 2, 0).is_none());
+    }
+}
@@ -0,0 +1,313 @@
+use miette::miette;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The largest message accepted from a peer. Leaves room for the bitfield
+/// of torrents with millions of pieces.
+const MAX_MESSAGE_LEN: u32 = 1 << 21;
+/// The length of a hash request, leading the hashes message.
+const HASH_REQUEST_LEN: usize = 48;
+
+/// A block of a piece, as requested or cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// A range of hashes in the merkle tree of a file, as requested or
+/// rejected (BEP 52).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    /// The layer of the hashes, 0 for the hashes of the blocks.
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    /// The amount of uncle layers proving the hashes.
+    pub proof_layers: u32,
+}
+
+/// A message of the peer wire protocol, exchanged after the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(BlockRequest),
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel(BlockRequest),
+    /// The messages of the Fast Extension, BEP 6.
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(BlockRequest),
+    AllowedFast(u32),
+    /// The messages of BitTorrent v2, BEP 52. The hashes are followed by
+    /// their uncle hashes.
+    HashRequest(HashRequest),
+    Hashes(HashRequest, Vec<[u8; 32]>),
+    HashReject(HashRequest),
+    /// A message we don't handle, kept so it can be skipped.
+    Unknown(u8, Vec<u8>),
+}
+
+impl Message {
+    /// Parses the message from its id and payload.
+    pub fn parse(id: u8, payload: Vec<u8>) -> miette::Result<Self> {
+        let message = match id {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(read_u32(&payload, 0)?),
+            5 => Message::Bitfield(payload),
+            6 => Message::Request(read_block_request(&payload)?),
+            7 => Message::Piece {
+                index: read_u32(&payload, 0)?,
+                begin: read_u32(&payload, 4)?,
+                block: payload[8..].to_vec(),
+            },
+            8 => Message::Cancel(read_block_request(&payload)?),
+            0x0d => Message::SuggestPiece(read_u32(&payload, 0)?),
+            0x0e => Message::HaveAll,
+            0x0f => Message::HaveNone,
+            0x10 => Message::RejectRequest(read_block_request(&payload)?),
+            0x11 => Message::AllowedFast(read_u32(&payload, 0)?),
+            0x15 => Message::HashRequest(read_hash_request(&payload, true)?),
+            0x16 => {
+                let request = read_hash_request(&payload, false)?;
+                let hashes = &payload[HASH_REQUEST_LEN..];
+                if !hashes.len().is_multiple_of(32) {
+                    return Err(miette!("invalid hashes length {}", hashes.len()));
+                }
+                let hashes = hashes
+                    .chunks_exact(32)
+                    .map(|hash| hash.try_into().expect("32 bytes"))
+                    .collect();
+                Message::Hashes(request, hashes)
+            }
+            0x17 => Message::HashReject(read_hash_request(&payload, true)?),
+            _ => Message::Unknown(id, payload),
+        };
+        Ok(message)
+    }
+
+    /// Serializes the message with its length prefix.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (id, payload) = match self {
+            Message::KeepAlive => return 0u32.to_be_bytes().to_vec(),
+            Message::Choke => (0, vec![]),
+            Message::Unchoke => (1, vec![]),
+            Message::Interested => (2, vec![]),
+            Message::NotInterested => (3, vec![]),
+            Message::Have(index) => (4, index.to_be_bytes().to_vec()),
+            Message::Bitfield(bitfield) => (5, bitfield.clone()),
+            Message::Request(request) => (6, block_request_bytes(request)),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let mut payload = Vec::with_capacity(8 + block.len());
+                payload.extend(index.to_be_bytes());
+                payload.extend(begin.to_be_bytes());
+                payload.extend(block);
+                (7, payload)
+            }
+            Message::Cancel(request) => (8, block_request_bytes(request)),
+            Message::SuggestPiece(index) => (0x0d, index.to_be_bytes().to_vec()),
+            Message::HaveAll => (0x0e, vec![]),
+            Message::HaveNone => (0x0f, vec![]),
+            Message::RejectRequest(request) => (0x10, block_request_bytes(request)),
+            Message::AllowedFast(index) => (0x11, index.to_be_bytes().to_vec()),
+            Message::HashRequest(request) => (0x15, hash_request_bytes(request)),
+            Message::Hashes(request, hashes) => {
+                let mut payload = hash_request_bytes(request);
+                payload.extend(hashes.iter().flatten());
+                (0x16, payload)
+            }
+            Message::HashReject(request) => (0x17, hash_request_bytes(request)),
+            Message::Unknown(id, payload) => (*id, payload.clone()),
+        };
+
+        let mut bytes = Vec::with_capacity(5 + payload.len());
+        bytes.extend((payload.len() as u32 + 1).to_be_bytes());
+        bytes.push(id);
+        bytes.extend(payload);
+        bytes
+    }
+}
+
+/// Reads the next message from the peer.
+pub async fn read_message(reader: &mut (impl AsyncRead + Unpin)) -> miette::Result<Message> {
+    let mut length = [0u8; 4];
+    reader
+        .read_exact(&mut length)
+        .await
+        .map_err(|err| miette!(err))?;
+    let length = u32::from_be_bytes(length);
+
+    if length == 0 {
+        return Ok(Message::KeepAlive);
+    }
+    if length > MAX_MESSAGE_LEN {
+        return Err(miette!("message of {length} bytes is too long"));
+    }
+
+    let mut buffer = vec![0u8; length as usize];
+    reader
+        .read_exact(&mut buffer)
+        .await
+        .map_err(|err| miette!(err))?;
+    let payload = buffer.split_off(1);
+
+    Message::parse(buffer[0], payload)
+}
+
+/// Writes the message to the peer.
+pub async fn write_message(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &Message,
+) -> miette::Result<()> {
+    writer
+        .write_all(&message.to_bytes())
+        .await
+        .map_err(|err| miette!(err))?;
+    writer.flush().await.map_err(|err| miette!(err))
+}
+
+fn read_u32(payload: &[u8], offset: usize) -> miette::Result<u32> {
+    let bytes = payload
+        .get(offset..offset + 4)
+        .ok_or(miette!("message payload is too short"))?;
+    Ok(u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+}
+
+fn read_block_request(payload: &[u8]) -> miette::Result<BlockRequest> {
+    if payload.len() != 12 {
+        return Err(miette!("invalid block request length {}", payload.len()));
+    }
+    Ok(BlockRequest {
+        index: read_u32(payload, 0)?,
+        begin: read_u32(payload, 4)?,
+        length: read_u32(payload, 8)?,
+    })
+}
+
+fn block_request_bytes(request: &BlockRequest) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(12);
+    payload.extend(request.index.to_be_bytes());
+    payload.extend(request.begin.to_be_bytes());
+    payload.extend(request.length.to_be_bytes());
+    payload
+}
+
+/// Reads a hash request, the whole payload unless hashes follow it.
+fn read_hash_request(payload: &[u8], exact: bool) -> miette::Result<HashRequest> {
+    if payload.len() < HASH_REQUEST_LEN || (exact && payload.len() != HASH_REQUEST_LEN) {
+        return Err(miette!("invalid hash request length {}", payload.len()));
+    }
+    Ok(HashRequest {
+        pieces_root: payload[..32].try_into().expect("32 bytes"),
+        base_layer: read_u32(payload, 32)?,
+        index: read_u32(payload, 36)?,
+        length: read_u32(payload, 40)?,
+        proof_layers: read_u32(payload, 44)?,
+    })
+}
+
+fn hash_request_bytes(request: &HashRequest) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(HASH_REQUEST_LEN);
+    payload.extend(request.pieces_root);
+    payload.extend(request.base_layer.to_be_bytes());
+    payload.extend(request.index.to_be_bytes());
+    payload.extend(request.length.to_be_bytes());
+    payload.extend(request.proof_layers.to_be_bytes());
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let request = BlockRequest {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        };
+        let hash_request = HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 2,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        };
+        let messages = [
+            Message::KeepAlive,
+            Message::Unchoke,
+            Message::Have(7),
+            Message::Bitfield(vec![0b1010_0000]),
+            Message::Request(request),
+            Message::Piece {
+                index: 1,
+                begin: 0,
+                block: b"hello".to_vec(),
+            },
+            Message::Cancel(request),
+            Message::SuggestPiece(3),
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest(request),
+            Message::AllowedFast(5),
+            Message::HashRequest(hash_request),
+            Message::Hashes(hash_request, vec![[1; 32], [2; 32], [3; 32]]),
+            Message::HashReject(hash_request),
+            Message::Unknown(20, b"d1:md1:ai1eee".to_vec()),
+        ];
+
+        let bytes = messages
+            .iter()
+            .flat_map(Message::to_bytes)
+            .collect::<Vec<_>>();
+        let mut reader = bytes.as_slice();
+        for message in messages {
+            assert_eq!(read_message(&mut reader).await.unwrap(), message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_messages() {
+        // Have without an index, then a request of 8 bytes
+        let mut reader: &[u8] = &[0, 0, 0, 1, 4];
+        assert!(read_message(&mut reader).await.is_err());
+        let mut reader: &[u8] = &[0, 0, 0, 9, 6, 0, 0, 0, 1, 0, 0, 0, 0];
+        assert!(read_message(&mut reader).await.is_err());
+        // Hashes which aren't whole
+        let mut bytes = Message::Hashes(
+            HashRequest {
+                pieces_root: [0; 32],
+                base_layer: 0,
+                index: 0,
+                length: 2,
+                proof_layers: 0,
+            },
+            vec![[1; 32]],
+        )
+        .to_bytes();
+        bytes[3] -= 1;
+        bytes.pop();
+        let mut reader = bytes.as_slice();
+        assert!(read_message(&mut reader).await.is_err());
+    }
+}
@@ -0,0 +1,495 @@
+mod dh;
+mod rc4;
+
+use crate::mse::dh::{KeyPair, KEY_LEN};
+use crate::mse::rc4::Rc4;
+use crate::random::random_u64;
+use clap::ValueEnum;
+use miette::miette;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The verification constant, encrypted to find where the padding ends.
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// The maximum length of the random paddings.
+const MAX_PAD_LEN: usize = 512;
+
+/// Whether connections are encrypted with Message Stream Encryption.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EncryptionPolicy {
+    /// Only plain BitTorrent connections.
+    #[default]
+    Plaintext,
+    /// Encrypted connections first, plain ones when the peer refuses.
+    Prefer,
+    /// Only encrypted connections.
+    Require,
+}
+
+impl EncryptionPolicy {
+    /// Returns the crypto methods offered to a peer.
+    fn provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Plaintext => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Require => CRYPTO_RC4,
+        }
+    }
+
+    /// Picks one of the crypto methods offered by a peer.
+    fn select(self, provided: u32) -> miette::Result<u32> {
+        let supported = provided & self.provide();
+        if supported & CRYPTO_RC4 != 0 {
+            Ok(CRYPTO_RC4)
+        } else if supported & CRYPTO_PLAINTEXT != 0 {
+            Ok(CRYPTO_PLAINTEXT)
+        } else {
+            Err(miette!("no common crypto method in {provided:#x}"))
+        }
+    }
+}
+
+/// The RC4 ciphers of the two directions of a connection.
+pub struct Ciphers {
+    pub encrypt: Rc4,
+    pub decrypt: Rc4,
+}
+
+/// The result of an encrypted handshake.
+pub struct Negotiated {
+    /// The ciphers of the payload, None when plaintext was selected.
+    pub ciphers: Option<Ciphers>,
+    /// The info hash the connection is for.
+    pub info_hash: [u8; 20],
+    /// The first bytes of the payload, sent with the handshake.
+    pub initial_payload: Vec<u8>,
+}
+
+/// Returns the RC4 cipher of one direction, `keyA` for the initiator and
+/// `keyB` for the receiver. The first 1024 bytes of the key stream are
+/// discarded.
+fn cipher(name: &[u8], secret: &[u8], info_hash: &[u8]) -> Rc4 {
+    let key = Sha1::new()
+        .chain_update(name)
+        .chain_update(secret)
+        .chain_update(info_hash)
+        .finalize();
+    let mut rc4 = Rc4::new(&key);
+    rc4.apply(&mut [0u8; 1024]);
+    rc4
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn random_pad() -> Vec<u8> {
+    let len = (random_u64() % (MAX_PAD_LEN as u64 + 1)) as usize;
+    let mut pad = vec![0u8; len];
+    for chunk in pad.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_be_bytes()[..chunk.len()]);
+    }
+    pad
+}
+
+async fn read_exact<S: AsyncRead + Unpin>(stream: &mut S, buffer: &mut [u8]) -> miette::Result<()> {
+    stream
+        .read_exact(buffer)
+        .await
+        .map(|_| ())
+        .map_err(|err| miette!(err))
+}
+
+async fn write_all<S: AsyncWrite + Unpin>(stream: &mut S, bytes: &[u8]) -> miette::Result<()> {
+    stream.write_all(bytes).await.map_err(|err| miette!(err))
+}
+
+/// Reads byte by byte until the pattern, which must come within the
+/// padding.
+async fn sync<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8]) -> miette::Result<()> {
+    let max_len = MAX_PAD_LEN + pattern.len();
+    let mut read = Vec::with_capacity(max_len);
+    while !read.ends_with(pattern) {
+        if read.len() >= max_len {
+            return Err(miette!("encrypted handshake didn't sync"));
+        }
+        let mut byte = [0u8];
+        read_exact(stream, &mut byte).await?;
+        read.push(byte[0]);
+    }
+    Ok(())
+}
+
+/// Runs the encrypted handshake of an outgoing connection for the torrent.
+/// The crypto methods offered follow the policy.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> miette::Result<Negotiated> {
+    let keys = KeyPair::generate();
+    let mut message = keys.public_key().to_vec();
+    message.extend(random_pad());
+    write_all(stream, &message).await?;
+
+    let mut public_key = [0u8; KEY_LEN];
+    read_exact(stream, &mut public_key).await?;
+    let secret = keys.shared_secret(&public_key);
+    let mut encrypt = cipher(b"keyA", &secret, info_hash);
+    let mut decrypt = cipher(b"keyB", &secret, info_hash);
+
+    // The torrent is told with hashes, then the encrypted offer with no
+    // padding and no initial payload
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    let mut offer = VC.to_vec();
+    offer.extend(policy.provide().to_be_bytes());
+    offer.extend(0u16.to_be_bytes());
+    offer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut offer);
+    message.extend(offer);
+    write_all(stream, &message).await?;
+
+    // The answer starts after the padding of the receiver, with the
+    // encrypted verification constant
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    sync(stream, &vc).await?;
+    let mut answer = [0u8; 6];
+    read_exact(stream, &mut answer).await?;
+    decrypt.apply(&mut answer);
+    let selected = u32::from_be_bytes(answer[..4].try_into().expect("4 bytes"));
+    let pad_len = u16::from_be_bytes([answer[4], answer[5]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(miette!("invalid padding length {pad_len}"));
+    }
+    let mut pad = vec![0u8; pad_len];
+    read_exact(stream, &mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    if selected & policy.provide() == 0 || selected.count_ones() != 1 {
+        return Err(miette!("peer selected crypto method {selected:#x}"));
+    }
+    Ok(Negotiated {
+        ciphers: (selected == CRYPTO_RC4).then_some(Ciphers { encrypt, decrypt }),
+        info_hash: *info_hash,
+        initial_payload: vec![],
+    })
+}
+
+/// Runs the encrypted handshake of an incoming connection, once the start
+/// of the public key of the peer is read. The torrent is found among the
+/// provided ones, with the policy to select the crypto method.
+pub async fn receive<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+    torrents: &[([u8; 20], EncryptionPolicy)],
+) -> miette::Result<Negotiated> {
+    let mut public_key = [0u8; KEY_LEN];
+    public_key[..start.len()].copy_from_slice(start);
+    read_exact(stream, &mut public_key[start.len()..]).await?;
+
+    let keys = KeyPair::generate();
+    let mut message = keys.public_key().to_vec();
+    message.extend(random_pad());
+    write_all(stream, &message).await?;
+    let secret = keys.shared_secret(&public_key);
+
+    sync(stream, &hash(&[b"req1", &secret])).await?;
+    let mut obfuscated = [0u8; 20];
+    read_exact(stream, &mut obfuscated).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let (info_hash, policy) = torrents
+        .iter()
+        .find(|(info_hash, _)| {
+            let req2 = hash(&[b"req2", info_hash]);
+            req2.iter().zip(req3).map(|(a, b)| a ^ b).eq(obfuscated)
+        })
+        .copied()
+        .ok_or(miette!("encrypted handshake for an unknown torrent"))?;
+    let mut encrypt = cipher(b"keyB", &secret, &info_hash);
+    let mut decrypt = cipher(b"keyA", &secret, &info_hash);
+
+    let mut offer = [0u8; 14];
+    read_exact(stream, &mut offer).await?;
+    decrypt.apply(&mut offer);
+    if offer[..8] != VC {
+        return Err(miette!("invalid verification constant"));
+    }
+    let provided = u32::from_be_bytes(offer[8..12].try_into().expect("4 bytes"));
+    let pad_len = u16::from_be_bytes([offer[12], offer[13]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(miette!("invalid padding length {pad_len}"));
+    }
+    let mut pad = vec![0u8; pad_len + 2];
+    read_exact(stream, &mut pad).await?;
+    decrypt.apply(&mut pad);
+    let payload_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+    let mut initial_payload = vec![0u8; payload_len];
+    read_exact(stream, &mut initial_payload).await?;
+    decrypt.apply(&mut initial_payload);
+
+    let selected = policy.select(provided)?;
+    let mut answer = VC.to_vec();
+    answer.extend(selected.to_be_bytes());
+    answer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut answer);
+    write_all(stream, &answer).await?;
+
+    Ok(Negotiated {
+        ciphers: (selected == CRYPTO_RC4).then_some(Ciphers { encrypt, decrypt }),
+        info_hash,
+        initial_payload,
+    })
+}
+
+/// The reading half of a peer connection, decrypting when the connection
+/// is encrypted. Bytes already read during the handshakes are returned
+/// first.
+pub struct CipherReader<R> {
+    inner: R,
+    buffered: Vec<u8>,
+    cipher: Option<Rc4>,
+}
+
+impl<R> CipherReader<R> {
+    pub fn new(inner: R, buffered: Vec<u8>, cipher: Option<Rc4>) -> Self {
+        Self {
+            inner,
+            buffered,
+            cipher,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CipherReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let len = this.buffered.len().min(buf.remaining());
+            buf.put_slice(&this.buffered[..len]);
+            this.buffered.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.cipher {
+            cipher.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The writing half of a peer connection, encrypting when the connection
+/// is encrypted. Encrypted bytes are kept until written, so the writer must
+/// be flushed.
+pub struct CipherWriter<W> {
+    inner: W,
+    cipher: Option<Rc4>,
+    pending: Vec<u8>,
+}
+
+impl<W> CipherWriter<W> {
+    pub fn new(inner: W, cipher: Option<Rc4>) -> Self {
+        Self {
+            inner,
+            cipher,
+            pending: vec![],
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> CipherWriter<W> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let len = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..len);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CipherWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        ready!(this.poll_pending(cx))?;
+        let mut encrypted = buf.to_vec();
+        if let Some(cipher) = &mut this.cipher {
+            cipher.apply(&mut encrypted);
+        }
+        this.pending = encrypted;
+        if let Poll::Ready(Err(err)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    async fn negotiate(
+        initiator: EncryptionPolicy,
+        receiver: EncryptionPolicy,
+    ) -> miette::Result<(Negotiated, Negotiated)> {
+        let (mut a, mut b) = duplex(4096);
+        let receive = async {
+            let mut start = [0u8; 20];
+            read_exact(&mut b, &mut start).await?;
+            let res = receive(&mut b, &start, &[(INFO_HASH, receiver)]).await;
+            // Let the initiator see the connection close on failures
+            drop(b);
+            res
+        };
+        let (initiated, received) = tokio::join!(initiate(&mut a, &INFO_HASH, initiator), receive);
+        Ok((initiated?, received?))
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let pattern = b"pattern";
+        let stream = |pad_len| {
+            let mut bytes = vec![0u8; pad_len];
+            bytes.extend(pattern);
+            bytes.extend(b"after");
+            bytes
+        };
+
+        let bytes = stream(MAX_PAD_LEN);
+        let mut reader = &bytes[..];
+        sync(&mut reader, pattern).await.unwrap();
+        assert_eq!(reader, b"after");
+
+        let bytes = stream(MAX_PAD_LEN + 1);
+        assert!(sync(&mut &bytes[..], pattern).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_select() {
+        let (initiated, received) = negotiate(EncryptionPolicy::Prefer, EncryptionPolicy::Prefer)
+            .await
+            .unwrap();
+        assert!(initiated.ciphers.is_some());
+        assert!(received.ciphers.is_some());
+        assert_eq!(received.info_hash, INFO_HASH);
+
+        let (initiated, received) =
+            negotiate(EncryptionPolicy::Prefer, EncryptionPolicy::Plaintext)
+                .await
+                .unwrap();
+        assert!(initiated.ciphers.is_none());
+        assert!(received.ciphers.is_none());
+
+        assert!(
+            negotiate(EncryptionPolicy::Require, EncryptionPolicy::Plaintext)
+                .await
+                .is_err()
+        );
+        assert!(
+            negotiate(EncryptionPolicy::Plaintext, EncryptionPolicy::Require)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_torrent() {
+        let (mut a, mut b) = duplex(4096);
+        let receive = async {
+            let mut start = [0u8; 20];
+            read_exact(&mut b, &mut start).await?;
+            let res = receive(&mut b, &start, &[([8; 20], EncryptionPolicy::Prefer)]).await;
+            drop(b);
+            res
+        };
+        let (initiated, received) = tokio::join!(
+            initiate(&mut a, &INFO_HASH, EncryptionPolicy::Prefer),
+            receive
+        );
+        assert!(initiated.is_err());
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_stream() {
+        let (initiated, received) = negotiate(EncryptionPolicy::Require, EncryptionPolicy::Prefer)
+            .await
+            .unwrap();
+        let initiated = initiated.ciphers.unwrap();
+        let received = received.ciphers.unwrap();
+
+        let (a, b) = duplex(4096);
+        let (read_a, write_a) = tokio::io::split(a);
+        let (read_b, write_b) = tokio::io::split(b);
+        let mut writer = CipherWriter::new(write_a, Some(initiated.encrypt));
+        let mut reader = CipherReader::new(read_b, vec![], Some(received.decrypt));
+        writer.write_all(b"hello world").await.unwrap();
+        writer.flush().await.unwrap();
+        let mut buffer = [0u8; 11];
+        reader.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello world");
+
+        // The bytes on the wire aren't the plaintext
+        let mut raw = read_a;
+        let mut writer = CipherWriter::new(write_b, Some(received.encrypt));
+        writer.write_all(b"hello world").await.unwrap();
+        writer.flush().await.unwrap();
+        raw.read_exact(&mut buffer).await.unwrap();
+        assert_ne!(&buffer, b"hello world");
+        let mut decrypt = initiated.decrypt;
+        decrypt.apply(&mut buffer);
+        assert_eq!(&buffer, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_buffered_bytes() {
+        let (a, _b) = duplex(64);
+        let mut reader = CipherReader::new(a, b"buffered".to_vec(), None);
+        let mut buffer = [0u8; 8];
+        reader.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"buffered");
+    }
+}
@@ -0,0 +1,182 @@
+use crate::random::random_bytes;
+
+/// The amount of 64 bits limbs of a 768 bits number.
+const LIMBS: usize = 12;
+
+/// The length in bytes of the public keys and of the shared secret.
+pub const KEY_LEN: usize = 96;
+
+/// The 768 bits prime of the key exchange, big endian.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+/// An unsigned 768 bits number, little endian limbs. Only the modular
+/// operations needed by the key exchange are implemented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct U768([u64; LIMBS]);
+
+impl U768 {
+    const ZERO: Self = Self([0; LIMBS]);
+
+    fn from_u64(value: u64) -> Self {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = value;
+        Self(limbs)
+    }
+
+    fn from_be_bytes(bytes: &[u8; KEY_LEN]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.rchunks(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().expect("8 bytes"));
+        }
+        Self(limbs)
+    }
+
+    fn to_be_bytes(self) -> [u8; KEY_LEN] {
+        let mut bytes = [0u8; KEY_LEN];
+        for (limb, chunk) in self.0.iter().zip(bytes.rchunks_mut(8)) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn prime() -> Self {
+        let bytes = hex::decode(PRIME).expect("prime is valid hex");
+        Self::from_be_bytes(&bytes.try_into().expect("prime is 96 bytes"))
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[index / 64] >> (index % 64) & 1 == 1
+    }
+
+    /// Returns (a + b) mod p, for a and b lower than p.
+    fn add_mod(self, other: Self, p: &Self) -> Self {
+        let mut sum = [0u64; LIMBS];
+        let mut carry = false;
+        for (i, limb) in sum.iter_mut().enumerate() {
+            let (value, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (value, c2) = value.overflowing_add(carry as u64);
+            *limb = value;
+            carry = c1 || c2;
+        }
+        let sum = Self(sum);
+        if carry || sum >= *p {
+            sum.wrapping_sub(p)
+        } else {
+            sum
+        }
+    }
+
+    fn wrapping_sub(self, other: &Self) -> Self {
+        let mut difference = [0u64; LIMBS];
+        let mut borrow = false;
+        for (i, limb) in difference.iter_mut().enumerate() {
+            let (value, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (value, b2) = value.overflowing_sub(borrow as u64);
+            *limb = value;
+            borrow = b1 || b2;
+        }
+        Self(difference)
+    }
+
+    /// Returns (a * b) mod p by doubling and adding, for a and b lower
+    /// than p.
+    fn mul_mod(self, other: Self, p: &Self) -> Self {
+        let mut result = Self::ZERO;
+        for index in (0..LIMBS * 64).rev() {
+            result = result.add_mod(result, p);
+            if other.bit(index) {
+                result = result.add_mod(self, p);
+            }
+        }
+        result
+    }
+
+    /// Returns base^exponent mod p, the exponent given big endian.
+    fn pow_mod(self, exponent: &[u8], p: &Self) -> Self {
+        let mut result = Self::from_u64(1);
+        for byte in exponent {
+            for shift in (0..8).rev() {
+                result = result.mul_mod(result, p);
+                if byte >> shift & 1 == 1 {
+                    result = result.mul_mod(self, p);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl PartialOrd for U768 {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for U768 {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+/// Our half of a Diffie-Hellman key exchange, with a 160 bits private key.
+pub struct KeyPair {
+    private: [u8; 20],
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self {
+            private: random_bytes(),
+        }
+    }
+
+    /// Returns the public key to send, 2^private mod P.
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        U768::from_u64(2)
+            .pow_mod(&self.private, &U768::prime())
+            .to_be_bytes()
+    }
+
+    /// Returns the secret shared with the owner of the public key.
+    pub fn shared_secret(&self, public_key: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        let prime = U768::prime();
+        let mut public_key = U768::from_be_bytes(public_key);
+        // An invalid key is reduced, the handshake fails later on
+        while public_key >= prime {
+            public_key = public_key.wrapping_sub(&prime);
+        }
+        public_key.pow_mod(&self.private, &prime).to_be_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let prime = U768::prime();
+        assert_eq!(hex::encode_upper(prime.to_be_bytes()), PRIME);
+    }
+
+    #[test]
+    fn test_pow_mod() {
+        let p = U768::prime();
+        // 3^5 = 243, below p
+        assert_eq!(U768::from_u64(3).pow_mod(&[5], &p), U768::from_u64(243));
+        // p - 1 is -1, its square is 1
+        let minus_one = p.wrapping_sub(&U768::from_u64(1));
+        assert_eq!(minus_one.mul_mod(minus_one, &p), U768::from_u64(1));
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        let a = KeyPair::generate();
+        let b = KeyPair::generate();
+
+        let secret = a.shared_secret(&b.public_key());
+
+        assert_eq!(secret, b.shared_secret(&a.public_key()));
+        assert_ne!(secret, [0u8; KEY_LEN]);
+    }
+}
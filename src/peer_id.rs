@@ -0,0 +1,197 @@
+use crate::random::random_bytes;
+use itertools::Itertools;
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+/// The prefix of our peer ids, in the Azureus style: client code `BT`,
+/// version 0001.
+const PREFIX: &[u8; 8] = b"-BT0001-";
+
+/// Azureus style client codes, as `-XXVVVV-`.
+const AZUREUS_CLIENTS: [(&str, &str); 20] = [
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent (Rasterbar)"),
+    ("lt", "libTorrent (Rakshasa)"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("RT", "rTorrent"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Shadow style client codes, as a letter followed by the version.
+const SHADOW_CLIENTS: [(u8, &str); 7] = [
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Returns our peer id. It is generated once per session: our prefix
+/// followed by 12 random bytes.
+pub fn our_peer_id() -> [u8; 20] {
+    static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+    *PEER_ID.get_or_init(|| {
+        let mut peer_id = [0u8; 20];
+        peer_id[..8].copy_from_slice(PREFIX);
+        peer_id[8..].copy_from_slice(&random_bytes::<12>());
+        peer_id
+    })
+}
+
+/// The client software of a remote peer, identified from its peer id.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Client {
+    pub name: String,
+    pub version: String,
+}
+
+impl Client {
+    /// Identifies the client from the peer id. Returns None for unknown
+    /// conventions.
+    pub fn identify(peer_id: &[u8; 20]) -> Option<Self> {
+        Self::azureus(peer_id)
+            .or_else(|| Self::mainline(peer_id))
+            .or_else(|| Self::shadow(peer_id))
+    }
+
+    /// Parses `-XXVVVV-`, where each version char is a digit or a letter.
+    fn azureus(peer_id: &[u8; 20]) -> Option<Self> {
+        if peer_id[0] != b'-' || peer_id[7] != b'-' {
+            return None;
+        }
+        let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+        let name = match AZUREUS_CLIENTS.iter().find(|(c, _)| *c == code) {
+            Some((_, name)) => name.to_string(),
+            None if code.chars().all(|c| c.is_ascii_alphanumeric()) => code.to_string(),
+            None => return None,
+        };
+        let version = peer_id[3..7]
+            .iter()
+            .map(|c| version_digit(*c))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            name,
+            version: trim_version(&version),
+        })
+    }
+
+    /// Parses `Mx-y-z--` or `Mx-yy-z-`.
+    fn mainline(peer_id: &[u8; 20]) -> Option<Self> {
+        if peer_id[0] != b'M' {
+            return None;
+        }
+        let version = std::str::from_utf8(&peer_id[1..8]).ok()?;
+        let parts = version
+            .trim_end_matches('-')
+            .split('-')
+            .map(|part| part.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if parts.len() != 3 {
+            return None;
+        }
+        Some(Self {
+            name: "Mainline".into(),
+            version: parts.iter().join("."),
+        })
+    }
+
+    /// Parses a letter followed by up to 5 version chars and dashes.
+    fn shadow(peer_id: &[u8; 20]) -> Option<Self> {
+        let (_, name) = SHADOW_CLIENTS.iter().find(|(c, _)| *c == peer_id[0])?;
+        if peer_id[6..9] != *b"---" {
+            return None;
+        }
+        let version = peer_id[1..6]
+            .iter()
+            .take_while(|c| **c != b'-')
+            .map(|c| version_digit(*c))
+            .collect::<Option<Vec<_>>>()?;
+        if version.is_empty() {
+            return None;
+        }
+        Some(Self {
+            name: name.to_string(),
+            version: trim_version(&version),
+        })
+    }
+}
+
+impl Display for Client {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+/// Returns the value of a version char: 0-9, then A-Z, then a-z.
+fn version_digit(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+/// Joins the version digits with dots, dropping the trailing zeros but
+/// keeping at least a major and a minor.
+fn trim_version(digits: &[u32]) -> String {
+    let mut len = digits.len();
+    while len > 2 && digits[len - 1] == 0 {
+        len -= 1;
+    }
+    digits[..len].iter().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(prefix: &[u8]) -> [u8; 20] {
+        let mut peer_id = [b'x'; 20];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        peer_id
+    }
+
+    #[test]
+    fn test_our_peer_id() {
+        let peer_id = our_peer_id();
+
+        // Same id for the whole session
+        assert_eq!(peer_id, our_peer_id());
+        assert_eq!(&peer_id[..8], PREFIX);
+    }
+
+    #[test]
+    fn test_identify() {
+        let cases: [(&[u8], &str); 6] = [
+            (b"-qB4250-", "qBittorrent 4.2.5"),
+            (b"-TR2940-", "Transmission 2.9.4"),
+            (b"-UT355W-", "µTorrent 3.5.5.32"),
+            (b"-ZZ1000-", "ZZ 1.0"),
+            (b"M4-3-6--", "Mainline 4.3.6"),
+            (b"S58B-----", "Shadow 5.8.11"),
+        ];
+
+        for (prefix, client) in cases {
+            assert_eq!(Client::identify(&id(prefix)).unwrap().to_string(), client);
+        }
+        assert_eq!(Client::identify(&[0u8; 20]), None);
+    }
+}
@@ -1,48 +1,92 @@
-use crate::decode::Decoder;
+use crate::dht::Dht;
+use crate::peer_id::our_peer_id;
 use crate::torrent::Torrent;
-use itertools::Itertools;
+use crate::tracker::{AnnounceParams, Trackers};
 use miette::miette;
-use serde::Serialize;
-use serde_json::Value;
 use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 /// The peers in the network.
-pub struct Peers(pub(crate) Vec<String>);
-
-#[derive(Serialize)]
-struct PeersQueryParams {
-    peer_id: String,
-    port: String,
-    uploaded: u32,
-    downloaded: u32,
-    left: u32,
-    compact: u8,
-}
+#[derive(Debug, Default)]
+pub struct Peers(pub(crate) Vec<SocketAddr>);
 
 impl Peers {
-    /// Get peers for the provided torrent.
-    pub async fn get_peers(torrent: &Torrent) -> miette::Result<Self> {
-        let params = PeersQueryParams {
-            peer_id: "00112233445566778899".to_string(),
-            port: "6881".to_string(),
+    /// Get peers for the provided torrent from its trackers. Trackerless
+    /// torrents get their peers from the DHT. The port is the one we
+    /// accept connections on.
+    pub async fn get_peers(torrent: &Torrent, port: u16) -> miette::Result<Self> {
+        let mut trackers = Trackers::new(torrent);
+        if trackers.is_empty() {
+            let dht = Dht::start(&torrent.nodes).await?;
+            return Self::get_peers_from_dht(torrent, &dht, Some(port)).await;
+        }
+
+        let params = AnnounceParams {
+            info_hash: torrent
+                .raw_info_hash()
+                .try_into()
+                .map_err(|_| miette!("invalid info hash"))?,
+            peer_id: our_peer_id(),
+            port,
             uploaded: 0,
             downloaded: 0,
-            left: torrent.info.length,
-            compact: 1,
+            left: torrent.info.length as u64,
+            event: None,
+            tracker_id: None,
         };
-        let info_hash = torrent.url_encoded_info_hash();
-        let encoded_params = serde_urlencoded::to_string(&params).map_err(|err| miette!(err))?;
-        let encoded_params = format!("{}&info_hash={}", encoded_params, info_hash);
-
-        let url = format!("{}?{}", torrent.announce, encoded_params);
+        let response = trackers.announce(&params).await?;
+        if let Some(warning) = &response.warning_message {
+            eprintln!("Tracker warning: {warning}");
+        }
+        Ok(response.peers)
+    }
 
-        let res = reqwest::get(url).await.map_err(|err| miette!(err))?;
-        let raw_res = res.bytes().await.map_err(|err| miette!(err))?;
+    /// Get peers for the provided torrent from the DHT, announcing ourselves
+    /// on the way. A hybrid torrent is looked up by both its info hashes.
+    /// Without a port, the nodes take the one the DHT sends from, which
+    /// is the port of a DHT sharing the uTP socket.
+    pub async fn get_peers_from_dht(
+        torrent: &Torrent,
+        dht: &Dht,
+        port: Option<u16>,
+    ) -> miette::Result<Self> {
+        let mut peers = vec![];
+        for info_hash in torrent.info_hashes() {
+            for peer in dht.announce(&info_hash, port).await? {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+        }
+        let _ = dht.save_nodes(&Dht::node_cache_path());
+        Ok(Peers(peers))
+    }
 
-        let mut decoder = Decoder::new(raw_res.as_ref());
-        let res = decoder.decode()?;
+    /// Parses peers in the compact format: 4 bytes ip, 2 bytes port.
+    pub fn from_compact(bytes: &[u8]) -> Self {
+        let peers = bytes
+            .chunks_exact(6)
+            .map(|peer| {
+                let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+                let port = u16::from_be_bytes([peer[4], peer[5]]);
+                SocketAddr::V4(SocketAddrV4::new(ip, port))
+            })
+            .collect();
+        Peers(peers)
+    }
 
-        res.try_into()
+    /// Parses IPv6 peers in the compact format: 16 bytes ip, 2 bytes port
+    /// (BEP 7).
+    pub fn from_compact6(bytes: &[u8]) -> Self {
+        let peers = bytes
+            .chunks_exact(18)
+            .map(|peer| {
+                let ip: [u8; 16] = peer[..16].try_into().expect("chunk is 18 bytes");
+                let port = u16::from_be_bytes([peer[16], peer[17]]);
+                SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+            })
+            .collect();
+        Peers(peers)
     }
 }
 
@@ -54,25 +98,3 @@ impl Display for Peers {
         Ok(())
     }
 }
-
-impl TryFrom<Value> for Peers {
-    type Error = miette::Error;
-    fn try_from(value: Value) -> miette::Result<Self> {
-        let map = value.as_object().ok_or(miette!("expected object"))?;
-        let peers = map.get("peers").ok_or(miette!("missing peers key"))?;
-        let peers = peers.as_str().ok_or(miette!("expected str for peers"))?;
-        let peers = hex::decode(peers).map_err(|_| miette!("failed decoding peers hex str"))?;
-        let peers = peers
-            .chunks(6)
-            .filter_map(|peer| {
-                let ip = &peer[..4].iter().map(|b| format!("{b}")).join(".");
-                let port = format!(
-                    "{}",
-                    u32::from_str_radix(&hex::encode(&peer[4..]), 16).ok()?
-                );
-                Some(format!("{ip}:{port}"))
-            })
-            .collect::<Vec<_>>();
-        Ok(Peers(peers))
-    }
-}
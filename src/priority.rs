@@ -0,0 +1,140 @@
+use clap::ValueEnum;
+use miette::miette;
+use std::ops::RangeInclusive;
+
+/// How much a file of a torrent is wanted. The pieces of the files with a
+/// higher priority are downloaded first, those of skipped files never.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Priority {
+    /// Not downloaded, unless it shares a piece with a wanted file.
+    Skip,
+    /// Downloaded after the other files.
+    Low,
+    #[default]
+    Normal,
+    /// Downloaded before the other files.
+    High,
+}
+
+/// Parses the file indexes and inclusive ranges of indexes, such as
+/// `0,2,4-6`, the syntax of the `so` parameter of magnet links (BEP 53).
+pub fn parse_indexes(value: &str) -> miette::Result<Vec<RangeInclusive<usize>>> {
+    let parse = |index: &str| {
+        index
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| miette!("invalid file index {index:?}"))
+    };
+    value
+        .split(',')
+        .map(|part| match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(miette!("invalid file range {part:?}"));
+                }
+                Ok(start..=end)
+            }
+            None => parse(part).map(|index| index..=index),
+        })
+        .collect()
+}
+
+/// Returns the priorities of the files of a torrent with only the files
+/// at the indexes selected, as with the `so` parameter of a magnet link.
+/// Indexes past the files are ignored.
+pub fn select(count: usize, indexes: &str) -> miette::Result<Vec<Priority>> {
+    let ranges = parse_indexes(indexes)?;
+    Ok((0..count)
+        .map(|index| {
+            if ranges.iter().any(|range| range.contains(&index)) {
+                Priority::Normal
+            } else {
+                Priority::Skip
+            }
+        })
+        .collect())
+}
+
+/// Parses a `GLOB=PRIORITY` rule giving a priority to the matching files.
+pub fn parse_rule(value: &str) -> miette::Result<(String, Priority)> {
+    let (glob, priority) = value
+        .rsplit_once('=')
+        .ok_or(miette!("expected GLOB=PRIORITY, got {value:?}"))?;
+    let priority = Priority::from_str(priority, true).map_err(|err| miette!(err))?;
+    Ok((glob.to_string(), priority))
+}
+
+/// Returns true if the path of a file, its parts joined with `/`, matches
+/// the glob. `*` matches within a directory, `**` across directories and
+/// `?` a single character.
+pub fn glob_match(glob: &str, path: &str) -> bool {
+    fn matches(glob: &[char], path: &[char]) -> bool {
+        match glob {
+            [] => path.is_empty(),
+            // Zero or more whole directories
+            ['*', '*', '/', rest @ ..] => (0..=path.len())
+                .filter(|i| *i == 0 || path[i - 1] == '/')
+                .any(|i| matches(rest, &path[i..])),
+            ['*', '*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+            ['*', rest @ ..] => {
+                let end = path.iter().position(|c| *c == '/').unwrap_or(path.len());
+                (0..=end).any(|i| matches(rest, &path[i..]))
+            }
+            ['?', rest @ ..] => {
+                path.first().is_some_and(|c| *c != '/') && matches(rest, &path[1..])
+            }
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+    let glob: Vec<char> = glob.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches(&glob, &path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_indexes() {
+        assert_eq!(parse_indexes("1,3,5").unwrap(), vec![1..=1, 3..=3, 5..=5]);
+        assert_eq!(parse_indexes("0,4-6").unwrap(), vec![0..=0, 4..=6]);
+        assert!(parse_indexes("").is_err());
+        assert!(parse_indexes("2-1").is_err());
+        assert!(parse_indexes("a").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        use Priority::*;
+        assert_eq!(
+            select(5, "0,2-3,9").unwrap(),
+            [Normal, Skip, Normal, Normal, Skip]
+        );
+        assert!(select(5, "1,x").is_err());
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            parse_rule("a=b=HIGH").unwrap(),
+            ("a=b".to_string(), Priority::High)
+        );
+        assert!(parse_rule("*.txt").is_err());
+        assert!(parse_rule("*.txt=urgent").is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.mkv", "movie.mkv"));
+        assert!(!glob_match("*.mkv", "extras/movie.mkv"));
+        assert!(glob_match("*/*.mkv", "extras/movie.mkv"));
+        assert!(glob_match("**/*.mkv", "movie.mkv"));
+        assert!(glob_match("**/*.mkv", "a/b/movie.mkv"));
+        assert!(glob_match("disc?/**", "disc1/track/01.flac"));
+        assert!(!glob_match("disc?/**", "disc10/01.flac"));
+        assert!(glob_match("readme", "readme"));
+        assert!(!glob_match("readme", "readme.txt"));
+    }
+}
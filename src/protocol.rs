@@ -1,105 +1,302 @@
-use crate::handshake::HandShake;
+use crate::handshake::{HandShake, HANDSHAKE_PREFIX_LEN};
+use crate::message::{read_message, Message};
+use crate::mse::{self, CipherReader, CipherWriter, Ciphers, EncryptionPolicy};
+use crate::peer_id::our_peer_id;
 use crate::torrent::Torrent;
+use crate::utp::{UtpSocket, UtpStream};
+use itertools::Itertools;
 use miette::miette;
-use std::mem::size_of;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+use tokio::time::timeout;
 
-const MY_PEER_ID: [u8; 20] = *b"00112233445566778899";
 pub const SIXTEEN_KILO_BYTES: u32 = 1 << 14;
 
-/// The bit torrent protocol stream. Wraps the tcp connection
-/// and adds methods to handle the various message.
-pub struct BitTorrentStream(tokio::net::TcpStream);
+/// The time to connect to an address.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The time for the peer to answer a handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+/// The time for the peer to send a message we wait for.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A reliable ordered byte stream to a peer, the connection under a
+/// [`BitTorrentStream`].
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl Transport for TcpStream {}
+
+impl Transport for UtpStream {}
+
+/// The reading half of a peer connection.
+pub type PeerReader = CipherReader<ReadHalf<Box<dyn Transport>>>;
+/// The writing half of a peer connection.
+pub type PeerWriter = CipherWriter<WriteHalf<Box<dyn Transport>>>;
+
+/// Connects to the peer over uTP when a socket of the same family is
+/// provided, falling back to TCP.
+async fn dial(address: SocketAddr, utp: Option<&UtpSocket>) -> miette::Result<Box<dyn Transport>> {
+    if let Some(utp) = utp.filter(|utp| utp.local_addr().is_ipv4() == address.is_ipv4()) {
+        if let Ok(Ok(stream)) = timeout(CONNECT_TIMEOUT, utp.connect(address)).await {
+            return Ok(Box::new(stream));
+        }
+    }
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| miette!("connection to {address} timed out"))?
+        .map_err(|err| miette!("failed to connect to {address}: {err}"))?;
+    Ok(Box::new(stream))
+}
+
+/// The bit torrent protocol stream. Wraps the connection, TCP or uTP and
+/// encrypted or not, and adds methods to handle the various message.
+pub struct BitTorrentStream {
+    reader: PeerReader,
+    writer: PeerWriter,
+}
 
 impl BitTorrentStream {
-    /// Returns a new [`BitTorrentStream`].
-    pub async fn new(address: &str) -> Self {
-        BitTorrentStream(
-            tokio::net::TcpStream::connect(address)
-                .await
-                .expect("failed to connect to peer"),
-        )
+    /// Returns a new [`BitTorrentStream`]. When the address resolves to
+    /// both IPv6 and IPv4 addresses, the families are tried alternately,
+    /// IPv6 first, until one connects.
+    pub async fn new(address: impl ToSocketAddrs) -> miette::Result<Self> {
+        let (v6, v4): (Vec<_>, Vec<_>) = lookup_host(address)
+            .await
+            .map_err(|err| miette!(err))?
+            .partition(SocketAddr::is_ipv6);
+
+        let mut last_err = miette!("no address to connect to");
+        for addr in v6.into_iter().interleave(v4) {
+            match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(BitTorrentStream::from_transport(Box::new(stream))),
+                Ok(Err(err)) => last_err = miette!("failed to connect to {addr}: {err}"),
+                Err(_) => last_err = miette!("connection to {addr} timed out"),
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Returns a plaintext stream over the connection.
+    pub fn from_transport(transport: Box<dyn Transport>) -> Self {
+        Self::with_negotiated(transport, None, vec![])
+    }
+
+    /// Returns a stream over the connection, encrypted with the ciphers if
+    /// any. The buffered bytes are read first.
+    pub fn with_negotiated(
+        transport: Box<dyn Transport>,
+        ciphers: Option<Ciphers>,
+        buffered: Vec<u8>,
+    ) -> Self {
+        let (reader, writer) = tokio::io::split(transport);
+        let (encrypt, decrypt) = match ciphers {
+            Some(ciphers) => (Some(ciphers.encrypt), Some(ciphers.decrypt)),
+            None => (None, None),
+        };
+        Self {
+            reader: CipherReader::new(reader, buffered, decrypt),
+            writer: CipherWriter::new(writer, encrypt),
+        }
+    }
+
+    /// Connects to the peer for the torrent, over uTP first when a socket
+    /// is provided, and encrypting the connection as the policy asks. When
+    /// encryption is only preferred, a peer refusing it is connected to
+    /// again in plaintext.
+    pub async fn open(
+        address: SocketAddr,
+        info_hash: &[u8; 20],
+        policy: EncryptionPolicy,
+        utp: Option<&UtpSocket>,
+    ) -> miette::Result<Self> {
+        if policy == EncryptionPolicy::Plaintext {
+            return Ok(Self::from_transport(dial(address, utp).await?));
+        }
+
+        let res = async {
+            let mut stream = dial(address, utp).await?;
+            let negotiated = with_timeout(
+                HANDSHAKE_TIMEOUT,
+                "encrypted handshake",
+                mse::initiate(&mut stream, info_hash, policy),
+            )
+            .await?;
+            Ok(Self::with_negotiated(stream, negotiated.ciphers, vec![]))
+        }
+        .await;
+
+        match res {
+            Err(_) if policy == EncryptionPolicy::Prefer => {
+                Ok(Self::from_transport(dial(address, utp).await?))
+            }
+            res => res,
+        }
+    }
+
+    /// Splits the stream, to read and write messages concurrently.
+    pub fn into_split(self) -> (PeerReader, PeerWriter) {
+        (self.reader, self.writer)
+    }
+
+    /// Connects to the peer, handshakes and waits until the peer unchokes
+    /// us. A peer with the Fast extension can send have all instead of its
+    /// bitfield.
+    pub async fn connect(address: SocketAddr, torrent: &Torrent) -> miette::Result<Self> {
+        // Perform handshake
+        let mut stream = BitTorrentStream::new(address).await?;
+        stream.handshake(torrent, our_peer_id()).await?;
+
+        // Wait for the pieces of the peer
+        match stream.next_message().await? {
+            Message::Bitfield(_) | Message::HaveAll => {}
+            Message::HaveNone => return Err(miette!("peer has no pieces")),
+            message => return Err(miette!("expected a bitfield, got {message:?}")),
+        }
+
+        // Send an interested message
+        stream.send_message(2, vec![]).await?;
+
+        // Wait for an unchoke message
+        match stream.next_message().await? {
+            Message::Unchoke => Ok(stream),
+            message => Err(miette!("expected an unchoke, got {message:?}")),
+        }
+    }
+
+    /// Waits for the next message of the peer, skipping the keep-alives and
+    /// the hints of the Fast extension.
+    async fn next_message(&mut self) -> miette::Result<Message> {
+        with_timeout(REQUEST_TIMEOUT, "message", async {
+            loop {
+                match read_message(&mut self.reader).await? {
+                    Message::KeepAlive | Message::SuggestPiece(_) | Message::AllowedFast(_) => {}
+                    message => return Ok(message),
+                }
+            }
+        })
+        .await
     }
 
     /// Connect to the tcp stream and request the torrent piece for the
     /// provided index.
     pub async fn connect_and_request_piece(
-        address: &str,
+        address: SocketAddr,
         torrent: &Torrent,
         index: u32,
     ) -> miette::Result<Vec<u8>> {
-        // Perform handshake
-        let mut stream = BitTorrentStream::new(&address).await;
-        stream.handshake(&torrent).await.expect("handshake failed");
-
-        // Wait for the bitfield
-        stream
-            .wait_message(5)
-            .await
-            .expect("missing bitfield message");
-
-        // Send an interested message
-        stream
-            .send_message(2, vec![])
-            .await
-            .expect("failed to send interested");
+        let mut stream = BitTorrentStream::connect(address, torrent).await?;
+        stream.download_piece(torrent, index).await
+    }
 
-        // Wait for an unchoke message
-        stream
-            .wait_message(1)
-            .await
-            .expect("failed to get unchoke message");
-
-        // Request each full piece
-        let mut file = Vec::with_capacity(torrent.info.piece_length as usize);
-        let full = torrent.info.length / torrent.info.piece_length;
-
-        // The piece length will be torrent.info.piece_length if the index of the
-        // piece isn't the last one, or torrent.info.length % torrent.info.piece_length
-        let piece_len = if index == full {
-            torrent.info.length % torrent.info.piece_length
-        } else {
-            torrent.info.piece_length
-        };
+    /// Downloads the piece for the provided index, block by block.
+    pub async fn download_piece(
+        &mut self,
+        torrent: &Torrent,
+        index: u32,
+    ) -> miette::Result<Vec<u8>> {
+        let piece_len = torrent.info.piece_len(index);
+        let mut file = Vec::with_capacity(piece_len as usize);
 
-        // Request all full pieces
+        // Request all full blocks
         for i in 0..piece_len / SIXTEEN_KILO_BYTES {
-            stream
-                .request_piece(index, i * SIXTEEN_KILO_BYTES, SIXTEEN_KILO_BYTES, &mut file)
-                .await
-                .expect("failed to request piece");
+            self.request_piece(index, i * SIXTEEN_KILO_BYTES, SIXTEEN_KILO_BYTES, &mut file)
+                .await?;
         }
 
-        // Request the last piece
+        // Request the last block, if the piece isn't a multiple of the
+        // block size
         let size = piece_len % SIXTEEN_KILO_BYTES;
-        let offset = piece_len - size;
-        stream
-            .request_piece(index, offset, size, &mut file)
-            .await
-            .expect("failed to request piece");
+        if size > 0 {
+            let offset = piece_len - size;
+            self.request_piece(index, offset, size, &mut file).await?;
+        }
 
         Ok(file)
     }
 
-    /// Handshakes with the peer for the provided torrent.
-    pub async fn handshake(&mut self, torrent: &Torrent) -> miette::Result<()> {
-        let mut handshake = HandShake::new(torrent.raw_info_hash().as_ref(), MY_PEER_ID);
-        let handshake = &mut handshake as *mut HandShake as *mut [u8; size_of::<HandShake>()];
-        let handshake: &mut [u8; size_of::<HandShake>()] = unsafe { &mut *handshake };
+    /// Handshakes with the peer for the provided torrent. Returns the
+    /// handshake of the peer, once checked it is for the same torrent and
+    /// isn't from ourselves.
+    pub async fn handshake(
+        &mut self,
+        torrent: &Torrent,
+        peer_id: [u8; 20],
+    ) -> miette::Result<HandShake> {
+        let info_hash = torrent.raw_info_hash();
+        with_timeout(HANDSHAKE_TIMEOUT, "handshake", async {
+            self.send_handshake(&info_hash, peer_id).await?;
 
-        self.0
-            .write_all(handshake)
-            .await
-            .expect("failed to write in stream");
-        self.0
-            .read_exact(handshake)
+            // The prefix is checked before waiting for the peer id
+            let response = self.read_handshake_prefix().await?;
+            response.validate(&info_hash)?;
+            self.read_peer_id(response, peer_id).await
+        })
+        .await
+    }
+
+    /// Answers the handshake of a peer which connected to us, once its
+    /// prefix is read and validated. Returns the complete handshake of the
+    /// peer.
+    pub async fn answer_handshake(
+        &mut self,
+        prefix: HandShake,
+        peer_id: [u8; 20],
+    ) -> miette::Result<HandShake> {
+        with_timeout(HANDSHAKE_TIMEOUT, "handshake", async {
+            self.send_handshake(prefix.info_hash(), peer_id).await?;
+            self.read_peer_id(prefix, peer_id).await
+        })
+        .await
+    }
+
+    /// Reads the handshake of the peer up to its peer id, which is enough
+    /// to know the torrent it is for.
+    pub async fn read_handshake_prefix(&mut self) -> miette::Result<HandShake> {
+        let mut prefix = [0u8; HANDSHAKE_PREFIX_LEN];
+        with_timeout(HANDSHAKE_TIMEOUT, "handshake", async {
+            self.reader
+                .read_exact(&mut prefix)
+                .await
+                .map_err(|err| miette!(err))
+        })
+        .await?;
+        HandShake::from_bytes(&prefix)
+    }
+
+    async fn send_handshake(&mut self, info_hash: &[u8], peer_id: [u8; 20]) -> miette::Result<()> {
+        let handshake = HandShake::new(info_hash, peer_id);
+        self.write(&handshake.to_bytes()).await
+    }
+
+    /// Writes the bytes, flushing them through the cipher.
+    async fn write(&mut self, bytes: &[u8]) -> miette::Result<()> {
+        self.writer
+            .write_all(bytes)
             .await
-            .expect("failed to read stream");
+            .map_err(|err| miette!(err))?;
+        self.writer.flush().await.map_err(|err| miette!(err))
+    }
 
-        let offset = size_of::<HandShake>() - 20;
-        println!("Peer ID: {}", hex::encode(&handshake[offset..]));
+    /// Completes the handshake of the peer with its peer id, dropping
+    /// connections to ourselves.
+    async fn read_peer_id(
+        &mut self,
+        mut handshake: HandShake,
+        peer_id: [u8; 20],
+    ) -> miette::Result<HandShake> {
+        let mut remote_peer_id = [0u8; 20];
+        self.reader
+            .read_exact(&mut remote_peer_id)
+            .await
+            .map_err(|err| miette!(err))?;
+        handshake.set_peer_id(remote_peer_id);
 
-        Ok(())
+        if remote_peer_id == peer_id {
+            return Err(miette!("connected to ourselves"));
+        }
+        Ok(handshake)
     }
 
     /// Makes a request for a piece to the stream. Modifies the provided mutable
@@ -132,9 +329,13 @@ impl BitTorrentStream {
 
     /// Waits until a message with the provided id comes from the stream.
     pub async fn wait_message(&mut self, id: u8) -> miette::Result<Vec<u8>> {
+        with_timeout(REQUEST_TIMEOUT, "message", self.read_expected_message(id)).await
+    }
+
+    async fn read_expected_message(&mut self, id: u8) -> miette::Result<Vec<u8>> {
         // Read the message length in bytes
         let mut length = [0u8; 4];
-        self.0
+        self.reader
             .read_exact(&mut length)
             .await
             .map_err(|err| miette!(err))?;
@@ -147,7 +348,7 @@ impl BitTorrentStream {
 
         // Read the msg id
         let mut msg_id = [0u8; 1];
-        self.0
+        self.reader
             .read_exact(&mut msg_id)
             .await
             .map_err(|err| miette!(err))?;
@@ -159,7 +360,7 @@ impl BitTorrentStream {
         }
 
         let mut payload = vec![0; (length - 1) as usize];
-        self.0
+        self.reader
             .read_exact(&mut payload)
             .await
             .map_err(|err| miette!(err))?;
@@ -177,11 +378,73 @@ impl BitTorrentStream {
         buffer.push(id);
         buffer.append(&mut payload);
 
-        self.0
-            .write_all(&buffer)
-            .await
-            .map_err(|err| miette!(err))?;
+        self.write(&buffer).await
+    }
+}
 
-        Ok(())
+/// Runs the future, failing once the duration elapsed.
+pub async fn with_timeout<T>(
+    duration: Duration,
+    what: &str,
+    future: impl Future<Output = miette::Result<T>>,
+) -> miette::Result<T> {
+    timeout(duration, future)
+        .await
+        .map_err(|_| miette!("{what} timed out"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::HANDSHAKE_LEN;
+    use crate::message::{write_message, BlockRequest};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_download_piece_from_fast_peer() {
+        let data = b"hello world".to_vec();
+        let torrent = Torrent::single_piece(&data);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let info_hash = torrent.raw_info_hash();
+        let piece = data.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            let answer = HandShake::new(&info_hash, [1u8; 20]).to_bytes();
+            stream.write_all(&answer).await.unwrap();
+
+            // The pieces and the allowed fast set come before the unchoke
+            for message in [Message::HaveAll, Message::AllowedFast(0)] {
+                write_message(&mut stream, &message).await.unwrap();
+            }
+            assert_eq!(
+                read_message(&mut stream).await.unwrap(),
+                Message::Interested
+            );
+            write_message(&mut stream, &Message::Unchoke).await.unwrap();
+            let request = BlockRequest {
+                index: 0,
+                begin: 0,
+                length: piece.len() as u32,
+            };
+            assert_eq!(
+                read_message(&mut stream).await.unwrap(),
+                Message::Request(request)
+            );
+            let piece = Message::Piece {
+                index: 0,
+                begin: 0,
+                block: piece,
+            };
+            write_message(&mut stream, &piece).await.unwrap();
+        });
+
+        let piece = BitTorrentStream::connect_and_request_piece(address, &torrent, 0)
+            .await
+            .unwrap();
+        assert_eq!(piece, data);
     }
 }
@@ -0,0 +1,149 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting a rate in bytes per second. The bucket holds up
+/// to one second of tokens, so short bursts go through. Taking more tokens
+/// than available puts the bucket in debt, and the caller waits until the
+/// debt is paid back.
+pub struct RateLimiter(Mutex<Bucket>);
+
+struct Bucket {
+    /// The rate in bytes per second, None when unlimited.
+    rate: Option<u64>,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Adds the tokens earned since the last update.
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    /// Returns a new [`RateLimiter`], with a full bucket.
+    pub fn new(rate: Option<u64>) -> Self {
+        Self(Mutex::new(Bucket {
+            rate,
+            tokens: rate.unwrap_or_default() as f64,
+            updated: Instant::now(),
+        }))
+    }
+
+    /// Changes the rate, effective for the next bytes.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.0.lock().expect("poisoned lock");
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate.unwrap_or_default() as f64);
+    }
+
+    /// Takes the tokens for the bytes. Returns how long to wait before
+    /// using them.
+    fn reserve(&self, len: u64, now: Instant) -> Duration {
+        let mut bucket = self.0.lock().expect("poisoned lock");
+        bucket.refill(now);
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+        bucket.tokens -= len as f64;
+        if bucket.tokens >= 0.0 || rate == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-bucket.tokens / rate as f64)
+    }
+}
+
+/// Waits until all the limiters allow the bytes. The tokens are taken from
+/// all of them at once, so the wait is the one of the slowest.
+pub async fn acquire(limiters: &[&RateLimiter], len: u64) {
+    let now = Instant::now();
+    let delay = limiters
+        .iter()
+        .map(|limiter| limiter.reserve(len, now))
+        .max()
+        .unwrap_or_default();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The download and upload limiters of a scope: the whole client, a torrent
+/// or a peer.
+pub struct RateLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+
+    /// Returns the limits shared by all the torrents.
+    pub fn global() -> &'static Self {
+        static GLOBAL: OnceLock<RateLimits> = OnceLock::new();
+        GLOBAL.get_or_init(|| Self::new(None, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(Some(1000));
+        let start = Instant::now();
+
+        // The burst of one second goes through, then the debt is waited
+        assert_eq!(limiter.reserve(1000, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, start), Duration::from_millis(500));
+
+        // Tokens come back with time, up to one second of them
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(1000, later), Duration::ZERO);
+        assert_eq!(limiter.reserve(100, later), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(None);
+        assert_eq!(
+            limiter.reserve(u32::MAX as u64, Instant::now()),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_set_rate() {
+        let limiter = RateLimiter::new(None);
+
+        limiter.set_rate(Some(100));
+        // The bucket of an unlimited limiter starts empty
+        let delay = limiter.reserve(100, Instant::now());
+        assert!(delay > Duration::from_millis(900));
+
+        limiter.set_rate(None);
+        assert_eq!(limiter.reserve(100, Instant::now()), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_slowest() {
+        let fast = RateLimiter::new(Some(1_000_000));
+        let slow = RateLimiter::new(Some(1000));
+        let start = Instant::now();
+
+        acquire(&[&fast, &slow], 1100).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -1,14 +1,27 @@
 use crate::decode::Decoder;
+use crate::merkle::{self, Hash};
+use crate::message::HashRequest;
+use crate::priority::Priority;
+use crate::sha256::sha256;
 use itertools::Itertools;
 use miette::miette;
 use serde_json::{Map, Value};
 use sha1::{Digest, Sha1};
-use std::fmt::Write;
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
 
 pub struct Torrent {
-    pub(crate) announce: String,
+    pub(crate) announce: Option<String>,
+    /// The tiers of trackers from the `announce-list` key (BEP 12).
+    pub(crate) announce_list: Vec<Vec<String>>,
+    /// The DHT nodes of a trackerless torrent, as `host:port`.
+    pub(crate) nodes: Vec<String>,
+    /// The web seeds from the `url-list` key (BEP 19).
+    pub(crate) url_list: Vec<String>,
+    /// The HTTP seeds from the `httpseeds` key (BEP 17).
+    pub(crate) http_seeds: Vec<String>,
     pub(crate) info: Info,
 }
 
@@ -16,13 +29,23 @@ impl Torrent {
     /// Reads the torrent from a file.
     pub fn read_from_file(path: &PathBuf) -> miette::Result<Self> {
         let file_content = std::fs::read(path).map_err(|_| miette!("failed to read file"))?;
-        let mut decoder = Decoder::new(&file_content);
+        Self::from_bytes(&file_content)
+    }
 
-        let value = decoder
+    /// Parses the torrent from the content of a file.
+    pub fn from_bytes(bytes: &[u8]) -> miette::Result<Self> {
+        let value = Decoder::new(bytes)
             .decode()
             .map_err(|_| miette!("failed to decode file"))?;
 
-        value.try_into()
+        let mut torrent: Self = value.try_into()?;
+        // The info hashes are over the info as encoded, which the decoded
+        // values can't always be encoded back to
+        let info = Decoder::new(bytes)
+            .raw_value("info")
+            .ok_or(miette!("expected info field"))?;
+        torrent.info.encoded = Some(info.to_vec());
+        Ok(torrent)
     }
 
     /// Returns the info hash of the torrent.
@@ -30,56 +53,450 @@ impl Torrent {
         hex::encode(self.info.hash())
     }
 
-    /// Returns the raw info hash of the torrent.
+    /// Returns the raw info hash of the torrent. A v2 torrent has the
+    /// SHA-256 info hash truncated to 20 bytes on the wire.
     pub fn raw_info_hash(&self) -> Vec<u8> {
-        self.info.hash()
+        match self.info.v2_only() {
+            Some(_) => self.info.v2_hash()[..20].to_vec(),
+            None => self.info.hash(),
+        }
     }
 
-    /// Returns the url encoded info hash.
-    pub fn url_encoded_info_hash(&self) -> String {
-        self.info_hash()
-            .chars()
-            .chunks(2)
-            .into_iter()
-            .map(|chunk| {
-                chunk.fold(String::from("%"), |mut output, c| {
-                    let _ = write!(output, "{c}");
-                    output
-                })
-            })
-            .collect()
+    /// Returns the info hashes the torrent is known by on the wire. A
+    /// hybrid torrent has both its v1 and truncated v2 info hashes.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut info_hashes = vec![self
+            .raw_info_hash()
+            .try_into()
+            .expect("info hash is 20 bytes")];
+        if self.info.v2.is_some() && self.info.v2_only().is_none() {
+            let v2_hash = self.info.v2_hash();
+            info_hashes.push(v2_hash[..20].try_into().expect("20 bytes"));
+        }
+        info_hashes
     }
 }
 
 pub struct Info {
+    /// The length of the content, all the files of a multi-file torrent.
     pub(crate) length: u32,
     pub(crate) name: String,
     pub(crate) piece_length: u32,
     pub(crate) pieces_raw: Vec<u8>,
     pub(crate) pieces: String,
+    /// The files of a multi-file torrent, empty for a single file.
+    pub(crate) files: Vec<File>,
+    /// The metadata of a v2 torrent (BEP 52).
+    pub(crate) v2: Option<InfoV2>,
+    /// The info dictionary as encoded in the torrent file. Torrents built
+    /// otherwise are encoded from their fields.
+    pub(crate) encoded: Option<Vec<u8>>,
+}
+
+/// A file of a multi-file torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct File {
+    /// The attributes of the file (BEP 47): `p` for a padding file, `x`
+    /// for an executable, `h` for a hidden one and `l` for a symlink.
+    pub(crate) attr: String,
+    pub(crate) length: u32,
+    /// The path of the file in the directory of the torrent.
+    pub(crate) path: Vec<String>,
+    /// The target of a symlink, from the directory of the torrent.
+    pub(crate) symlink_path: Option<Vec<String>>,
+    /// The sha-1 hash of the content of the file.
+    pub(crate) sha1: Option<[u8; 20]>,
+}
+
+/// The metadata of a v2 torrent, each file having its own merkle tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfoV2 {
+    /// The files of the `file tree`, in order.
+    pub(crate) files: Vec<FileV2>,
+}
+
+/// A file of the `file tree`, with the hashes of its pieces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileV2 {
+    pub(crate) length: u32,
+    pub(crate) path: Vec<String>,
+    /// The root of the merkle tree of the file, None for an empty file.
+    pub(crate) pieces_root: Option<Hash>,
+    /// The hashes of the pieces from the `piece layers`, empty for a file
+    /// of a single piece.
+    pub(crate) piece_layer: Vec<Hash>,
+    /// The index of the first piece of the file. Pieces never span files.
+    pub(crate) first_piece: u32,
+}
+
+impl InfoV2 {
+    /// Returns the file of the piece, with the index of the piece in it.
+    fn file_of_piece(&self, index: u32, piece_length: u32) -> Option<(&FileV2, u32)> {
+        self.files.iter().find_map(|file| {
+            let pieces = file.first_piece..file.first_piece + file.length.div_ceil(piece_length);
+            pieces
+                .contains(&index)
+                .then(|| (file, index - file.first_piece))
+        })
+    }
+
+    /// Returns true if the piece matches the merkle tree of its file. The
+    /// piece of a hybrid torrent runs into the padding after its file.
+    fn verify_piece(&self, index: u32, piece: &[u8], piece_length: u32) -> bool {
+        let Some((file, index)) = self.file_of_piece(index, piece_length) else {
+            return false;
+        };
+        let len = (file.length - index * piece_length).min(piece_length);
+        let Some(piece) = piece.get(..len as usize) else {
+            return false;
+        };
+        match file.piece_layer.get(index as usize) {
+            Some(hash) => merkle::piece_hash(piece, piece_length) == *hash,
+            None => file.pieces_root == Some(merkle::file_root(piece)),
+        }
+    }
+}
+
+impl File {
+    /// Returns true for a padding file, aligning the next file on a piece
+    /// boundary.
+    pub fn is_padding(&self) -> bool {
+        self.attr.contains('p')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.attr.contains('x')
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.attr.contains('l')
+    }
+
+    /// Encodes the file as an entry of the `files` list.
+    fn encode(&self) -> Vec<u8> {
+        let list = |parts: &[String]| {
+            let parts: String = parts
+                .iter()
+                .map(|part| format!("{}:{part}", part.len()))
+                .collect();
+            format!("l{parts}e")
+        };
+        let mut encoded = b"d".to_vec();
+        if !self.attr.is_empty() {
+            encoded.extend(format!("4:attr{}:{}", self.attr.len(), self.attr).as_bytes());
+        }
+        encoded.extend(format!("6:lengthi{}e4:path{}", self.length, list(&self.path)).as_bytes());
+        if let Some(sha1) = &self.sha1 {
+            encoded.extend(b"4:sha120:");
+            encoded.extend(sha1);
+        }
+        if let Some(target) = &self.symlink_path {
+            encoded.extend(format!("12:symlink path{}", list(target)).as_bytes());
+        }
+        encoded.push(b'e');
+        encoded
+    }
 }
 
 impl Info {
+    /// Returns the v2 metadata of a torrent without v1 pieces, whose pieces
+    /// are laid out by file.
+    fn v2_only(&self) -> Option<&InfoV2> {
+        self.v2.as_ref().filter(|_| self.pieces_raw.is_empty())
+    }
+
+    /// Returns the files of the torrent with their offset in the content.
+    /// A single-file torrent has one file named after the torrent.
+    pub fn file_offsets(&self) -> Vec<(u64, File)> {
+        if self.files.is_empty() {
+            let file = File {
+                attr: String::new(),
+                length: self.length,
+                path: vec![self.name.clone()],
+                symlink_path: None,
+                sha1: None,
+            };
+            return vec![(0, file)];
+        }
+        let mut offset = 0;
+        self.files
+            .iter()
+            .map(|file| {
+                let start = offset;
+                offset += file.length as u64;
+                (start, file.clone())
+            })
+            .collect()
+    }
+
+    /// Returns the parts of the files holding the range of the content, as
+    /// the index of the file with the range in the file.
+    pub fn file_ranges(&self, begin: u64, length: u64) -> Vec<(usize, u64, u64)> {
+        let end = begin + length;
+        self.file_offsets()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, (offset, file))| {
+                let start = begin.max(offset);
+                let stop = end.min(offset + file.length as u64);
+                (start < stop).then(|| (i, start - offset, stop - offset))
+            })
+            .collect()
+    }
+
+    /// Returns the pieces holding each file of [`Info::file_offsets`], an
+    /// empty range for an empty file.
+    pub fn file_pieces(&self) -> Vec<Range<u32>> {
+        if let Some(v2) = self.v2_only() {
+            return v2
+                .files
+                .iter()
+                .map(|file| {
+                    file.first_piece..file.first_piece + file.length.div_ceil(self.piece_length)
+                })
+                .collect();
+        }
+        let piece_length = self.piece_length as u64;
+        self.file_offsets()
+            .into_iter()
+            .map(|(offset, file)| {
+                let start = (offset / piece_length) as u32;
+                if file.length == 0 {
+                    return start..start;
+                }
+                start..(offset + file.length as u64).div_ceil(piece_length) as u32
+            })
+            .collect()
+    }
+
+    /// Writes the content to the path, a directory holding the files for a
+    /// multi-file torrent. The files with the skip priority aren't written,
+    /// the others when there are fewer priorities than files.
+    pub fn write_content(
+        &self,
+        data: &[u8],
+        path: &Path,
+        priorities: &[Priority],
+    ) -> miette::Result<()> {
+        let skipped = |index| priorities.get(index) == Some(&Priority::Skip);
+        if self.files.is_empty() {
+            if skipped(0) {
+                return Ok(());
+            }
+            return std::fs::write(path, data).map_err(|err| miette!(err));
+        }
+        for (index, (offset, file)) in self.file_offsets().into_iter().enumerate() {
+            // Padding files only align the others
+            if file.is_padding() || skipped(index) {
+                continue;
+            }
+            let path = path.join(relative_path(&file.path)?);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(|err| miette!(err))?;
+            }
+            let content = &data[offset as usize..offset as usize + file.length as usize];
+            if file
+                .sha1
+                .is_some_and(|sha1| Sha1::digest(content).as_slice() != sha1)
+            {
+                return Err(miette!("{} doesn't match its sha1", path.display()));
+            }
+
+            match &file.symlink_path {
+                Some(target) if file.is_symlink() => {
+                    // The target is relative to the directory of the torrent,
+                    // and stays in it with plain parts only
+                    let target = relative_path(target)?;
+                    let target: PathBuf = std::iter::repeat_n(Path::new(".."), file.path.len() - 1)
+                        .chain([target.as_path()])
+                        .collect();
+                    symlink(&target, &path)?;
+                }
+                _ => {
+                    std::fs::write(&path, content).map_err(|err| miette!(err))?;
+                    if file.is_executable() {
+                        set_executable(&path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the amount of pieces.
+    pub fn pieces_count(&self) -> u32 {
+        match self.v2_only() {
+            Some(v2) => v2
+                .files
+                .iter()
+                .map(|file| file.first_piece + file.length.div_ceil(self.piece_length))
+                .max()
+                .unwrap_or(0),
+            None => self.length.div_ceil(self.piece_length),
+        }
+    }
+
+    /// Returns the length of the piece at the index. The last piece can be
+    /// shorter than the others, and the last piece of each file for v2.
+    pub fn piece_len(&self, index: u32) -> u32 {
+        if let Some(v2) = self.v2_only() {
+            return v2
+                .file_of_piece(index, self.piece_length)
+                .map_or(0, |(file, index)| {
+                    (file.length - index * self.piece_length).min(self.piece_length)
+                });
+        }
+        if index + 1 == self.pieces_count() {
+            self.length - index * self.piece_length
+        } else {
+            self.piece_length
+        }
+    }
+
+    /// Returns the offset of the piece in the content.
+    pub fn piece_offset(&self, index: u32) -> u64 {
+        if let Some(v2) = self.v2_only() {
+            let mut offset = 0;
+            for file in &v2.files {
+                if index < file.first_piece + file.length.div_ceil(self.piece_length) {
+                    let index = index.saturating_sub(file.first_piece);
+                    return offset + index as u64 * self.piece_length as u64;
+                }
+                offset += file.length as u64;
+            }
+        }
+        index as u64 * self.piece_length as u64
+    }
+
+    /// Returns the sha-1 hash of the piece at the index.
+    pub fn piece_hash(&self, index: u32) -> Option<&[u8]> {
+        self.pieces_raw.chunks_exact(20).nth(index as usize)
+    }
+
+    /// Returns true if the piece matches its hashes, the sha-1 one and the
+    /// merkle tree of its file for v2.
+    pub fn verify_piece(&self, index: u32, piece: &[u8]) -> bool {
+        // Trailing bytes would be stored with the piece
+        if piece.len() != self.piece_len(index) as usize {
+            return false;
+        }
+        let v1 = self.pieces_raw.is_empty() || {
+            let piece = self.zero_padding(index, piece);
+            self.piece_hash(index) == Some(Sha1::digest(&piece).as_slice())
+        };
+        let v2 = self
+            .v2
+            .as_ref()
+            .is_none_or(|v2| v2.verify_piece(index, piece, self.piece_length));
+        v1 && v2
+    }
+
+    /// Returns the piece with the bytes of the padding files as zeros,
+    /// whatever was received for them.
+    pub fn zero_padding<'a>(&self, index: u32, piece: impl Into<Cow<'a, [u8]>>) -> Cow<'a, [u8]> {
+        let mut piece = piece.into();
+        let begin = self.piece_offset(index);
+        let end = begin + piece.len() as u64;
+        for (offset, file) in self.file_offsets() {
+            let start = offset.max(begin);
+            let stop = (offset + file.length as u64).min(end);
+            if file.is_padding() && start < stop {
+                piece.to_mut()[(start - begin) as usize..(stop - begin) as usize].fill(0);
+            }
+        }
+        piece
+    }
+
+    /// Returns the hashes of a hash request, from the piece layer of the
+    /// file. Other layers aren't served.
+    pub fn hashes(&self, request: &HashRequest) -> Option<Vec<Hash>> {
+        let file = self
+            .v2
+            .as_ref()?
+            .files
+            .iter()
+            .find(|file| file.pieces_root == Some(request.pieces_root))?;
+        let height = merkle::piece_height(self.piece_length);
+        if file.piece_layer.is_empty() || request.base_layer != height {
+            return None;
+        }
+        let layers = merkle::layers(
+            &file.piece_layer,
+            file.piece_layer.len().next_power_of_two(),
+            merkle::pad_hash(height),
+        );
+        merkle::hashes(
+            &layers,
+            request.index as usize,
+            request.length as usize,
+            request.proof_layers as usize,
+        )
+    }
+
+    /// Checks the v1 view of a hybrid torrent matches its v2 view: the same
+    /// files, each starting on a piece boundary thanks to the padding files
+    /// (BEP 47).
+    fn check_hybrid(&self) -> miette::Result<()> {
+        let Some(v2) = self.v2.as_ref().filter(|_| self.v2_only().is_none()) else {
+            return Ok(());
+        };
+        let files: Vec<_> = self
+            .file_offsets()
+            .into_iter()
+            .filter(|(_, file)| !file.is_padding())
+            .collect();
+        if files.len() != v2.files.len() {
+            return Err(miette!("the v1 and v2 files of the torrent differ"));
+        }
+        for ((offset, file), v2_file) in files.iter().zip(&v2.files) {
+            let path = file.path.join("/");
+            if file.path != v2_file.path || file.length != v2_file.length {
+                return Err(miette!("the v1 and v2 views of {path} differ"));
+            }
+            let start = v2_file.first_piece as u64 * self.piece_length as u64;
+            if file.length > 0 && *offset != start {
+                return Err(miette!("{path} isn't aligned on a piece boundary"));
+            }
+        }
+        if self.pieces_raw.len() != self.pieces_count() as usize * 20 {
+            return Err(miette!("the v1 pieces don't match the length"));
+        }
+        Ok(())
+    }
+
     /// Returns the sha-1 hash of the information.
     fn hash(&self) -> Vec<u8> {
-        let bytes = self.encode();
+        let bytes = self.encoded.clone().unwrap_or_else(|| self.encode());
         let mut hasher = Sha1::new();
         hasher.update(bytes);
         hasher.finalize().to_vec()
     }
 
+    /// Returns the SHA-256 hash of the information, for v2.
+    fn v2_hash(&self) -> Hash {
+        sha256(self.encoded.as_deref().unwrap_or(&self.encode()))
+    }
+
     /// Encode the information by reconstructing it and converting it to
     /// a slice u8.
     fn encode(&self) -> Vec<u8> {
+        // Multi-file torrents have a list of files instead of a length
+        let mut encoded = b"d".to_vec();
+        if self.files.is_empty() {
+            encoded.extend(format!("6:lengthi{}e", self.length).as_bytes());
+        } else {
+            encoded.extend(b"5:filesl");
+            encoded.extend(self.files.iter().flat_map(File::encode));
+            encoded.extend(b"e");
+        }
         let info = format!(
-            "d6:lengthi{}e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
-            self.length,
+            "4:name{}:{}12:piece lengthi{}e6:pieces{}:",
             self.name.len(),
             self.name,
             self.piece_length,
             self.pieces_raw.len(),
         );
-        let mut encoded = info.as_bytes().to_vec();
+        encoded.extend(info.as_bytes());
         // extend the slice with the pieces
         encoded.extend(self.pieces_raw.clone());
         // extend the slice with the terminating e
@@ -88,56 +505,356 @@ impl Info {
     }
 }
 
+/// Returns the parts of a path of the torrent as a relative path. Parts
+/// which could lead out of the directory of the torrent are refused, such
+/// as `..`, a root or a separator.
+fn relative_path(parts: &[String]) -> miette::Result<PathBuf> {
+    if parts.is_empty() {
+        return Err(miette!("empty file path"));
+    }
+    for part in parts {
+        let mut components = Path::new(part).components();
+        let plain = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(name)), None) if name == part.as_str()
+        );
+        if !plain || part.contains(std::path::is_separator) {
+            return Err(miette!("invalid file path {parts:?}"));
+        }
+    }
+    Ok(parts.iter().collect())
+}
+
+/// Links the path to the target. A link to the same target is kept, but
+/// anything else at the path is never replaced.
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> miette::Result<()> {
+    if std::fs::read_link(path).is_ok_and(|existing| existing == target) {
+        return Ok(());
+    }
+    std::os::unix::fs::symlink(target, path)
+        .map_err(|err| miette!("failed to link {}: {err}", path.display()))
+}
+
+/// Symlinks are left out where they aren't supported.
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _path: &Path) -> miette::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> miette::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = std::fs::metadata(path)
+        .map_err(|err| miette!(err))?
+        .permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    std::fs::set_permissions(path, permissions).map_err(|err| miette!(err))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> miette::Result<()> {
+    Ok(())
+}
+
+fn as_str(object: &Map<String, Value>, key: &str) -> miette::Result<String> {
+    object
+        .get(key)
+        .ok_or(miette!("expected {key} field"))?
+        .as_str()
+        .ok_or(miette!("expected str"))
+        .map(|x| x.to_string())
+}
+
+/// The content is kept in memory, so its lengths are limited to a u32.
+fn as_u32(object: &Map<String, Value>, key: &str) -> miette::Result<u32> {
+    let value = object
+        .get(key)
+        .ok_or(miette!("expected {key} field"))?
+        .as_u64()
+        .ok_or(miette!("expected a non-negative {key}"))?;
+    u32::try_from(value).map_err(|_| miette!("{key} {value} is too large"))
+}
+
+/// Sums the lengths of the files, failing past the u32 limit of `as_u32`.
+fn total_length(lengths: impl IntoIterator<Item = u32>) -> miette::Result<u32> {
+    lengths
+        .into_iter()
+        .try_fold(0u32, |total, length| total.checked_add(length))
+        .ok_or(miette!("the files are too large"))
+}
+
+fn as_path(object: &Map<String, Value>, key: &str) -> miette::Result<Vec<String>> {
+    object
+        .get(key)
+        .and_then(Value::as_array)
+        .ok_or(miette!("expected {key} field"))?
+        .iter()
+        .map(|part| part.as_str().map(str::to_string))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(miette!("expected str"))
+}
+
+/// Returns the bytes of a binary string. The decoder turns them to hex,
+/// unless they happen to be valid utf8.
+fn binary(value: &str) -> Vec<u8> {
+    hex::decode(value).unwrap_or_else(|_| value.as_bytes().to_vec())
+}
+
+fn as_hash(value: &str) -> miette::Result<Hash> {
+    binary(value)
+        .try_into()
+        .map_err(|_| miette!("expected a 32 bytes hash"))
+}
+
 impl TryFrom<Value> for Torrent {
     type Error = miette::Error;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
         let object = value.as_object().ok_or(miette!("expected object"))?;
-        fn as_str(object: &Map<String, Value>, key: &str) -> miette::Result<String> {
-            object
-                .get(key)
-                .ok_or(miette!("expected {key} field"))?
-                .as_str()
-                .ok_or(miette!("expected str"))
-                .map(|x| x.to_string())
-        }
-        fn as_u32(object: &Map<String, Value>, key: &str) -> miette::Result<u32> {
-            object
-                .get(key)
-                .ok_or(miette!("expected {key} field"))?
-                .as_u64()
-                .ok_or(miette!("expected usize"))
-                .map(|x| x as u32)
-        }
-
-        let announce = as_str(object, "announce")?;
+        let announce = as_str(object, "announce").ok();
+        let announce_list = object
+            .get("announce-list")
+            .and_then(Value::as_array)
+            .map(|tiers| {
+                tiers
+                    .iter()
+                    .filter_map(|tier| {
+                        let tier = tier
+                            .as_array()?
+                            .iter()
+                            .filter_map(|url| Some(url.as_str()?.to_string()))
+                            .collect::<Vec<_>>();
+                        (!tier.is_empty()).then_some(tier)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Trackerless torrents list DHT nodes as [host, port] pairs
+        let nodes = object
+            .get("nodes")
+            .and_then(Value::as_array)
+            .map(|nodes| {
+                nodes
+                    .iter()
+                    .filter_map(|node| {
+                        let node = node.as_array()?;
+                        let host = node.first()?.as_str()?;
+                        let port = node.get(1)?.as_u64()?;
+                        Some(format!("{host}:{port}"))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // A single web seed can be given as a string
+        let url_list = match object.get("url-list") {
+            Some(Value::String(url)) => vec![url.clone()],
+            Some(Value::Array(urls)) => urls
+                .iter()
+                .filter_map(|url| Some(url.as_str()?.to_string()))
+                .collect(),
+            _ => vec![],
+        };
+        let http_seeds = object
+            .get("httpseeds")
+            .and_then(Value::as_array)
+            .map(|urls| {
+                urls.iter()
+                    .filter_map(|url| Some(url.as_str()?.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
 
         let info = object
             .get("info")
             .ok_or(miette!("expected announce field"))?
             .as_object()
             .ok_or(miette!("expected object"))?;
-        let length = as_u32(info, "length")?;
+        let files = match info.get("files").and_then(Value::as_array) {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    let file = file.as_object().ok_or(miette!("expected object"))?;
+                    let sha1 = match file.get("sha1").and_then(Value::as_str) {
+                        Some(sha1) => Some(
+                            binary(sha1)
+                                .try_into()
+                                .map_err(|_| miette!("expected a 20 bytes sha1"))?,
+                        ),
+                        None => None,
+                    };
+                    Ok(File {
+                        attr: file
+                            .get("attr")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default()
+                            .to_string(),
+                        length: as_u32(file, "length")?,
+                        path: as_path(file, "path")?,
+                        symlink_path: file
+                            .contains_key("symlink path")
+                            .then(|| as_path(file, "symlink path"))
+                            .transpose()?,
+                        sha1,
+                    })
+                })
+                .collect::<miette::Result<Vec<_>>>()?,
+            None => vec![],
+        };
         let piece_length = as_u32(info, "piece length")?;
         let name = as_str(info, "name")?;
+        let v2 = match info.get("meta version").and_then(Value::as_u64) {
+            None | Some(1) => None,
+            Some(2) => Some(parse_v2(object, info, piece_length)?),
+            Some(version) => return Err(miette!("unsupported meta version {version}")),
+        };
+        let (files, length, pieces) = match &v2 {
+            // A v2 torrent without v1 pieces has its files in the file tree
+            Some(v2) if !info.contains_key("pieces") => {
+                let mut files: Vec<_> = v2
+                    .files
+                    .iter()
+                    .map(|file| File {
+                        attr: String::new(),
+                        length: file.length,
+                        path: file.path.clone(),
+                        symlink_path: None,
+                        sha1: None,
+                    })
+                    .collect();
+                if let [file] = files.as_slice() {
+                    if file.path == [name.as_str()] {
+                        files.clear();
+                    }
+                }
+                let length = total_length(v2.files.iter().map(|file| file.length))?;
+                (files, length, String::new())
+            }
+            _ => {
+                let length = if files.is_empty() {
+                    as_u32(info, "length")?
+                } else {
+                    total_length(files.iter().map(|file| file.length))?
+                };
+                (files, length, as_str(info, "pieces")?)
+            }
+        };
         // Reconstruct pieces from the hex string
         // by taking 2 chars and converting them to a byte
-        let pieces = as_str(info, "pieces")?;
         let pieces_raw = hex::decode(&pieces).map_err(|_| miette!("decoding error"))?;
 
+        let info = Info {
+            name,
+            pieces_raw,
+            pieces,
+            piece_length,
+            length,
+            files,
+            v2,
+            encoded: None,
+        };
+        info.check_hybrid()?;
+
         Ok(Self {
             announce,
-            info: Info {
-                name,
-                pieces_raw,
-                pieces,
-                piece_length,
-                length,
-            },
+            announce_list,
+            nodes,
+            url_list,
+            http_seeds,
+            info,
         })
     }
 }
 
+/// Parses the metadata of a v2 torrent. The piece layers of the files are
+/// checked against their roots.
+fn parse_v2(
+    object: &Map<String, Value>,
+    info: &Map<String, Value>,
+    piece_length: u32,
+) -> miette::Result<InfoV2> {
+    if !piece_length.is_power_of_two() || (piece_length as usize) < merkle::BLOCK_LEN {
+        return Err(miette!("invalid v2 piece length {piece_length}"));
+    }
+    let tree = info
+        .get("file tree")
+        .and_then(Value::as_object)
+        .ok_or(miette!("expected file tree field"))?;
+    let mut files = vec![];
+    walk_file_tree(tree, &mut vec![], &mut files)?;
+    total_length(files.iter().map(|file| file.length))?;
+
+    let piece_layers = object
+        .get("piece layers")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default();
+    let mut first_piece = 0;
+    for file in &mut files {
+        let pieces = file.length.div_ceil(piece_length);
+        file.first_piece = first_piece;
+        first_piece += pieces;
+        // Files of a single piece are checked against their root directly
+        let Some(root) = file.pieces_root.filter(|_| pieces > 1) else {
+            continue;
+        };
+        let layer = piece_layers
+            .iter()
+            .find(|(key, _)| as_hash(key).ok() == Some(root))
+            .and_then(|(_, layer)| Some(binary(layer.as_str()?)))
+            .ok_or(miette!("missing piece layer of {}", file.path.join("/")))?;
+        if layer.len() != pieces as usize * 32 {
+            return Err(miette!("invalid piece layer of {}", file.path.join("/")));
+        }
+        let layer: Vec<Hash> = layer
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().expect("32 bytes"))
+            .collect();
+        if merkle::layer_root(&layer, piece_length) != root {
+            return Err(miette!(
+                "piece layer of {} doesn't match its root",
+                file.path.join("/")
+            ));
+        }
+        file.piece_layer = layer;
+    }
+
+    Ok(InfoV2 { files })
+}
+
+/// Collects the files of the file tree, in order. A file is a dictionary
+/// with an empty key, the others are directories.
+fn walk_file_tree(
+    tree: &Map<String, Value>,
+    path: &mut Vec<String>,
+    files: &mut Vec<FileV2>,
+) -> miette::Result<()> {
+    for (name, node) in tree {
+        let node = node.as_object().ok_or(miette!("expected object"))?;
+        path.push(name.clone());
+        match node.get("").and_then(Value::as_object) {
+            Some(file) => {
+                let length = as_u32(file, "length")?;
+                let pieces_root = match file.get("pieces root").and_then(Value::as_str) {
+                    Some(root) => Some(as_hash(root)?),
+                    None if length == 0 => None,
+                    None => return Err(miette!("expected pieces root field")),
+                };
+                files.push(FileV2 {
+                    length,
+                    path: path.clone(),
+                    pieces_root,
+                    piece_layer: vec![],
+                    first_piece: 0,
+                });
+            }
+            None => walk_file_tree(node, path, files)?,
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
 impl Display for Torrent {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let pieces = self
@@ -151,11 +868,452 @@ impl Display for Torrent {
         write!(
             f,
             "Tracker URL: {}\nLength: {}\nInfo Hash: {}\nPiece Length: {}\nPiece Hashes:\n{}",
-            self.announce,
+            self.announce.as_deref().unwrap_or_default(),
             self.info.length,
-            hex::encode(self.info.hash()),
+            self.info_hash(),
             self.info.piece_length,
             pieces
         )
     }
 }
+
+#[cfg(test)]
+impl Torrent {
+    /// Returns a trackerless torrent of the data in a single piece.
+    pub fn single_piece(data: &[u8]) -> Self {
+        Self::with_files(data, data.len() as u32, vec![])
+    }
+
+    /// Returns a trackerless torrent of the data split into pieces of the
+    /// length, with the files or a single file without any.
+    pub fn with_files(data: &[u8], piece_length: u32, files: Vec<File>) -> Self {
+        let pieces_raw: Vec<u8> = data
+            .chunks(piece_length.max(1) as usize)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        Self {
+            announce: None,
+            announce_list: vec![],
+            nodes: vec![],
+            url_list: vec![],
+            http_seeds: vec![],
+            info: Info {
+                length: data.len() as u32,
+                name: "test".into(),
+                piece_length,
+                pieces: hex::encode(&pieces_raw),
+                pieces_raw,
+                files,
+                v2: None,
+                encoded: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(value: &[u8]) -> Vec<u8> {
+        [format!("{}:", value.len()).as_bytes(), value].concat()
+    }
+
+    /// Returns a v2 torrent of the files, with pieces of 16 KiB.
+    fn v2_torrent(files: &[(&str, &[u8])], corrupt_layer: bool) -> Vec<u8> {
+        encode_torrent(files, corrupt_layer, None)
+    }
+
+    /// Returns a hybrid torrent of the files, with padding files aligning
+    /// them on pieces unless disabled.
+    fn hybrid_torrent(files: &[(&str, &[u8])], padding: bool) -> Vec<u8> {
+        encode_torrent(files, false, Some(padding))
+    }
+
+    fn encode_torrent(
+        files: &[(&str, &[u8])],
+        corrupt_layer: bool,
+        hybrid: Option<bool>,
+    ) -> Vec<u8> {
+        let piece_length = merkle::BLOCK_LEN;
+        let mut tree = b"d".to_vec();
+        let mut layers = b"d".to_vec();
+        let mut v1_files = b"l".to_vec();
+        let mut content = vec![];
+        for (i, (name, data)) in files.iter().enumerate() {
+            let layer: Vec<Hash> = data
+                .chunks(piece_length)
+                .map(|piece| merkle::piece_hash(piece, piece_length as u32))
+                .collect();
+            let root = merkle::file_root(data);
+            tree.extend(bytes(name.as_bytes()));
+            tree.extend(format!("d0:d6:lengthi{}e11:pieces root", data.len()).as_bytes());
+            tree.extend(bytes(&root));
+            tree.extend(b"ee");
+            if layer.len() > 1 {
+                let mut layer = layer.concat();
+                if corrupt_layer {
+                    layer[0] ^= 1;
+                }
+                layers.extend(bytes(&root));
+                layers.extend(bytes(&layer));
+            }
+
+            v1_files.extend(format!("d6:lengthi{}e4:pathl", data.len()).as_bytes());
+            v1_files.extend(bytes(name.as_bytes()));
+            v1_files.extend(b"ee");
+            content.extend(*data);
+            let pad = content.len().next_multiple_of(piece_length) - content.len();
+            if hybrid == Some(true) && pad > 0 && i + 1 < files.len() {
+                v1_files.extend(format!("d4:attr1:p6:lengthi{pad}e4:pathl").as_bytes());
+                v1_files.extend(bytes(b".pad"));
+                v1_files.extend(bytes(pad.to_string().as_bytes()));
+                v1_files.extend(b"ee");
+                content.resize(content.len() + pad, 0);
+            }
+        }
+        tree.extend(b"e");
+        layers.extend(b"e");
+        v1_files.extend(b"e");
+
+        let mut torrent = b"d4:infod9:file tree".to_vec();
+        torrent.extend(tree);
+        if hybrid.is_some() {
+            torrent.extend(b"5:files");
+            torrent.extend(v1_files);
+        }
+        torrent.extend(b"12:meta versioni2e4:name3:dir12:piece lengthi16384e");
+        if hybrid.is_some() {
+            let pieces: Vec<u8> = content
+                .chunks(piece_length)
+                .flat_map(|piece| Sha1::digest(piece).to_vec())
+                .collect();
+            torrent.extend(b"6:pieces");
+            torrent.extend(bytes(&pieces));
+        }
+        torrent.extend(b"e12:piece layers");
+        torrent.extend(layers);
+        torrent.extend(b"e");
+        torrent
+    }
+
+    #[test]
+    fn test_v2_torrent() {
+        let a: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let b = b"a file of a single piece".to_vec();
+        let bytes = v2_torrent(&[("a", &a), ("b", &b)], false);
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        let info = &torrent.info;
+
+        // The pieces of each file start on a piece boundary
+        assert_eq!(info.length, 40000 + 24);
+        assert_eq!(info.pieces_count(), 4);
+        assert_eq!(info.piece_len(2), 40000 - 32768);
+        assert_eq!(info.piece_len(3), 24);
+        assert_eq!(info.piece_offset(3), 40000);
+        assert_eq!(
+            info.files,
+            vec![
+                File {
+                    attr: String::new(),
+                    length: 40000,
+                    path: vec!["a".into()],
+                    symlink_path: None,
+                    sha1: None,
+                },
+                File {
+                    attr: String::new(),
+                    length: 24,
+                    path: vec!["b".into()],
+                    symlink_path: None,
+                    sha1: None,
+                },
+            ]
+        );
+
+        assert!(info.verify_piece(1, &a[16384..32768]));
+        assert!(info.verify_piece(2, &a[32768..]));
+        assert!(info.verify_piece(3, &b));
+        assert!(!info.verify_piece(0, &a[16384..32768]));
+        assert!(!info.verify_piece(3, b"another file of one piece"));
+        assert!(!info.verify_piece(3, &[&b[..], b"\n"].concat()));
+
+        // The wire info hash is the SHA-256 of the info, truncated
+        let raw_info = Decoder::new(&bytes).raw_value("info").unwrap();
+        assert_eq!(torrent.raw_info_hash(), sha256(raw_info)[..20].to_vec());
+    }
+
+    #[test]
+    fn test_v2_hashes() {
+        let a: Vec<u8> = (0..40000u32).map(|i| (i % 7) as u8).collect();
+        let torrent = Torrent::from_bytes(&v2_torrent(&[("a", &a)], false)).unwrap();
+        let file = &torrent.info.v2.as_ref().unwrap().files[0];
+        let mut request = HashRequest {
+            pieces_root: file.pieces_root.unwrap(),
+            base_layer: 0,
+            index: 0,
+            length: 2,
+            proof_layers: 1,
+        };
+        // The piece layer of 3 pieces is padded to 4, with one uncle
+        let hashes = torrent.info.hashes(&request).unwrap();
+        assert_eq!(&hashes[..2], &file.piece_layer[..2]);
+        assert_eq!(hashes.len(), 3);
+
+        request.base_layer = 1;
+        assert!(torrent.info.hashes(&request).is_none());
+        request.pieces_root = [0; 32];
+        assert!(torrent.info.hashes(&request).is_none());
+    }
+
+    #[test]
+    fn test_invalid_v2_torrent() {
+        let a = vec![1u8; 40000];
+        assert!(Torrent::from_bytes(&v2_torrent(&[("a", &a)], true)).is_err());
+        // A missing piece layer
+        let bytes = v2_torrent(&[("a", &a)], false);
+        let end = bytes
+            .windows(15)
+            .position(|w| w == b"12:piece layers")
+            .unwrap();
+        let bytes = [&bytes[..end], b"e"].concat();
+        assert!(Torrent::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_large_v2_torrent() {
+        let file = |name: &str| {
+            let mut file = bytes(name.as_bytes());
+            file.extend(b"d0:d6:lengthi3221225472e11:pieces root");
+            file.extend(bytes(&[0; 32]));
+            file.extend(b"ee");
+            file
+        };
+        let mut bytes = b"d4:infod9:file treed".to_vec();
+        bytes.extend([file("a"), file("b")].concat());
+        bytes.extend(b"e12:meta versioni2e4:name3:dir12:piece lengthi16384ee");
+        bytes.extend(b"12:piece layersdee");
+
+        // The files are too large before their piece layers are looked for
+        let err = Torrent::from_bytes(&bytes).err().unwrap();
+        assert!(err.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn test_hybrid_torrent() {
+        let a: Vec<u8> = (0..40000u32).map(|i| (i % 13) as u8).collect();
+        let b = b"the second file".to_vec();
+        let bytes = hybrid_torrent(&[("a", &a), ("b", &b)], true);
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        let info = &torrent.info;
+
+        // The padding file aligns the second file on a piece boundary
+        assert_eq!(info.files.len(), 3);
+        assert!(info.files[1].is_padding());
+        assert_eq!(info.pieces_count(), 4);
+        assert_eq!(info.piece_offset(3), 3 * 16384);
+
+        // Both hash trees are checked, padding included for v1
+        let mut piece = a[32768..].to_vec();
+        piece.resize(16384, 0);
+        assert!(info.verify_piece(2, &piece));
+        assert!(!info.verify_piece(2, &a[32768..]));
+        assert!(info.verify_piece(3, &b));
+
+        // Known by both info hashes, the v1 one first
+        let raw_info = Decoder::new(&bytes).raw_value("info").unwrap();
+        let info_hashes: Vec<[u8; 20]> = vec![
+            Sha1::digest(raw_info).into(),
+            sha256(raw_info)[..20].try_into().unwrap(),
+        ];
+        assert_eq!(torrent.info_hashes(), info_hashes);
+    }
+
+    #[test]
+    fn test_invalid_hybrid_torrent() {
+        let a = vec![1u8; 40000];
+        let b = b"the second file".to_vec();
+        // Without padding, the second file isn't aligned
+        let bytes = hybrid_torrent(&[("a", &a), ("b", &b)], false);
+        assert!(Torrent::from_bytes(&bytes).is_err());
+
+        // The v1 files disagree with the file tree
+        let bytes = hybrid_torrent(&[("a", &a), ("b", &b)], true);
+        let files = bytes.windows(7).position(|w| w == b"5:files").unwrap();
+        let position = bytes[files..]
+            .windows(4)
+            .position(|w| w == b"1:be")
+            .unwrap();
+        let mut bytes = bytes;
+        bytes[files + position + 2] = b'c';
+        assert!(Torrent::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_lengths_past_u32() {
+        let torrent = |files: &str| {
+            let bytes =
+                format!("d4:infod5:filesl{files}e4:name3:dir12:piece lengthi16384e6:pieces0:ee");
+            Torrent::from_bytes(bytes.as_bytes())
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        };
+        let file = |length: &str| format!("d6:lengthi{length}e4:pathl1:aee");
+
+        // 5 GiB, and 3 GiB twice, don't fit the lengths
+        assert!(torrent(&file("5368709120"))
+            .unwrap_err()
+            .contains("too large"));
+        let files = file("3221225472").repeat(2);
+        assert!(torrent(&files).unwrap_err().contains("too large"));
+        assert!(torrent(&file("-1")).unwrap_err().contains("non-negative"));
+        assert!(torrent(&file("3221225472")).is_ok());
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let path = |parts: &[&str]| {
+            relative_path(
+                &parts
+                    .iter()
+                    .map(|part| part.to_string())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(path(&["a", "b.txt"]).unwrap(), Path::new("a/b.txt"));
+        for parts in [
+            &["..", "escape"][..],
+            &["/etc"],
+            &["a/b"],
+            &["a", ""],
+            &["."],
+            &[],
+        ] {
+            assert!(path(parts).is_err(), "{parts:?}");
+        }
+    }
+
+    #[test]
+    fn test_write_outside_the_directory() {
+        let file = File {
+            attr: String::new(),
+            length: 5,
+            path: vec!["..".into(), "escape".into()],
+            symlink_path: None,
+            sha1: None,
+        };
+        let torrent = Torrent::with_files(b"hello", 5, vec![file]);
+        let dir = tempfile::tempdir().unwrap();
+
+        let res = torrent
+            .info
+            .write_content(b"hello", &dir.path().join("out"), &[]);
+
+        assert!(res.is_err());
+        assert!(!dir.path().join("escape").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_outside_the_directory() {
+        let link = |target: &[&str]| File {
+            attr: "l".into(),
+            length: 0,
+            path: vec!["sub".into(), "link".into()],
+            symlink_path: Some(target.iter().map(|part| part.to_string()).collect()),
+            sha1: None,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        for target in [&["..", "..", "etc"][..], &["/etc"], &[]] {
+            let torrent = Torrent::with_files(b"", 1, vec![link(target)]);
+            assert!(torrent.info.write_content(b"", dir.path(), &[]).is_err());
+            assert!(std::fs::symlink_metadata(dir.path().join("sub/link")).is_err());
+        }
+
+        // An existing file isn't replaced by the link
+        std::fs::write(dir.path().join("sub/link"), b"mine").unwrap();
+        let torrent = Torrent::with_files(b"", 1, vec![link(&["run.sh"])]);
+        assert!(torrent.info.write_content(b"", dir.path(), &[]).is_err());
+        assert_eq!(std::fs::read(dir.path().join("sub/link")).unwrap(), b"mine");
+    }
+
+    #[test]
+    fn test_file_attributes() {
+        let script = b"#!/bin/sh\necho hi\n";
+        let readme = b"hidden";
+        let content = [&script[..], &[0; 14], readme].concat();
+        let pieces: Vec<u8> = content
+            .chunks(16)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+
+        let mut info = b"d5:filesl".to_vec();
+        info.extend(b"d4:attr1:x6:lengthi18e4:pathl6:run.she4:sha120:");
+        info.extend(Sha1::digest(script));
+        info.extend(b"ed4:attr1:p6:lengthi14e4:pathl4:.pad2:14ee");
+        info.extend(b"d4:attr1:l6:lengthi0e4:pathl3:sub4:linke12:symlink pathl6:run.shee");
+        info.extend(b"d4:attr1:h6:lengthi6e4:pathl3:sub6:readmeee");
+        info.extend(b"e4:name3:dir12:piece lengthi16e6:pieces");
+        info.extend(bytes(&pieces));
+        info.extend(b"e");
+        let torrent = Torrent::from_bytes(&[b"d4:info", &info[..], b"e"].concat()).unwrap();
+        let info_dict = &torrent.info;
+
+        assert_eq!(info_dict.length, 38);
+        assert!(info_dict.files[0].is_executable());
+        assert!(info_dict.files[1].is_padding());
+        assert!(info_dict.files[2].is_symlink());
+        assert_eq!(
+            info_dict.files[2].symlink_path,
+            Some(vec!["run.sh".to_string()])
+        );
+        // The fields are encoded back to the same info
+        assert_eq!(info_dict.encode(), info);
+
+        // The padding is checked as zeros, whatever was received
+        let mut piece = content[16..32].to_vec();
+        piece[4..].fill(0xff);
+        assert!(info_dict.verify_piece(1, &piece));
+        piece[0] ^= 1;
+        assert!(!info_dict.verify_piece(1, &piece));
+
+        let dir = tempfile::tempdir().unwrap();
+        info_dict.write_content(&content, dir.path(), &[]).unwrap();
+        assert!(!dir.path().join(".pad").exists());
+        assert_eq!(
+            std::fs::read(dir.path().join("sub/readme")).unwrap(),
+            readme
+        );
+        assert_eq!(std::fs::read(dir.path().join("sub/link")).unwrap(), script);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(dir.path().join("run.sh")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o111, 0o111);
+            let link = std::fs::read_link(dir.path().join("sub/link")).unwrap();
+            assert_eq!(link, Path::new("../run.sh"));
+        }
+        // Writing again keeps the same link
+        info_dict.write_content(&content, dir.path(), &[]).unwrap();
+
+        // A file not matching its sha1 isn't written
+        let mut corrupt = content.clone();
+        corrupt[0] ^= 1;
+        assert!(info_dict.write_content(&corrupt, dir.path(), &[]).is_err());
+
+        // Skipped files aren't written
+        assert_eq!(info_dict.file_pieces(), vec![0..2, 1..2, 2..2, 2..3]);
+        let dir = tempfile::tempdir().unwrap();
+        let priorities = [
+            Priority::Normal,
+            Priority::Skip,
+            Priority::Skip,
+            Priority::Skip,
+        ];
+        info_dict
+            .write_content(&content, dir.path(), &priorities)
+            .unwrap();
+        assert!(dir.path().join("run.sh").exists());
+        assert!(!dir.path().join("sub").exists());
+    }
+}
@@ -0,0 +1,331 @@
+mod announcer;
+mod http;
+mod response;
+mod udp;
+
+use crate::peers::Peers;
+use crate::random::shuffle;
+use crate::torrent::Torrent;
+use itertools::Itertools;
+use miette::miette;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub use crate::tracker::announcer::Announcer;
+pub use crate::tracker::response::{AnnounceResponse, ScrapeStats};
+pub use crate::tracker::udp::UdpTracker;
+
+/// The longest a tracker is waited for before trying the next one.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The parameters of an announce to a tracker.
+#[derive(Clone)]
+pub struct AnnounceParams {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<Event>,
+    /// The tracker id returned by a previous announce to the same tracker.
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+/// The event sent with an announce. Regular announces have no event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    /// Returns the value of the `event` parameter of HTTP trackers.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+
+    /// Returns the event code of UDP trackers.
+    pub fn udp_code(&self) -> u32 {
+        match self {
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+}
+
+/// Announces to the tracker at the provided url, speaking HTTP or UDP
+/// depending on the url scheme.
+pub async fn announce(url: &str, params: &AnnounceParams) -> miette::Result<AnnounceResponse> {
+    let (scheme, _) = url
+        .split_once("://")
+        .ok_or(miette!("invalid tracker url {url}"))?;
+    match scheme {
+        "http" | "https" => http::announce(url, params).await,
+        "udp" => UdpTracker::connect(url).await?.announce(params).await,
+        _ => Err(miette!("unsupported tracker scheme {scheme}")),
+    }
+}
+
+/// Scrapes the tracker at the provided url for the info hashes, speaking
+/// HTTP or UDP depending on the url scheme.
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> miette::Result<Vec<ScrapeStats>> {
+    let (scheme, _) = url
+        .split_once("://")
+        .ok_or(miette!("invalid tracker url {url}"))?;
+    match scheme {
+        "http" | "https" => http::scrape(url, info_hashes).await,
+        "udp" => UdpTracker::connect(url).await?.scrape(info_hashes).await,
+        _ => Err(miette!("unsupported tracker scheme {scheme}")),
+    }
+}
+
+/// Scrapes the main tracker of each torrent. Torrents sharing a tracker
+/// are scraped with a single request. The stats are returned in the same
+/// order as the torrents.
+pub async fn scrape_torrents(torrents: &[Torrent]) -> Vec<miette::Result<ScrapeStats>> {
+    let mut results = torrents
+        .iter()
+        .map(|_| Err(miette!("torrent has no tracker")))
+        .collect::<Vec<_>>();
+
+    // Group the torrents by tracker
+    let mut groups: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, torrent) in torrents.iter().enumerate() {
+        let url = torrent
+            .announce_list
+            .iter()
+            .flatten()
+            .chain(&torrent.announce)
+            .next();
+        if let Some(url) = url {
+            groups.entry(url).or_default().push(i);
+        }
+    }
+
+    for (url, indexes) in groups {
+        let info_hashes = indexes
+            .iter()
+            .map(|i| {
+                torrents[*i]
+                    .raw_info_hash()
+                    .try_into()
+                    .expect("info hash is 20 bytes")
+            })
+            .collect::<Vec<_>>();
+        match scrape(url, &info_hashes).await {
+            Ok(stats) => {
+                for (i, stats) in indexes.into_iter().zip(stats) {
+                    results[i] = Ok(stats);
+                }
+            }
+            Err(err) => {
+                for i in indexes {
+                    results[i] = Err(miette!("{url}: {err}"));
+                }
+            }
+        }
+    }
+
+    results
+}
+
+/// The trackers of a torrent, grouped in tiers (BEP 12). Trackers are
+/// shuffled within their tier, and a tracker that responds is moved to the
+/// front of its tier.
+pub struct Trackers {
+    tiers: Vec<Vec<String>>,
+    /// The tracker ids handed out by the trackers, by url.
+    tracker_ids: HashMap<String, Vec<u8>>,
+    /// The clients of the UDP trackers, by url, keeping their connection
+    /// ids between announces.
+    udp_trackers: Arc<Mutex<HashMap<String, UdpTracker>>>,
+    announce_timeout: Duration,
+}
+
+impl Trackers {
+    /// Returns the trackers of the torrent. The `announce-list` takes
+    /// precedence over the `announce` key.
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut tiers = if torrent.announce_list.is_empty() {
+            torrent
+                .announce
+                .iter()
+                .map(|url| vec![url.clone()])
+                .collect()
+        } else {
+            torrent.announce_list.clone()
+        };
+        tiers.iter_mut().for_each(|tier| shuffle(tier));
+        Self {
+            tiers,
+            tracker_ids: HashMap::new(),
+            udp_trackers: Arc::default(),
+            announce_timeout: ANNOUNCE_TIMEOUT,
+        }
+    }
+
+    /// Returns true if the torrent has no tracker.
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Announces to the first responding tracker of each tier. The peers of
+    /// all tiers are merged and deduplicated, the other fields are those of
+    /// the first responding tier.
+    pub async fn announce(&mut self, params: &AnnounceParams) -> miette::Result<AnnounceResponse> {
+        let udp_trackers = self.udp_trackers.clone();
+        self.announce_with(|url, tracker_id| {
+            let params = AnnounceParams {
+                tracker_id,
+                ..params.clone()
+            };
+            let udp_trackers = udp_trackers.clone();
+            async move {
+                if !url.starts_with("udp://") {
+                    return announce(&url, &params).await;
+                }
+                // The client is taken out while announcing, to not hold the
+                // lock across the announce
+                let tracker = udp_trackers.lock().unwrap().remove(&url);
+                let mut tracker = match tracker {
+                    Some(tracker) => tracker,
+                    None => UdpTracker::connect(&url).await?,
+                };
+                let response = tracker.announce(&params).await;
+                udp_trackers.lock().unwrap().insert(url, tracker);
+                response
+            }
+        })
+        .await
+    }
+
+    async fn announce_with<F, Fut>(&mut self, mut announce: F) -> miette::Result<AnnounceResponse>
+    where
+        F: FnMut(String, Option<Vec<u8>>) -> Fut,
+        Fut: Future<Output = miette::Result<AnnounceResponse>>,
+    {
+        let mut merged: Option<AnnounceResponse> = None;
+        let mut peers = vec![];
+        let mut errors = vec![];
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let tracker_id = self.tracker_ids.get(&tier[i]).cloned();
+                let response = tokio::time::timeout(
+                    self.announce_timeout,
+                    announce(tier[i].clone(), tracker_id),
+                )
+                .await
+                .unwrap_or_else(|_| Err(miette!("timed out")));
+                match response {
+                    Ok(mut response) => {
+                        let url = tier.remove(i);
+                        if let Some(tracker_id) = &response.tracker_id {
+                            self.tracker_ids.insert(url.clone(), tracker_id.clone());
+                        }
+                        tier.insert(0, url);
+                        peers.append(&mut response.peers.0);
+                        merged.get_or_insert(response);
+                        break;
+                    }
+                    Err(err) => errors.push(format!("{}: {err}", tier[i])),
+                }
+            }
+        }
+
+        let Some(mut response) = merged else {
+            return Err(miette!("all trackers failed: {}", errors.join(", ")));
+        };
+        response.peers = Peers(peers.into_iter().unique().collect());
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trackers(tiers: &[&[&str]]) -> Trackers {
+        Trackers {
+            tracker_ids: HashMap::new(),
+            udp_trackers: Arc::default(),
+            announce_timeout: Duration::from_millis(50),
+            tiers: tiers
+                .iter()
+                .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+                .collect(),
+        }
+    }
+
+    /// Announces with fake trackers, the urls starting with `ok` respond
+    /// with the peers listed after the scheme, those starting with `slow`
+    /// never respond.
+    async fn fake_announce(trackers: &mut Trackers) -> miette::Result<Peers> {
+        let response = trackers
+            .announce_with(|url, _| async move {
+                if url.starts_with("slow://") {
+                    std::future::pending::<()>().await;
+                }
+                match url.strip_prefix("ok://") {
+                    Some(peers) => Ok(AnnounceResponse {
+                        peers: Peers(peers.split(',').map(|p| p.parse().unwrap()).collect()),
+                        ..Default::default()
+                    }),
+                    None => Err(miette!("unreachable")),
+                }
+            })
+            .await?;
+        Ok(response.peers)
+    }
+
+    #[tokio::test]
+    async fn test_responding_tracker_moves_to_front() {
+        let mut trackers = trackers(&[&["down://a", "ok://1.1.1.1:1", "down://b"]]);
+
+        fake_announce(&mut trackers).await.unwrap();
+
+        assert_eq!(
+            trackers.tiers[0],
+            vec!["ok://1.1.1.1:1", "down://a", "down://b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_merged_across_tiers() {
+        let mut trackers = trackers(&[
+            &["ok://1.1.1.1:1,2.2.2.2:2", "ok://9.9.9.9:9"],
+            &["down://a"],
+            &["down://b", "ok://2.2.2.2:2,3.3.3.3:3"],
+        ]);
+
+        let peers = fake_announce(&mut trackers).await.unwrap();
+
+        assert_eq!(peers.to_string(), "1.1.1.1:1\n2.2.2.2:2\n3.3.3.3:3\n");
+    }
+
+    #[tokio::test]
+    async fn test_slow_tracker_times_out() {
+        let mut trackers = trackers(&[&["slow://a", "ok://1.1.1.1:1"]]);
+
+        let peers = fake_announce(&mut trackers).await.unwrap();
+
+        assert_eq!(peers.to_string(), "1.1.1.1:1\n");
+    }
+
+    #[tokio::test]
+    async fn test_all_trackers_down() {
+        let mut trackers = trackers(&[&["down://a"], &["down://b"]]);
+
+        let res = fake_announce(&mut trackers).await;
+
+        assert!(res.is_err());
+    }
+}
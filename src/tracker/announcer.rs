@@ -0,0 +1,225 @@
+use crate::download::Download;
+use crate::tracker::{AnnounceParams, AnnounceResponse, Event, Trackers};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// The interval used until a tracker responds, and the shortest one
+/// followed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// The longest the `stopped` announce delays the shutdown.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Announces a download to the trackers of its torrent in the background:
+/// `started` first, then at the interval asked by the trackers,
+/// `completed` once the download completes and `stopped` on shutdown.
+/// The peers returned by each announce are added to the download.
+pub struct Announcer {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Announcer {
+    /// Spawns the announcer of the download.
+    pub fn spawn(download: Arc<Download>, peer_id: [u8; 20], port: u16) -> Self {
+        let trackers = Trackers::new(download.torrent());
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(run(
+            trackers,
+            download,
+            peer_id,
+            port,
+            STOP_TIMEOUT,
+            stopped,
+        ));
+        Self { stop, task }
+    }
+
+    /// Stops the announcer, waiting for the `stopped` announce.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+async fn run(
+    mut trackers: Trackers,
+    download: Arc<Download>,
+    peer_id: [u8; 20],
+    port: u16,
+    stop_timeout: Duration,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    // A hybrid torrent is announced by both its info hashes
+    let info_hashes = download.info_hashes();
+    let params = |info_hash, event| AnnounceParams {
+        info_hash,
+        peer_id,
+        port,
+        uploaded: download.uploaded(),
+        downloaded: download.downloaded(),
+        left: download.left(),
+        event,
+        tracker_id: None,
+    };
+
+    // A download that is complete from the start is seeding, it never
+    // sends `completed`
+    let mut event = Some(Event::Started);
+    let mut completed = download.is_complete();
+    loop {
+        // The event is sent again until a tracker receives it
+        let announce = async {
+            let mut interval = None;
+            for info_hash in &info_hashes {
+                match trackers.announce(&params(*info_hash, event)).await {
+                    Ok(response) => {
+                        if let (Some(seeders), Some(leechers)) =
+                            (response.complete, response.incomplete)
+                        {
+                            eprintln!("Swarm: {seeders} seeders, {leechers} leechers");
+                        }
+                        download.add_peers(&response.peers);
+                        let asked = asked_interval(&response);
+                        interval =
+                            Some(interval.map_or(asked, |interval: Duration| interval.min(asked)));
+                    }
+                    Err(err) => eprintln!("Announce failed: {err}"),
+                }
+            }
+            interval
+        };
+        // Slow trackers don't delay the shutdown
+        let interval = tokio::select! {
+            interval = announce => interval,
+            _ = &mut stopped => break,
+        };
+        if interval.is_some() {
+            event = None;
+        }
+        let interval = interval.unwrap_or(RETRY_INTERVAL);
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = download.wait_complete(), if !completed => {
+                completed = true;
+                event = Some(Event::Completed);
+            }
+            _ = &mut stopped => break,
+        }
+    }
+
+    for info_hash in &info_hashes {
+        let params = params(*info_hash, Some(Event::Stopped));
+        let _ = tokio::time::timeout(stop_timeout, trackers.announce(&params)).await;
+    }
+}
+
+/// Returns the interval asked by the tracker, no shorter than
+/// [`RETRY_INTERVAL`] so that a zero interval doesn't flood the tracker.
+fn asked_interval(response: &AnnounceResponse) -> Duration {
+    let min_interval = response.min_interval.unwrap_or_default();
+    Duration::from_secs(response.interval.max(min_interval)).max(RETRY_INTERVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+    use std::sync::Mutex;
+    use tokio::net::UdpSocket;
+
+    /// Spawns a UDP tracker recording the events and `left` of the
+    /// announces. Returns its url and the recorded announces.
+    async fn recording_tracker() -> (String, Arc<Mutex<Vec<(u32, u64)>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let announces = Arc::new(Mutex::new(vec![]));
+        let recorded = announces.clone();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let (_, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let action = u32::from_be_bytes(buffer[8..12].try_into().unwrap());
+                let mut response = vec![];
+                response.extend(&buffer[8..16]);
+                if action == 0 {
+                    response.extend(1u64.to_be_bytes());
+                } else {
+                    let left = u64::from_be_bytes(buffer[64..72].try_into().unwrap());
+                    let event = u32::from_be_bytes(buffer[80..84].try_into().unwrap());
+                    recorded.lock().unwrap().push((event, left));
+                    // A long interval, the test never waits for it
+                    response.extend([0, 0, 0x0e, 0x10, 0, 0, 0, 0, 0, 0, 0, 0]);
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        (url, announces)
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let (url, announces) = recording_tracker().await;
+        let piece = b"hello world".to_vec();
+        let download = Download::new(
+            Arc::new(Torrent {
+                announce: Some(url),
+                ..Torrent::single_piece(&piece)
+            }),
+            [0u8; 20],
+        );
+
+        let announcer = Announcer::spawn(download.clone(), [0u8; 20], 6881);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        download.complete_piece(0, piece);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        announcer.stop().await;
+
+        // started, completed then stopped, with the bytes left
+        let announces = announces.lock().unwrap().clone();
+        assert_eq!(announces, vec![(2, 11), (1, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn test_asked_interval() {
+        let response = |interval, min_interval| AnnounceResponse {
+            interval,
+            min_interval,
+            ..Default::default()
+        };
+        assert_eq!(asked_interval(&response(0, None)), RETRY_INTERVAL);
+        assert_eq!(asked_interval(&response(0, Some(0))), RETRY_INTERVAL);
+        assert_eq!(
+            asked_interval(&response(1800, Some(3600))),
+            Duration::from_secs(3600)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stop_with_a_silent_tracker() {
+        // The tracker receives the announces but never responds
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let torrent = Torrent {
+            announce: Some(url),
+            ..Torrent::single_piece(b"hello")
+        };
+        let download = Download::new(Arc::new(torrent), [0u8; 20]);
+        let trackers = Trackers::new(download.torrent());
+        let (stop, stopped) = oneshot::channel();
+        let timeout = Duration::from_millis(50);
+        let task = tokio::spawn(run(trackers, download, [0u8; 20], 6881, timeout, stopped));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
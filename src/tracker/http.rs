@@ -0,0 +1,136 @@
+use crate::tracker::response::{parse_scrape_response, ScrapeStats};
+use crate::tracker::{AnnounceParams, AnnounceResponse, Event};
+use miette::miette;
+use serde::Serialize;
+use std::net::{IpAddr, Ipv6Addr};
+
+#[derive(Serialize)]
+struct PeersQueryParams {
+    port: u16,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<&'static str>,
+}
+
+/// Announces to an HTTP tracker.
+pub async fn announce(url: &str, params: &AnnounceParams) -> miette::Result<AnnounceResponse> {
+    let query = PeersQueryParams {
+        port: params.port,
+        uploaded: params.uploaded,
+        downloaded: params.downloaded,
+        left: params.left,
+        compact: 1,
+        event: params.event.as_ref().map(Event::as_str),
+    };
+    let encoded_params = serde_urlencoded::to_string(&query).map_err(|err| miette!(err))?;
+    // The binary fields are escaped by hand, serde would expect utf8
+    let mut encoded_params = format!(
+        "{}&info_hash={}&peer_id={}",
+        encoded_params,
+        url_encode(&params.info_hash),
+        url_encode(&params.peer_id),
+    );
+    // Let dual-stack trackers know our IPv6 address (BEP 7)
+    if let Some(ip) = local_ipv6() {
+        encoded_params.push_str(&format!("&ipv6={}", ip.to_string().replace(':', "%3A")));
+    }
+    if let Some(tracker_id) = &params.tracker_id {
+        encoded_params.push_str(&format!("&trackerid={}", url_encode(tracker_id)));
+    }
+
+    // The announce url may already carry a query string
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!("{url}{separator}{encoded_params}");
+
+    let res = reqwest::get(url).await.map_err(|err| miette!(err))?;
+    let raw_res = res.bytes().await.map_err(|err| miette!(err))?;
+
+    AnnounceResponse::from_bytes(raw_res.as_ref())
+}
+
+/// Scrapes an HTTP tracker for the provided info hashes.
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> miette::Result<Vec<ScrapeStats>> {
+    let url = scrape_url(url).ok_or(miette!("tracker {url} doesn't support scrape"))?;
+    let query = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", url_encode(info_hash)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!("{url}{separator}{query}");
+
+    let res = reqwest::get(url).await.map_err(|err| miette!(err))?;
+    let raw_res = res.bytes().await.map_err(|err| miette!(err))?;
+
+    parse_scrape_response(raw_res.as_ref(), info_hashes)
+}
+
+/// Derives the scrape url from the announce url (BEP 48): the last path
+/// segment must start with `announce`, which is replaced by `scrape`. The
+/// query can hold slashes, such as in a passkey.
+fn scrape_url(announce: &str) -> Option<String> {
+    let path = announce.split_once('?').map_or(announce, |(path, _)| path);
+    let slash = path.rfind('/')?;
+    let (base, last) = announce.split_at(slash + 1);
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{base}scrape{rest}"))
+}
+
+/// Returns our global IPv6 address, if we have one. The address is the
+/// source address the system would use to reach a public IPv6 host, no
+/// packet is sent.
+fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind("[::]:0").ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if !ip.is_loopback() && !ip.is_unicast_link_local() => Some(ip),
+        _ => None,
+    }
+}
+
+/// Url encodes raw bytes, escaping every byte.
+fn url_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("%{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_url() {
+        // Examples from BEP 48
+        let cases = [
+            (
+                "http://example.com/announce",
+                Some("http://example.com/scrape"),
+            ),
+            (
+                "http://example.com/x/announce",
+                Some("http://example.com/x/scrape"),
+            ),
+            (
+                "http://example.com/announce.php",
+                Some("http://example.com/scrape.php"),
+            ),
+            ("http://example.com/a", None),
+            (
+                "http://example.com/announce?x2%0644",
+                Some("http://example.com/scrape?x2%0644"),
+            ),
+            ("http://example.com/x%064announce", None),
+            // BEP 48 rejects it, but the slash is only in the query
+            (
+                "http://example.com/announce?x=2/4",
+                Some("http://example.com/scrape?x=2/4"),
+            ),
+        ];
+
+        for (announce, scrape) in cases {
+            assert_eq!(scrape_url(announce).as_deref(), scrape, "{announce}");
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use crate::peers::Peers;
+use miette::miette;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// The response of a tracker to an announce.
+#[derive(Debug, Default)]
+pub struct AnnounceResponse {
+    /// Seconds to wait between regular announces.
+    pub interval: u64,
+    /// Seconds to wait at least before announcing again.
+    pub min_interval: Option<u64>,
+    /// The amount of seeders.
+    pub complete: Option<u64>,
+    /// The amount of leechers.
+    pub incomplete: Option<u64>,
+    /// An id to send back on the next announces.
+    pub tracker_id: Option<Vec<u8>>,
+    pub warning_message: Option<String>,
+    pub peers: Peers,
+}
+
+/// The bencoded response, before validation.
+#[derive(Deserialize)]
+struct RawAnnounceResponse {
+    #[serde(default, rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default, rename = "warning message")]
+    warning_message: Option<String>,
+    #[serde(default)]
+    interval: Option<u64>,
+    #[serde(default, rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(default, rename = "tracker id")]
+    tracker_id: Option<ByteBuf>,
+    #[serde(default)]
+    complete: Option<u64>,
+    #[serde(default)]
+    incomplete: Option<u64>,
+    #[serde(default)]
+    peers: Option<RawPeers>,
+    #[serde(default)]
+    peers6: Option<ByteBuf>,
+}
+
+/// Trackers send either a compact string of peers, or a list of
+/// dictionaries.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPeers {
+    Compact(ByteBuf),
+    Dictionaries(Vec<RawPeer>),
+}
+
+#[derive(Deserialize)]
+struct RawPeer {
+    ip: String,
+    port: u16,
+}
+
+impl AnnounceResponse {
+    /// Parses the bencoded response of an HTTP tracker. A failure reason
+    /// is returned as an error.
+    pub fn from_bytes(bytes: &[u8]) -> miette::Result<Self> {
+        let raw: RawAnnounceResponse = serde_bencode::from_bytes(bytes)
+            .map_err(|err| miette!("invalid tracker response: {err}"))?;
+
+        if let Some(reason) = raw.failure_reason {
+            return Err(miette!("tracker failure: {reason}"));
+        }
+
+        if raw.peers.is_none() && raw.peers6.is_none() {
+            return Err(miette!("missing peers key"));
+        }
+        let mut peers = match raw.peers {
+            Some(RawPeers::Compact(bytes)) => Peers::from_compact(&bytes),
+            // Peers given as a host name are skipped
+            Some(RawPeers::Dictionaries(peers)) => Peers(
+                peers
+                    .into_iter()
+                    .filter_map(|peer| {
+                        let ip = peer.ip.parse::<IpAddr>().ok()?;
+                        Some(SocketAddr::new(ip, peer.port))
+                    })
+                    .collect(),
+            ),
+            None => Peers::default(),
+        };
+        if let Some(peers6) = raw.peers6 {
+            peers.0.extend(Peers::from_compact6(&peers6).0);
+        }
+
+        Ok(Self {
+            interval: raw.interval.ok_or(miette!("missing interval key"))?,
+            min_interval: raw.min_interval,
+            complete: raw.complete,
+            incomplete: raw.incomplete,
+            tracker_id: raw.tracker_id.map(ByteBuf::into_vec),
+            warning_message: raw.warning_message,
+            peers,
+        })
+    }
+}
+
+/// Swarm statistics of a torrent, as returned by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+#[derive(Deserialize)]
+struct RawScrapeResponse {
+    #[serde(default, rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: HashMap<ByteBuf, RawScrapeStats>,
+}
+
+#[derive(Deserialize)]
+struct RawScrapeStats {
+    complete: u32,
+    downloaded: u32,
+    incomplete: u32,
+}
+
+/// Parses the bencoded scrape response of an HTTP tracker. The stats are
+/// returned in the same order as the info hashes.
+pub fn parse_scrape_response(
+    bytes: &[u8],
+    info_hashes: &[[u8; 20]],
+) -> miette::Result<Vec<ScrapeStats>> {
+    let raw: RawScrapeResponse = serde_bencode::from_bytes(bytes)
+        .map_err(|err| miette!("invalid scrape response: {err}"))?;
+
+    if let Some(reason) = raw.failure_reason {
+        return Err(miette!("tracker failure: {reason}"));
+    }
+
+    info_hashes
+        .iter()
+        .map(|info_hash| {
+            let stats = raw
+                .files
+                .get(&ByteBuf::from(info_hash.to_vec()))
+                .ok_or(miette!("torrent {} not scraped", hex::encode(info_hash)))?;
+            Ok(ScrapeStats {
+                seeders: stats.complete,
+                completed: stats.downloaded,
+                leechers: stats.incomplete,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_response() {
+        let mut bytes =
+            b"d8:completei3e10:incompletei1e8:intervali1800e12:min intervali60e5:peers12:".to_vec();
+        bytes.extend([127, 0, 0, 1, 0x1a, 0xe1, 0xa5, 0x17, 0xd7, 0x41, 0xc8, 0xd5]);
+        bytes.extend(b"e");
+
+        let response = AnnounceResponse::from_bytes(&bytes).unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(response.complete, Some(3));
+        assert_eq!(response.incomplete, Some(1));
+        assert_eq!(
+            response.peers.to_string(),
+            "127.0.0.1:6881\n165.23.215.65:51413\n"
+        );
+    }
+
+    #[test]
+    fn test_dictionary_peers() {
+        let bytes = b"d8:intervali900e5:peersld2:ip9:10.0.0.107:peer id20:-BT0001-0123456789ab4:porti6881eed2:ip3:::14:porti51413eee10:tracker id3:abc15:warning message4:slowe";
+
+        let response = AnnounceResponse::from_bytes(bytes).unwrap();
+
+        assert_eq!(response.peers.to_string(), "10.0.0.10:6881\n[::1]:51413\n");
+        assert_eq!(response.tracker_id, Some(b"abc".to_vec()));
+        assert_eq!(response.warning_message, Some("slow".into()));
+    }
+
+    #[test]
+    fn test_compact_ipv6_peers() {
+        let mut bytes = b"d8:intervali1800e5:peers0:6:peers618:".to_vec();
+        bytes.extend([
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1,
+        ]);
+        bytes.extend(b"e");
+
+        let response = AnnounceResponse::from_bytes(&bytes).unwrap();
+
+        assert_eq!(response.peers.to_string(), "[2001:db8::1]:6881\n");
+    }
+
+    #[test]
+    fn test_scrape_response() {
+        let mut bytes = b"d5:filesd20:".to_vec();
+        bytes.extend([1u8; 20]);
+        bytes.extend(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+        let stats = parse_scrape_response(&bytes, &[[1u8; 20]]).unwrap();
+
+        assert_eq!(
+            stats,
+            vec![ScrapeStats {
+                seeders: 5,
+                completed: 50,
+                leechers: 10
+            }]
+        );
+        assert!(parse_scrape_response(&bytes, &[[2u8; 20]]).is_err());
+    }
+
+    #[test]
+    fn test_failure_reason() {
+        let bytes = b"d14:failure reason17:torrent not founde";
+
+        let err = AnnounceResponse::from_bytes(bytes).unwrap_err();
+
+        assert_eq!(err.to_string(), "tracker failure: torrent not found");
+    }
+}
@@ -0,0 +1,400 @@
+use crate::peers::Peers;
+use crate::random::random_u64;
+use crate::tracker::response::ScrapeStats;
+use crate::tracker::{AnnounceParams, AnnounceResponse, Event};
+use miette::miette;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+
+/// The magic constant sent in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+/// A connection id can be used for one minute after being received.
+const CONNECTION_TTL: Duration = Duration::from_secs(60);
+/// Requests are retransmitted after 15 * 2 ^ n seconds, n up to 8.
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRIES: u32 = 8;
+/// The attempts of a single request. The schedule goes on with the next
+/// request rather than blocking an announce for hours.
+const ATTEMPTS: u32 = 2;
+/// The most info hashes scraped in a request, to fit in a packet.
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// A client of a UDP tracker (BEP 15). Caches the connection id between
+/// requests.
+pub struct UdpTracker {
+    socket: UdpSocket,
+    /// Trackers reached over IPv6 return IPv6 peers.
+    ipv6: bool,
+    base_timeout: Duration,
+    /// The n of the retransmission schedule, reset by a response.
+    retries: u32,
+    connection: Option<(u64, Instant)>,
+}
+
+impl UdpTracker {
+    /// Returns a [`UdpTracker`] for the provided `udp://host:port` url.
+    pub async fn connect(url: &str) -> miette::Result<Self> {
+        let host = url
+            .strip_prefix("udp://")
+            .ok_or(miette!("expected udp url"))?
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        let addr = lookup_host(host)
+            .await
+            .map_err(|err| miette!(err))?
+            .next()
+            .ok_or(miette!("failed to resolve {host}"))?;
+
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await.map_err(|err| miette!(err))?;
+        socket.connect(addr).await.map_err(|err| miette!(err))?;
+
+        Ok(Self {
+            socket,
+            ipv6: addr.is_ipv6(),
+            base_timeout: BASE_TIMEOUT,
+            retries: 0,
+            connection: None,
+        })
+    }
+
+    /// Announces to the tracker.
+    pub async fn announce(&mut self, params: &AnnounceParams) -> miette::Result<AnnounceResponse> {
+        let mut body = Vec::with_capacity(82);
+        body.extend(params.info_hash);
+        body.extend(params.peer_id);
+        body.extend(params.downloaded.to_be_bytes());
+        body.extend(params.left.to_be_bytes());
+        body.extend(params.uploaded.to_be_bytes());
+        // event, ip (0 lets the tracker use the sender address), key
+        let event = params.event.as_ref().map(Event::udp_code);
+        body.extend(event.unwrap_or_default().to_be_bytes());
+        body.extend(0u32.to_be_bytes());
+        body.extend((random_u64() as u32).to_be_bytes());
+        // num_want, -1 for the tracker default
+        body.extend((-1i32).to_be_bytes());
+        body.extend(params.port.to_be_bytes());
+
+        // Response: interval, leechers, seeders, then the compact peers
+        let payload = self.request(ACTION_ANNOUNCE, &body).await?;
+        if payload.len() < 12 {
+            return Err(miette!("announce response too short"));
+        }
+        let read = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap()) as u64;
+        Ok(AnnounceResponse {
+            interval: read(0),
+            incomplete: Some(read(4)),
+            complete: Some(read(8)),
+            peers: if self.ipv6 {
+                Peers::from_compact6(&payload[12..])
+            } else {
+                Peers::from_compact(&payload[12..])
+            },
+            ..Default::default()
+        })
+    }
+
+    /// Scrapes the tracker for the provided info hashes, in batches of
+    /// [`MAX_SCRAPE_HASHES`]. The stats are returned in the same order as
+    /// the info hashes.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> miette::Result<Vec<ScrapeStats>> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let payload = self.request(ACTION_SCRAPE, &batch.concat()).await?;
+            let entries = payload.chunks_exact(12).map(|chunk| {
+                let read = |i: usize| u32::from_be_bytes(chunk[i..i + 4].try_into().unwrap());
+                ScrapeStats {
+                    seeders: read(0),
+                    completed: read(4),
+                    leechers: read(8),
+                }
+            });
+            if entries.len() != batch.len() {
+                return Err(miette!("expected {} scrape entries", batch.len()));
+            }
+            stats.extend(entries);
+        }
+        Ok(stats)
+    }
+
+    /// Sends a request with the provided action and body, connecting
+    /// first if needed. Requests are retransmitted following the BEP 15
+    /// schedule, [`ATTEMPTS`] times at most, where the previous request
+    /// left it. Returns the payload of the response, after the action and
+    /// transaction id.
+    async fn request(&mut self, action: u32, body: &[u8]) -> miette::Result<Vec<u8>> {
+        for _ in 0..ATTEMPTS {
+            let timeout = self.base_timeout * 2u32.pow(self.retries);
+            self.retries = (self.retries + 1).min(MAX_RETRIES);
+
+            let connection_id = match self.connection {
+                Some((id, received_at)) if received_at.elapsed() < CONNECTION_TTL => id,
+                _ => {
+                    let Some(payload) = self
+                        .exchange(PROTOCOL_ID, ACTION_CONNECT, &[], timeout)
+                        .await?
+                    else {
+                        continue;
+                    };
+                    let id = payload
+                        .get(..8)
+                        .ok_or(miette!("connect response too short"))?;
+                    let id = u64::from_be_bytes(id.try_into().unwrap());
+                    self.connection = Some((id, Instant::now()));
+                    id
+                }
+            };
+
+            if let Some(payload) = self.exchange(connection_id, action, body, timeout).await? {
+                self.retries = 0;
+                return Ok(payload);
+            }
+        }
+        Err(miette!("tracker did not respond"))
+    }
+
+    /// Sends a single packet and waits for the matching response until
+    /// the timeout. Returns None on timeout.
+    async fn exchange(
+        &self,
+        connection_id: u64,
+        action: u32,
+        body: &[u8],
+        timeout: Duration,
+    ) -> miette::Result<Option<Vec<u8>>> {
+        let transaction = random_u64() as u32;
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend(connection_id.to_be_bytes());
+        packet.extend(action.to_be_bytes());
+        packet.extend(transaction.to_be_bytes());
+        packet.extend(body);
+        self.socket
+            .send(&packet)
+            .await
+            .map_err(|err| miette!(err))?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut buffer = vec![0u8; 4096];
+        loop {
+            let Ok(len) = tokio::time::timeout_at(deadline, self.socket.recv(&mut buffer)).await
+            else {
+                return Ok(None);
+            };
+            let len = len.map_err(|err| miette!(err))?;
+            if len < 8 {
+                continue;
+            }
+
+            // Responses to previous attempts are ignored
+            let response_action = u32::from_be_bytes(buffer[..4].try_into().unwrap());
+            let response_transaction = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
+            if response_transaction != transaction {
+                continue;
+            }
+
+            if response_action == ACTION_ERROR {
+                let message = String::from_utf8_lossy(&buffer[8..len]);
+                return Err(miette!("tracker error: {message}"));
+            }
+            if response_action != action {
+                return Err(miette!("expected action {action}, got {response_action}"));
+            }
+            return Ok(Some(buffer[8..len].to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+    use crate::tracker::Trackers;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const CONNECTION_ID: u64 = 0xdeadbeef;
+
+    /// Spawns a fake tracker, dropping the first `drop` packets it receives.
+    /// Returns the url of the tracker and the count of connect requests.
+    async fn fake_tracker(drop: usize, error: Option<&'static str>) -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 2048];
+            let mut received = 0;
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                received += 1;
+                if received <= drop {
+                    continue;
+                }
+                let action = u32::from_be_bytes(buffer[8..12].try_into().unwrap());
+                let transaction = &buffer[12..16];
+
+                let mut response = vec![];
+                if let Some(message) = error {
+                    response.extend(ACTION_ERROR.to_be_bytes());
+                    response.extend(transaction);
+                    response.extend(message.as_bytes());
+                } else if action == ACTION_CONNECT {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    response.extend(ACTION_CONNECT.to_be_bytes());
+                    response.extend(transaction);
+                    response.extend(CONNECTION_ID.to_be_bytes());
+                } else {
+                    assert_eq!(&buffer[..8], CONNECTION_ID.to_be_bytes());
+                    response.extend(action.to_be_bytes());
+                    response.extend(transaction);
+                    if action == ACTION_ANNOUNCE {
+                        assert_eq!(len, 98);
+                        // interval, leechers, seeders, 2 peers
+                        response.extend(1800u32.to_be_bytes());
+                        response.extend(1u32.to_be_bytes());
+                        response.extend(1u32.to_be_bytes());
+                        response.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+                        response.extend([10, 0, 0, 2, 0x1a, 0xe2]);
+                    } else {
+                        // One scrape entry per info hash
+                        assert!(len - 16 <= MAX_SCRAPE_HASHES * 20);
+                        for _ in 0..(len - 16) / 20 {
+                            response.extend([0, 0, 0, 5, 0, 0, 0, 10, 0, 0, 0, 2]);
+                        }
+                    }
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        (url, connects)
+    }
+
+    async fn client(url: &str) -> UdpTracker {
+        let mut tracker = UdpTracker::connect(url).await.unwrap();
+        tracker.base_timeout = Duration::from_millis(50);
+        tracker
+    }
+
+    fn params() -> AnnounceParams {
+        AnnounceParams {
+            info_hash: [1u8; 20],
+            peer_id: [2u8; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: None,
+            tracker_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let (url, _) = fake_tracker(0, None).await;
+        let mut tracker = client(&url).await;
+
+        let response = tracker.announce(&params()).await.unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(
+            response.peers.to_string(),
+            "127.0.0.1:6881\n10.0.0.2:6882\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_id_is_cached() {
+        let (url, connects) = fake_tracker(0, None).await;
+        let mut tracker = client(&url).await;
+
+        tracker.announce(&params()).await.unwrap();
+        tracker.announce(&params()).await.unwrap();
+
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_trackers_reuse_the_connection() {
+        let (url, connects) = fake_tracker(0, None).await;
+        let torrent = Torrent {
+            announce: Some(url),
+            ..Torrent::single_piece(b"hello")
+        };
+        let mut trackers = Trackers::new(&torrent);
+
+        trackers.announce(&params()).await.unwrap();
+        trackers.announce(&params()).await.unwrap();
+
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retransmission() {
+        // The first connect request is lost
+        let (url, _) = fake_tracker(1, None).await;
+        let mut tracker = client(&url).await;
+
+        let response = tracker.announce(&params()).await.unwrap();
+
+        assert_eq!(response.peers.0.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retransmission_across_requests() {
+        // The first three requests are lost, more than the attempts of one
+        let (url, _) = fake_tracker(3, None).await;
+        let mut tracker = client(&url).await;
+
+        assert!(tracker.announce(&params()).await.is_err());
+        assert_eq!(tracker.retries, 2);
+        tracker.announce(&params()).await.unwrap();
+        assert_eq!(tracker.retries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let (url, _) = fake_tracker(0, None).await;
+        let mut tracker = client(&url).await;
+
+        let stats = tracker.scrape(&[[1u8; 20], [2u8; 20]]).await.unwrap();
+
+        let expected = ScrapeStats {
+            seeders: 5,
+            completed: 10,
+            leechers: 2,
+        };
+        assert_eq!(stats, vec![expected, expected]);
+    }
+
+    #[tokio::test]
+    async fn test_error_action() {
+        let (url, _) = fake_tracker(0, Some("unknown torrent")).await;
+        let mut tracker = client(&url).await;
+
+        let err = tracker.announce(&params()).await.unwrap_err();
+
+        assert_eq!(err.to_string(), "tracker error: unknown torrent");
+    }
+
+    #[tokio::test]
+    async fn test_scrape_in_batches() {
+        let (url, _) = fake_tracker(0, None).await;
+        let mut tracker = client(&url).await;
+        let info_hashes: Vec<_> = (0..100u8).map(|i| [i; 20]).collect();
+
+        let stats = tracker.scrape(&info_hashes).await.unwrap();
+
+        assert_eq!(stats.len(), 100);
+    }
+}
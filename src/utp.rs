@@ -0,0 +1,335 @@
+mod ledbat;
+mod packet;
+mod stream;
+
+use crate::random::random_u64;
+use crate::utp::packet::{Packet, PacketType};
+use crate::utp::stream::{Connection, Rto};
+use miette::miette;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub use crate::utp::stream::UtpStream;
+
+/// The connections waiting to be accepted.
+const ACCEPT_BACKLOG: usize = 32;
+/// The datagrams of other protocols waiting to be received.
+const DATAGRAM_BACKLOG: usize = 256;
+
+/// A datagram which isn't a uTP packet, with its sender.
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+/// The connections of the socket, by peer and id of the packets received.
+type Connections = Arc<Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>>;
+
+/// A UDP socket carrying uTP connections (BEP 29). The datagrams which
+/// aren't uTP packets, like the DHT messages, are handed out separately
+/// so both protocols share the socket.
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    local_addr: SocketAddr,
+    connections: Connections,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<(UtpStream, SocketAddr)>>,
+    datagrams: Mutex<Option<mpsc::Receiver<Datagram>>>,
+    rto: Rto,
+    task: JoinHandle<()>,
+}
+
+impl UtpSocket {
+    /// Binds the socket to the address and starts receiving packets.
+    pub async fn bind(address: SocketAddr) -> miette::Result<Self> {
+        Self::bind_with_rto(address, Rto::default()).await
+    }
+
+    /// Binds the socket, its connections retransmitting with the timeout.
+    async fn bind_with_rto(address: SocketAddr, rto: Rto) -> miette::Result<Self> {
+        let socket = UdpSocket::bind(address)
+            .await
+            .map_err(|err| miette!("failed to bind {address}: {err}"))?;
+        let local_addr = socket.local_addr().map_err(|err| miette!(err))?;
+        let socket = Arc::new(socket);
+        let connections = Connections::default();
+        let (accept, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let (other, datagrams) = mpsc::channel(DATAGRAM_BACKLOG);
+        let task = tokio::spawn(receive(
+            socket.clone(),
+            connections.clone(),
+            accept,
+            other,
+            rto,
+        ));
+        Ok(Self {
+            socket,
+            local_addr,
+            connections,
+            incoming: tokio::sync::Mutex::new(incoming),
+            datagrams: Mutex::new(Some(datagrams)),
+            rto,
+            task,
+        })
+    }
+
+    /// Returns the address the socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the underlying socket, to send datagrams of other protocols.
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        self.socket.clone()
+    }
+
+    /// Returns the datagrams which aren't uTP packets. Only the first call
+    /// gets them.
+    pub fn take_datagrams(&self) -> Option<mpsc::Receiver<Datagram>> {
+        self.datagrams.lock().expect("poisoned lock").take()
+    }
+
+    /// Connects to the peer.
+    pub async fn connect(&self, address: SocketAddr) -> miette::Result<UtpStream> {
+        let (sender, packets) = mpsc::unbounded_channel();
+        let recv_id = {
+            let mut connections = self.connections.lock().expect("poisoned lock");
+            let recv_id = loop {
+                let recv_id = random_u64() as u16;
+                if !connections.contains_key(&(address, recv_id)) {
+                    break recv_id;
+                }
+            };
+            connections.insert((address, recv_id), sender);
+            recv_id
+        };
+
+        let (mut connection, stream) = Connection::new(
+            self.socket.clone(),
+            address,
+            recv_id.wrapping_add(1),
+            recv_id,
+            1,
+            0,
+            self.rto,
+        );
+        let connected = connection.connect().await;
+        spawn(
+            connection,
+            packets,
+            self.connections.clone(),
+            (address, recv_id),
+        );
+        connected
+            .await
+            .map_err(|_| miette!("uTP connection to {address} closed"))?
+            .map_err(|err| miette!("uTP connection to {address} failed: {err}"))?;
+        Ok(stream)
+    }
+
+    /// Waits for a peer to connect.
+    pub async fn accept(&self) -> miette::Result<(UtpStream, SocketAddr)> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(miette!("uTP socket closed"))
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Runs the connection, forgetting it once done.
+fn spawn(
+    connection: Connection,
+    packets: mpsc::UnboundedReceiver<Packet>,
+    connections: Connections,
+    key: (SocketAddr, u16),
+) {
+    tokio::spawn(async move {
+        connection.run(packets).await;
+        connections.lock().expect("poisoned lock").remove(&key);
+    });
+}
+
+/// Receives the datagrams of the socket. Packets go to their connection,
+/// SYNs open new ones, anything else is handed out as is.
+async fn receive(
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    accept: mpsc::Sender<(UtpStream, SocketAddr)>,
+    other: mpsc::Sender<Datagram>,
+    rto: Rto,
+) {
+    let mut buffer = [0u8; 65536];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Ok(packet) = Packet::from_bytes(&buffer[..len]) else {
+            let _ = other.try_send((buffer[..len].to_vec(), from));
+            continue;
+        };
+
+        // The peer sends its SYN with the id of the packets we send, one
+        // less than the id of the packets it sends
+        let id = match packet.kind {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let mut connections_lock = connections.lock().expect("poisoned lock");
+        if let Some(sender) = connections_lock.get(&(from, id)) {
+            let _ = sender.send(packet);
+            continue;
+        }
+        if packet.kind != PacketType::Syn {
+            continue;
+        }
+
+        let (connection, stream) = Connection::new(
+            socket.clone(),
+            from,
+            packet.connection_id,
+            id,
+            random_u64() as u16,
+            packet.seq_nr,
+            rto,
+        );
+        if accept.try_send((stream, from)).is_err() {
+            continue;
+        }
+        let (sender, packets) = mpsc::unbounded_channel();
+        // The connection answers the SYN as a repeated one
+        let _ = sender.send(packet);
+        connections_lock.insert((from, id), sender);
+        drop(connections_lock);
+        spawn(connection, packets, connections.clone(), (from, id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::random_bytes;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Forwards the datagrams between a client and the server, dropping
+    /// one in `loss` of them. Returns the address the client sends to.
+    async fn lossy_proxy(server: SocketAddr, loss: u64) -> SocketAddr {
+        let proxy = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = proxy.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buffer = [0u8; 65536];
+            loop {
+                let (len, from) = proxy.recv_from(&mut buffer).await.unwrap();
+                if random_u64().is_multiple_of(loss) {
+                    continue;
+                }
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+                let _ = proxy.send_to(&buffer[..len], to).await;
+            }
+        });
+        address
+    }
+
+    /// Binds a socket retransmitting sooner than by default.
+    async fn bind() -> UtpSocket {
+        let rto = Rto {
+            initial: Duration::from_millis(200),
+            min: Duration::from_millis(50),
+        };
+        UtpSocket::bind_with_rto("127.0.0.1:0".parse().unwrap(), rto)
+            .await
+            .unwrap()
+    }
+
+    async fn transfer(loss: Option<u64>, len: usize) {
+        let server = bind().await;
+        let client = bind().await;
+        let address = match loss {
+            Some(loss) => lossy_proxy(server.local_addr(), loss).await,
+            None => server.local_addr(),
+        };
+
+        let data: Vec<u8> = (0..len / 8).flat_map(|_| random_bytes::<8>()).collect();
+        let sent = data.clone();
+        let sender = tokio::spawn(async move {
+            let mut stream = client.connect(address).await.unwrap();
+            stream.write_all(&sent).await.unwrap();
+            stream.shutdown().await.unwrap();
+            // The answer comes back over the same connection
+            let mut answer = vec![];
+            stream.read_to_end(&mut answer).await.unwrap();
+            answer
+        });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(received == data);
+        stream.write_all(b"thanks").await.unwrap();
+        drop(stream);
+
+        let answer = tokio::time::timeout(Duration::from_secs(20), sender)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(answer, b"thanks");
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        tokio::time::timeout(Duration::from_secs(20), transfer(None, 1 << 20))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transfer_with_loss() {
+        tokio::time::timeout(Duration::from_secs(30), transfer(Some(10), 256 << 10))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let client = bind().await;
+        // The server never answers
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let res = client.connect(server.local_addr().unwrap()).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_other_datagrams() {
+        let socket = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut datagrams = socket.take_datagrams().unwrap();
+        assert!(socket.take_datagrams().is_none());
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .send_to(b"d1:y1:qe", socket.local_addr())
+            .await
+            .unwrap();
+        let (bytes, from) = datagrams.recv().await.unwrap();
+        assert_eq!(bytes, b"d1:y1:qe");
+        assert_eq!(from, sender.local_addr().unwrap());
+    }
+}
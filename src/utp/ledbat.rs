@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The largest payload of a packet, keeping the datagrams under the usual
+/// MTU.
+pub const MAX_PAYLOAD: usize = 1380;
+/// The queuing delay LEDBAT aims for, in microseconds.
+const TARGET_DELAY: f64 = 100_000.0;
+/// The most the window grows in a round trip, in bytes.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+/// The window never gets smaller than one packet.
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+/// The window of a new connection.
+const INITIAL_WINDOW: f64 = 2.0 * MAX_PAYLOAD as f64;
+/// The base delay is the lowest delay of the last minutes.
+const BASE_DELAY_HISTORY: usize = 2;
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The LEDBAT congestion controller: the window grows while the one-way
+/// delay of the packets stays under the target, and shrinks as the queues
+/// on the path fill up.
+pub struct Ledbat {
+    /// The bytes allowed in flight.
+    window: f64,
+    /// The lowest delay of each interval, the current one last.
+    base_delays: VecDeque<u32>,
+    interval_start: Instant,
+}
+
+impl Ledbat {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            base_delays: VecDeque::new(),
+            interval_start: now,
+        }
+    }
+
+    /// Returns the bytes allowed in flight.
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// Updates the window once bytes are acked. The delay is the one-way
+    /// delay measured by the peer, in microseconds.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        self.update_base_delay(delay, now);
+        let base_delay = self.base_delays.iter().min().copied().unwrap_or(delay);
+        let queuing_delay = delay.saturating_sub(base_delay) as f64;
+
+        let off_target = (TARGET_DELAY - queuing_delay) / TARGET_DELAY;
+        let window_factor = bytes_acked as f64 / self.window.max(bytes_acked as f64);
+        self.window += MAX_WINDOW_INCREASE * off_target * window_factor;
+        self.window = self.window.max(MIN_WINDOW);
+    }
+
+    /// Halves the window once a packet is lost.
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+    }
+
+    /// Shrinks the window to a single packet once the acks stop.
+    pub fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+
+    fn update_base_delay(&mut self, delay: u32, now: Instant) {
+        if self.base_delays.is_empty() || now - self.interval_start >= BASE_DELAY_INTERVAL {
+            if self.base_delays.len() == BASE_DELAY_HISTORY {
+                self.base_delays.pop_front();
+            }
+            self.base_delays.push_back(delay);
+            self.interval_start = now;
+        }
+        let current = self.base_delays.back_mut().expect("at least one interval");
+        *current = (*current).min(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grows_under_target() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+        for _ in 0..100 {
+            ledbat.on_ack(MAX_PAYLOAD, 20_000, now);
+        }
+        assert!(ledbat.window() > INITIAL_WINDOW as usize * 10);
+    }
+
+    #[test]
+    fn test_shrinks_over_target() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+        for _ in 0..100 {
+            ledbat.on_ack(MAX_PAYLOAD, 20_000, now);
+        }
+        let window = ledbat.window();
+        // The queues add 150ms on top of the base delay
+        for _ in 0..10 {
+            ledbat.on_ack(MAX_PAYLOAD, 170_000, now);
+        }
+        assert!(ledbat.window() < window);
+    }
+
+    #[test]
+    fn test_base_delay_expires() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+        ledbat.on_ack(MAX_PAYLOAD, 10_000, now);
+        // The route changed, the higher delay becomes the base
+        let later = now + BASE_DELAY_INTERVAL * 3;
+        ledbat.on_ack(MAX_PAYLOAD, 300_000, later);
+        ledbat.on_ack(MAX_PAYLOAD, 300_000, later + BASE_DELAY_INTERVAL);
+        let window = ledbat.window();
+        ledbat.on_ack(MAX_PAYLOAD, 300_000, later + BASE_DELAY_INTERVAL);
+        assert!(ledbat.window() > window);
+    }
+
+    #[test]
+    fn test_loss_and_timeout() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+        for _ in 0..100 {
+            ledbat.on_ack(MAX_PAYLOAD, 20_000, now);
+        }
+        let window = ledbat.window();
+        ledbat.on_loss();
+        assert!(ledbat.window().abs_diff(window / 2) <= 1);
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MAX_PAYLOAD);
+    }
+}
@@ -0,0 +1,173 @@
+use miette::miette;
+
+/// The length of the header, without extensions.
+pub const HEADER_LEN: usize = 20;
+/// The version of the protocol, in the low bits of the first byte.
+const VERSION: u8 = 1;
+/// The extension carrying the selective acks.
+const SELECTIVE_ACK: u8 = 1;
+
+/// The type of a packet, in the high bits of the first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+}
+
+/// A uTP packet (BEP 29).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub kind: PacketType,
+    pub connection_id: u16,
+    /// When the packet was sent, in microseconds.
+    pub timestamp: u32,
+    /// The delay of the last packet received, in microseconds.
+    pub timestamp_diff: u32,
+    /// The bytes the sender can still receive.
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// The selective acks, bit 0 for `ack_nr + 2`.
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Returns a packet without payload, the other fields set to zero.
+    pub fn new(kind: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq_nr,
+            ack_nr,
+            sack: None,
+            payload: vec![],
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let extension = if self.sack.is_some() {
+            SELECTIVE_ACK
+        } else {
+            0
+        };
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(extension);
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(self.window.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            bytes.push(0);
+            bytes.push(sack.len() as u8);
+            bytes.extend(sack);
+        }
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> miette::Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            return Err(miette!("uTP packet too short"));
+        }
+        if bytes[0] & 0x0f != VERSION {
+            return Err(miette!("invalid uTP version {}", bytes[0] & 0x0f));
+        }
+        let kind = PacketType::from_u8(bytes[0] >> 4)
+            .ok_or(miette!("invalid uTP packet type {}", bytes[0] >> 4))?;
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().expect("4 bytes"));
+
+        // Extensions are chained, each one tells the type of the next
+        let mut extension = bytes[1];
+        let mut position = HEADER_LEN;
+        let mut sack = None;
+        while extension != 0 {
+            let header = bytes
+                .get(position..position + 2)
+                .ok_or(miette!("truncated uTP extension"))?;
+            let (next, len) = (header[0], header[1] as usize);
+            let data = bytes
+                .get(position + 2..position + 2 + len)
+                .ok_or(miette!("truncated uTP extension"))?;
+            if extension == SELECTIVE_ACK {
+                sack = Some(data.to_vec());
+            }
+            extension = next;
+            position += 2 + len;
+        }
+
+        Ok(Self {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: bytes[position..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut packet = Packet::new(PacketType::Data, 0x1234, 7, 3);
+        packet.timestamp = 1_000_000;
+        packet.timestamp_diff = 2_000;
+        packet.window = 1 << 20;
+        packet.payload = b"hello".to_vec();
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + 5);
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(Packet::from_bytes(&bytes).unwrap(), packet);
+
+        packet.kind = PacketType::State;
+        packet.sack = Some(vec![0b101, 0, 0, 0]);
+        packet.payload = vec![];
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[0], 0x21);
+        assert_eq!(bytes[1], SELECTIVE_ACK);
+        assert_eq!(Packet::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Packet::from_bytes(&[0x01; 19]).is_err());
+        // A bencoded DHT message isn't a uTP packet
+        assert!(
+            Packet::from_bytes(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe")
+                .is_err()
+        );
+        let mut bytes = Packet::new(PacketType::Syn, 1, 1, 0).to_bytes();
+        bytes[1] = SELECTIVE_ACK;
+        assert!(Packet::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,585 @@
+use crate::utp::ledbat::{Ledbat, MAX_PAYLOAD};
+use crate::utp::packet::{Packet, PacketType};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::Instant;
+
+/// The bytes received we advertise room for.
+const RECV_BUFFER: usize = 1 << 20;
+/// The bytes written waiting to be sent, before writes block.
+const SEND_BUFFER: usize = 1 << 20;
+/// The most packets in flight, or received ahead of the next one.
+const MAX_PACKETS: u16 = 1024;
+/// The longest selective ack bitmask sent, in bytes.
+const MAX_SACK_LEN: usize = 128;
+/// Packets acked selectively this many times past a packet, or this many
+/// duplicate acks, mean the packet is lost.
+const LOSS_THRESHOLD: u32 = 3;
+const MAX_RTO: Duration = Duration::from_secs(16);
+/// The connection fails after this many timeouts in a row.
+const MAX_TIMEOUTS: u32 = 6;
+const MAX_SYN_TIMEOUTS: u32 = 3;
+
+/// The retransmission timeout of the connections, before any round trip
+/// is measured and at the least after.
+#[derive(Debug, Clone, Copy)]
+pub struct Rto {
+    pub initial: Duration,
+    pub min: Duration,
+}
+
+impl Default for Rto {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            min: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Returns the current time in microseconds, as sent in the packets.
+fn now_micros() -> u32 {
+    static START: OnceLock<std::time::Instant> = OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_micros() as u32
+}
+
+/// The buffers between a [`UtpStream`] and its connection task.
+#[derive(Default)]
+struct Buffers {
+    /// Bytes received in order, not read yet.
+    received: VecDeque<u8>,
+    /// Bytes written, not sent yet.
+    unsent: VecDeque<u8>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// Set once the FIN of the peer is received, with every byte before it.
+    eof: bool,
+    /// Set once no more bytes will be written.
+    shutdown: bool,
+    /// Set once the stream is dropped.
+    dropped: bool,
+    /// The error which ended the connection.
+    error: Option<io::ErrorKind>,
+}
+
+struct Shared {
+    buffers: Mutex<Buffers>,
+    /// Wakes the connection task once bytes are written or the stream is
+    /// shut down.
+    notify: Notify,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Buffers> {
+        self.buffers.lock().expect("poisoned lock")
+    }
+}
+
+/// A reliable ordered stream over uTP. The packets are sent and received
+/// by a connection task, the stream only exchanges bytes with it.
+pub struct UtpStream {
+    shared: Arc<Shared>,
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut buffers = self.shared.lock();
+        if !buffers.received.is_empty() {
+            let (front, _) = buffers.received.as_slices();
+            let len = front.len().min(buf.remaining());
+            buf.put_slice(&front[..len]);
+            buffers.received.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        if buffers.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = buffers.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        buffers.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buffers = self.shared.lock();
+        if let Some(kind) = buffers.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if buffers.shutdown {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = buf.len().min(SEND_BUFFER - buffers.unsent.len());
+        if len == 0 {
+            buffers.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        buffers.unsent.extend(&buf[..len]);
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.lock().shutdown = true;
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut buffers = self.shared.lock();
+        buffers.shutdown = true;
+        buffers.dropped = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+/// A packet sent and not acked yet.
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Set once the peer acked it selectively.
+    acked: bool,
+    /// Set once resent after it was found lost.
+    resent: bool,
+}
+
+/// The state of a uTP connection, run by its own task.
+pub struct Connection {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    shared: Arc<Shared>,
+    send_id: u16,
+    recv_id: u16,
+    /// The sequence number of the next packet sent.
+    seq_nr: u16,
+    /// The sequence number of the last packet received in order.
+    ack_nr: u16,
+    /// Set until the peer answers our SYN.
+    connecting: Option<oneshot::Sender<io::Result<()>>>,
+    in_flight: VecDeque<Sent>,
+    /// The packets received ahead of the next one.
+    out_of_order: HashMap<u16, Packet>,
+    fin_sent: bool,
+    ledbat: Ledbat,
+    srtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    min_rto: Duration,
+    /// The bytes the peer can still receive.
+    peer_window: usize,
+    /// The delay of the last packet received, echoed to the peer.
+    reply_micros: u32,
+    last_ack: u16,
+    duplicate_acks: u32,
+    timeouts: u32,
+    /// Losses of packets before this one belong to the window already
+    /// shrunk.
+    recovery: u16,
+}
+
+impl Connection {
+    /// Returns a new connection to the peer, with the stream exchanging its
+    /// bytes.
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        peer: SocketAddr,
+        send_id: u16,
+        recv_id: u16,
+        seq_nr: u16,
+        ack_nr: u16,
+        rto: Rto,
+    ) -> (Self, UtpStream) {
+        let shared = Arc::new(Shared {
+            buffers: Mutex::new(Buffers::default()),
+            notify: Notify::new(),
+        });
+        let connection = Self {
+            socket,
+            peer,
+            shared: shared.clone(),
+            send_id,
+            recv_id,
+            seq_nr,
+            ack_nr,
+            connecting: None,
+            in_flight: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_sent: false,
+            ledbat: Ledbat::new(Instant::now().into_std()),
+            srtt: None,
+            rtt_var: Duration::ZERO,
+            rto: rto.initial,
+            min_rto: rto.min,
+            peer_window: MAX_PAYLOAD,
+            reply_micros: 0,
+            last_ack: 0,
+            duplicate_acks: 0,
+            timeouts: 0,
+            recovery: seq_nr,
+        };
+        (connection, UtpStream { shared })
+    }
+
+    /// Sends our SYN. The receiver is told once the peer answers it.
+    pub async fn connect(&mut self) -> oneshot::Receiver<io::Result<()>> {
+        let (sender, receiver) = oneshot::channel();
+        self.connecting = Some(sender);
+        let mut syn = Packet::new(PacketType::Syn, self.recv_id, self.seq_nr, 0);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.send(&mut syn).await;
+        self.in_flight.push_back(Sent {
+            packet: syn,
+            sent_at: Instant::now(),
+            transmissions: 1,
+            acked: false,
+            resent: false,
+        });
+        receiver
+    }
+
+    /// Runs the connection until both sides are done, or it fails.
+    pub async fn run(mut self, mut packets: mpsc::UnboundedReceiver<Packet>) {
+        if let Err(kind) = self.run_until_done(&mut packets).await {
+            let mut buffers = self.shared.lock();
+            buffers.error = Some(kind);
+            wake(&mut buffers.read_waker);
+            wake(&mut buffers.write_waker);
+            drop(buffers);
+            if let Some(connecting) = self.connecting.take() {
+                let _ = connecting.send(Err(kind.into()));
+            }
+        }
+    }
+
+    async fn run_until_done(
+        &mut self,
+        packets: &mut mpsc::UnboundedReceiver<Packet>,
+    ) -> Result<(), io::ErrorKind> {
+        loop {
+            self.send_data().await;
+            if self.is_done() {
+                return Ok(());
+            }
+
+            // Only the oldest packet not acked is timed
+            let deadline = self
+                .in_flight
+                .iter()
+                .find(|sent| !sent.acked)
+                .map(|sent| sent.sent_at + self.rto);
+            tokio::select! {
+                packet = packets.recv() => {
+                    let packet = packet.ok_or(io::ErrorKind::ConnectionAborted)?;
+                    self.handle(packet).await?;
+                }
+                _ = self.shared.notify.notified() => {}
+                _ = sleep_until(deadline) => self.on_timeout().await?,
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        let buffers = self.shared.lock();
+        self.fin_sent && self.in_flight.is_empty() && (buffers.eof || buffers.dropped)
+    }
+
+    async fn handle(&mut self, packet: Packet) -> Result<(), io::ErrorKind> {
+        if packet.kind == PacketType::Reset {
+            return Err(io::ErrorKind::ConnectionReset);
+        }
+        if self.connecting.is_some() {
+            // The first packet of the peer acks our SYN, the ones before
+            // it can't be placed
+            if packet.ack_nr != self.seq_nr.wrapping_sub(1) {
+                return Ok(());
+            }
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some(connecting) = self.connecting.take() {
+                let _ = connecting.send(Ok(()));
+            }
+        }
+
+        self.peer_window = packet.window as usize;
+        self.reply_micros = now_micros().wrapping_sub(packet.timestamp);
+        self.process_acks(&packet).await;
+        match packet.kind {
+            PacketType::Data | PacketType::Fin => self.receive(packet).await,
+            // A SYN again means our answer was lost
+            PacketType::Syn => self.send_ack().await,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Removes the packets acked by the peer, then resends the ones which
+    /// look lost.
+    async fn process_acks(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let mut acked_bytes = 0;
+        let mut rtt = None;
+        let mut ack = |sent: &Sent| {
+            if !sent.acked {
+                acked_bytes += sent.packet.payload.len();
+                if sent.transmissions == 1 {
+                    rtt = Some(now - sent.sent_at);
+                }
+            }
+        };
+
+        while let Some(sent) = self.in_flight.front() {
+            if (sent.packet.seq_nr.wrapping_sub(packet.ack_nr) as i16) > 0 {
+                break;
+            }
+            ack(sent);
+            self.in_flight.pop_front();
+        }
+        if let Some(sack) = &packet.sack {
+            for sent in self.in_flight.iter_mut() {
+                let bit = sent
+                    .packet
+                    .seq_nr
+                    .wrapping_sub(packet.ack_nr)
+                    .wrapping_sub(2) as usize;
+                if bit < sack.len() * 8 && sack[bit / 8] >> (bit % 8) & 1 == 1 {
+                    ack(sent);
+                    sent.acked = true;
+                }
+            }
+        }
+
+        if acked_bytes > 0 {
+            self.timeouts = 0;
+            self.duplicate_acks = 0;
+            self.ledbat
+                .on_ack(acked_bytes, packet.timestamp_diff, now.into_std());
+        } else if packet.kind == PacketType::State
+            && packet.ack_nr == self.last_ack
+            && !self.in_flight.is_empty()
+        {
+            self.duplicate_acks += 1;
+        }
+        self.last_ack = packet.ack_nr;
+        if let Some(rtt) = rtt {
+            self.update_rtt(rtt);
+        }
+
+        // A packet is lost once enough of the later ones arrived
+        let mut sacked_count = self.in_flight.iter().filter(|sent| sent.acked).count() as u32;
+        let mut lost = vec![];
+        for (i, sent) in self.in_flight.iter().enumerate() {
+            if sent.acked {
+                sacked_count -= 1;
+            } else if !sent.resent
+                && (sacked_count >= LOSS_THRESHOLD
+                    || (i == 0 && self.duplicate_acks >= LOSS_THRESHOLD))
+            {
+                lost.push(i);
+            }
+        }
+        for i in lost {
+            let seq_nr = self.in_flight[i].packet.seq_nr;
+            if seq_nr.wrapping_sub(self.recovery) as i16 >= 0 {
+                self.ledbat.on_loss();
+                self.recovery = self.seq_nr;
+            }
+            self.in_flight[i].resent = true;
+            self.resend(i).await;
+        }
+    }
+
+    fn update_rtt(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rtt_var = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rtt_var = self.rtt_var * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.expect("rtt measured");
+        self.rto = (srtt + self.rtt_var * 4).clamp(self.min_rto, MAX_RTO);
+    }
+
+    /// Delivers the packets in order, keeping the ones received ahead.
+    async fn receive(&mut self, packet: Packet) {
+        let offset = packet.seq_nr.wrapping_sub(self.ack_nr);
+        if offset != 0 && offset <= MAX_PACKETS {
+            self.out_of_order.insert(packet.seq_nr, packet);
+        }
+
+        self.deliver();
+        self.send_ack().await;
+    }
+
+    /// Hands the packets following the last one received to the stream.
+    fn deliver(&mut self) {
+        let mut buffers = self.shared.lock();
+        while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = next.seq_nr;
+            if next.kind == PacketType::Fin {
+                buffers.eof = true;
+                self.out_of_order.clear();
+            } else if !buffers.dropped {
+                buffers.received.extend(&next.payload);
+            }
+            wake(&mut buffers.read_waker);
+        }
+    }
+
+    async fn send_ack(&mut self) {
+        let mut ack = Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr);
+        if let Some(last) = self
+            .out_of_order
+            .keys()
+            .map(|seq_nr| seq_nr.wrapping_sub(self.ack_nr) as usize - 2)
+            .max()
+        {
+            let mut sack = vec![0u8; (last / 32 + 1).min(MAX_SACK_LEN / 4) * 4];
+            for seq_nr in self.out_of_order.keys() {
+                let bit = seq_nr.wrapping_sub(self.ack_nr) as usize - 2;
+                if bit < sack.len() * 8 {
+                    sack[bit / 8] |= 1 << (bit % 8);
+                }
+            }
+            ack.sack = Some(sack);
+        }
+        self.send(&mut ack).await;
+    }
+
+    /// Sends the bytes written as the windows allow, then our FIN once the
+    /// stream is shut down.
+    async fn send_data(&mut self) {
+        if self.connecting.is_some() {
+            return;
+        }
+        while let Some(mut packet) = self.next_packet() {
+            self.send(&mut packet).await;
+            self.in_flight.push_back(Sent {
+                packet,
+                sent_at: Instant::now(),
+                transmissions: 1,
+                acked: false,
+                resent: false,
+            });
+        }
+    }
+
+    /// Returns the next packet to send, if the windows allow it.
+    fn next_packet(&mut self) -> Option<Packet> {
+        let in_flight_bytes: usize = self
+            .in_flight
+            .iter()
+            .filter(|sent| !sent.acked)
+            .map(|sent| sent.packet.payload.len())
+            .sum();
+        let window = self.ledbat.window().min(self.peer_window);
+
+        let mut buffers = self.shared.lock();
+        let len = buffers.unsent.len().min(MAX_PAYLOAD);
+        let fin = len == 0 && buffers.shutdown && !self.fin_sent;
+        if len == 0 && !fin {
+            return None;
+        }
+        // A packet always goes out when none is in flight, which probes a
+        // closed window
+        if (!self.in_flight.is_empty() && in_flight_bytes + len > window)
+            || self.in_flight.len() >= MAX_PACKETS as usize
+        {
+            return None;
+        }
+
+        let kind = if fin {
+            PacketType::Fin
+        } else {
+            PacketType::Data
+        };
+        let mut packet = Packet::new(kind, self.send_id, self.seq_nr, self.ack_nr);
+        packet.payload = buffers.unsent.drain(..len).collect();
+        wake(&mut buffers.write_waker);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.fin_sent |= fin;
+        Some(packet)
+    }
+
+    async fn on_timeout(&mut self) -> Result<(), io::ErrorKind> {
+        self.timeouts += 1;
+        let max_timeouts = if self.connecting.is_some() {
+            MAX_SYN_TIMEOUTS
+        } else {
+            MAX_TIMEOUTS
+        };
+        if self.timeouts > max_timeouts {
+            return Err(io::ErrorKind::TimedOut);
+        }
+
+        self.ledbat.on_timeout();
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        if let Some(i) = self.in_flight.iter().position(|sent| !sent.acked) {
+            self.resend(i).await;
+        }
+        Ok(())
+    }
+
+    async fn resend(&mut self, i: usize) {
+        let mut packet = self.in_flight[i].packet.clone();
+        self.send(&mut packet).await;
+        let sent = &mut self.in_flight[i];
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+    }
+
+    /// Sends the packet with the current acks, window and timestamps.
+    async fn send(&mut self, packet: &mut Packet) {
+        packet.timestamp = now_micros();
+        packet.timestamp_diff = self.reply_micros;
+        packet.window = RECV_BUFFER.saturating_sub(self.shared.lock().received.len()) as u32;
+        if packet.kind != PacketType::Syn {
+            packet.ack_nr = self.ack_nr;
+        }
+        let _ = self.socket.send_to(&packet.to_bytes(), self.peer).await;
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
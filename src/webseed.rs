@@ -0,0 +1,478 @@
+use crate::bitfield::Bitfield;
+use crate::connection::wait_stopped;
+use crate::download::Download;
+use crate::torrent::{File, Info};
+use miette::miette;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The longest delay before fetching from a failing web seed again.
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+/// The delay before looking for a piece again once none is left, in case
+/// a peer gives one back.
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A web seed, fetching pieces over HTTP.
+enum Seed {
+    /// A server holding the files of the torrent (BEP 19), by url of file.
+    Files(Vec<String>),
+    /// A script serving the pieces by index (BEP 17).
+    Pieces(String),
+}
+
+/// The answer of a web seed to a piece request.
+enum Fetched {
+    Piece(Vec<u8>),
+    /// The seed is busy, and tells when to come back.
+    RetryAfter(Duration),
+}
+
+/// Downloads pieces from the web seed of the `url-list` (BEP 19) until the
+/// download is complete or stopped. Only HTTP seeds are supported.
+pub async fn run(download: Arc<Download>, url: String) {
+    if !is_http(&url) {
+        return;
+    }
+    let urls = file_urls(&download.torrent().info, &url);
+    fetch_pieces(download, Seed::Files(urls)).await
+}
+
+/// Downloads pieces from the HTTP seed of the `httpseeds` (BEP 17) until
+/// the download is complete or stopped.
+pub async fn run_http_seed(download: Arc<Download>, url: String) {
+    if !is_http(&url) {
+        return;
+    }
+    fetch_pieces(download, Seed::Pieces(url)).await
+}
+
+/// Returns true for an HTTP url, the others are skipped with a message.
+fn is_http(url: &str) -> bool {
+    let http = url.starts_with("http://") || url.starts_with("https://");
+    if !http {
+        eprintln!("Skipping web seed {url}: only HTTP is supported");
+    }
+    http
+}
+
+/// Fetches pieces from the seed, taking them from the same queue as the
+/// peers.
+async fn fetch_pieces(download: Arc<Download>, seed: Seed) {
+    let client = reqwest::Client::new();
+    // A web seed has every piece
+    let available = Bitfield::full(download.torrent().info.pieces_count());
+    let mut stopped = download.stopped();
+    let backoff = download.timeouts().web_seed_backoff;
+    let mut failures = 0;
+
+    while !download.is_complete() && !*stopped.borrow() {
+        let Some(index) = download.next_piece(&available) else {
+            tokio::select! {
+                _ = download.wait_complete() => {}
+                _ = wait_stopped(&mut stopped) => {}
+                _ = tokio::time::sleep(IDLE_INTERVAL) => {}
+            }
+            continue;
+        };
+
+        let delay = match seed.fetch(&client, &download, index).await {
+            Ok(Fetched::Piece(piece)) if download.verify(index, &piece) => {
+                download.complete_piece(index, piece);
+                failures = 0;
+                continue;
+            }
+            Ok(Fetched::RetryAfter(delay)) => delay.min(MAX_BACKOFF),
+            _ => {
+                failures += 1;
+                backoff
+                    .saturating_mul(1 << (failures - 1).min(16))
+                    .min(MAX_BACKOFF)
+            }
+        };
+        download.release_piece(index);
+        tokio::select! {
+            _ = wait_stopped(&mut stopped) => {}
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+}
+
+impl Seed {
+    async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        download: &Download,
+        index: u32,
+    ) -> miette::Result<Fetched> {
+        match self {
+            Seed::Files(urls) => fetch_piece(client, &download.torrent().info, urls, index)
+                .await
+                .map(Fetched::Piece),
+            Seed::Pieces(url) => fetch_http_seed_piece(client, download, url, index).await,
+        }
+    }
+}
+
+/// Returns the url of each file of the torrent on the web seed. A url
+/// ending with a slash is the directory of the torrent, which the name of
+/// the torrent is appended to.
+fn file_urls(info: &Info, url: &str) -> Vec<String> {
+    if info.files.is_empty() {
+        return match url.ends_with('/') {
+            true => vec![format!("{url}{}", escape(&info.name))],
+            false => vec![url.to_string()],
+        };
+    }
+    let base = url.trim_end_matches('/');
+    info.files
+        .iter()
+        .map(|file| {
+            let path = file
+                .path
+                .iter()
+                .map(|part| escape(part))
+                .collect::<Vec<_>>();
+            format!("{base}/{}/{}", escape(&info.name), path.join("/"))
+        })
+        .collect()
+}
+
+/// Escapes a part of a url path.
+fn escape(part: &str) -> String {
+    part.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Fetches the piece from the web seed, with a range request for each file
+/// the piece spans.
+async fn fetch_piece(
+    client: &reqwest::Client,
+    info: &Info,
+    urls: &[String],
+    index: u32,
+) -> miette::Result<Vec<u8>> {
+    let begin = info.piece_offset(index);
+    let mut piece = Vec::with_capacity(info.piece_len(index) as usize);
+    for (file, start, end) in info.file_ranges(begin, info.piece_len(index) as u64) {
+        // Web seeds don't have the padding files
+        if info.files.get(file).is_some_and(File::is_padding) {
+            piece.resize(piece.len() + (end - start) as usize, 0);
+            continue;
+        }
+        let res = client
+            .get(&urls[file])
+            .header(RANGE, format!("bytes={start}-{}", end - 1))
+            .send()
+            .await
+            .map_err(|err| miette!(err))?;
+        let range = match res.status() {
+            StatusCode::PARTIAL_CONTENT => res.bytes().await.map_err(|err| miette!(err))?.to_vec(),
+            // Servers ignoring ranges send the whole file
+            StatusCode::OK => read_range(res, start, end).await?,
+            status => return Err(miette!("web seed answered {status}")),
+        };
+        if range.len() as u64 != end - start {
+            return Err(miette!("web seed sent a truncated range"));
+        }
+        piece.extend(range);
+    }
+    Ok(piece)
+}
+
+/// Reads the range out of a whole file, without keeping the bytes before
+/// it and without reading the bytes after it.
+async fn read_range(mut res: reqwest::Response, start: u64, end: u64) -> miette::Result<Vec<u8>> {
+    let mut range = Vec::with_capacity((end - start) as usize);
+    let mut offset = 0;
+    while offset < end {
+        let Some(chunk) = res.chunk().await.map_err(|err| miette!(err))? else {
+            break;
+        };
+        let chunk_start = start.saturating_sub(offset).min(chunk.len() as u64);
+        let chunk_end = (end - offset).min(chunk.len() as u64);
+        range.extend(&chunk[chunk_start as usize..chunk_end as usize]);
+        offset += chunk.len() as u64;
+    }
+    Ok(range)
+}
+
+/// Fetches the whole piece from the HTTP seed, so without `ranges`. A busy
+/// seed answers with the seconds to wait before asking again.
+async fn fetch_http_seed_piece(
+    client: &reqwest::Client,
+    download: &Download,
+    url: &str,
+    index: u32,
+) -> miette::Result<Fetched> {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let info_hash: String = download
+        .info_hash()
+        .iter()
+        .map(|b| format!("%{b:02x}"))
+        .collect();
+    let res = client
+        .get(format!(
+            "{url}{separator}info_hash={info_hash}&piece={index}"
+        ))
+        .send()
+        .await
+        .map_err(|err| miette!(err))?;
+    let status = res.status();
+    let body = res.bytes().await.map_err(|err| miette!(err))?;
+    match status {
+        StatusCode::OK => Ok(Fetched::Piece(body.to_vec())),
+        StatusCode::SERVICE_UNAVAILABLE => {
+            let seconds = std::str::from_utf8(&body)
+                .ok()
+                .and_then(|body| body.trim().parse().ok())
+                .ok_or(miette!("HTTP seed is unavailable"))?;
+            Ok(Fetched::RetryAfter(Duration::from_secs(seconds)))
+        }
+        _ => Err(miette!("HTTP seed answered {status}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Timeouts;
+    use crate::torrent::Torrent;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Serves the files over HTTP, with range requests if asked. The first
+    /// requests fail with a server error.
+    async fn serve(
+        files: HashMap<String, Vec<u8>>,
+        failures: usize,
+        ranges: bool,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let files = Arc::new(files);
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let answer = answer(stream, files.clone(), counter.clone(), failures, ranges);
+                tokio::spawn(answer);
+            }
+        });
+        (format!("http://{address}"), requests)
+    }
+
+    async fn answer(
+        mut stream: TcpStream,
+        files: Arc<HashMap<String, Vec<u8>>>,
+        requests: Arc<AtomicUsize>,
+        failures: usize,
+        ranges: bool,
+    ) {
+        let mut buffer = vec![];
+        loop {
+            let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+                let mut chunk = [0u8; 1024];
+                match stream.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(len) => buffer.extend(&chunk[..len]),
+                }
+                continue;
+            };
+            let request = String::from_utf8(buffer.drain(..end + 4).collect()).unwrap();
+            let path = request.split(' ').nth(1).unwrap().to_string();
+            let range = request.lines().find_map(|line| {
+                let (start, end) = line
+                    .to_ascii_lowercase()
+                    .strip_prefix("range: bytes=")?
+                    .split_once('-')
+                    .map(|(a, b)| (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap()))?;
+                Some(start..end + 1)
+            });
+            let range = range.filter(|_| ranges);
+
+            let response = if requests.fetch_add(1, Ordering::Relaxed) < failures {
+                // HTTP seeds read the seconds to wait from the body
+                b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 1\r\n\r\n1".to_vec()
+            } else if let Some(file) = files.get(&path) {
+                let (status, body) = match range {
+                    Some(range) => ("206 Partial Content", &file[range]),
+                    None => ("200 OK", &file[..]),
+                };
+                let mut response = format!(
+                    "HTTP/1.1 {status}\r\ncontent-length: {}\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                response.extend(body);
+                response
+            } else {
+                b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n".to_vec()
+            };
+            if stream.write_all(&response).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_urls() {
+        let mut single = Torrent::with_files(b"hello", 4, vec![]);
+        single.info.name = "test dir".into();
+        assert_eq!(
+            file_urls(&single.info, "http://example.com/files/"),
+            vec!["http://example.com/files/test%20dir"]
+        );
+        assert_eq!(
+            file_urls(&single.info, "http://example.com/file.iso"),
+            vec!["http://example.com/file.iso"]
+        );
+
+        let files = vec![
+            File {
+                attr: String::new(),
+                length: 2,
+                path: vec!["a.txt".into()],
+                symlink_path: None,
+                sha1: None,
+            },
+            File {
+                attr: String::new(),
+                length: 3,
+                path: vec!["sub".into(), "b c.txt".into()],
+                symlink_path: None,
+                sha1: None,
+            },
+        ];
+        let mut multi = Torrent::with_files(b"hello", 4, files);
+        multi.info.name = "test dir".into();
+        assert_eq!(
+            file_urls(&multi.info, "http://example.com/files"),
+            vec![
+                "http://example.com/files/test%20dir/a.txt",
+                "http://example.com/files/test%20dir/sub/b%20c.txt",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multi_file_seed() {
+        let a = b"first file, split over pieces".to_vec();
+        let b = b"second file".to_vec();
+        let data = [a.clone(), b.clone()].concat();
+        let files = vec![
+            File {
+                attr: String::new(),
+                length: a.len() as u32,
+                path: vec!["a.txt".into()],
+                symlink_path: None,
+                sha1: None,
+            },
+            File {
+                attr: String::new(),
+                length: b.len() as u32,
+                path: vec!["sub".into(), "b.txt".into()],
+                symlink_path: None,
+                sha1: None,
+            },
+        ];
+        let mut torrent = Torrent::with_files(&data, 8, files);
+        torrent.info.name = "test dir".into();
+        let served = HashMap::from([
+            ("/seed/test%20dir/a.txt".to_string(), a),
+            ("/seed/test%20dir/sub/b.txt".to_string(), b),
+        ]);
+        // The seed fails at first, then backs off
+        let (url, requests) = serve(served, 2, true).await;
+
+        let download = Download::new(Arc::new(torrent), [1u8; 20]);
+        download.set_timeouts(Timeouts {
+            web_seed_backoff: Duration::from_millis(10),
+            ..Timeouts::default()
+        });
+        download.add_web_seeds(&[format!("{url}/seed/")]);
+        tokio::time::timeout(Duration::from_secs(5), download.wait_complete())
+            .await
+            .unwrap();
+        assert_eq!(download.data().unwrap(), data);
+        // Piece 3 spans both files
+        assert!(requests.load(Ordering::Relaxed) > data.len().div_ceil(8));
+    }
+
+    #[tokio::test]
+    async fn test_seed_ignoring_ranges() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let torrent = Torrent::with_files(&data, 16384, vec![]);
+        let served = HashMap::from([("/file".to_string(), data.clone())]);
+        let (url, _) = serve(served, 0, false).await;
+
+        let download = Download::new(Arc::new(torrent), [1u8; 20]);
+        download.add_web_seeds(&[format!("{url}/file")]);
+        tokio::time::timeout(Duration::from_secs(5), download.wait_complete())
+            .await
+            .unwrap();
+        assert_eq!(download.data().unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_failing_seed() {
+        let data = b"hello world".to_vec();
+        let torrent = Torrent::with_files(&data, 4, vec![]);
+        // The seed serves other content
+        let (url, _) = serve(
+            HashMap::from([("/file".to_string(), b"HELLO WORLD".to_vec())]),
+            0,
+            true,
+        )
+        .await;
+
+        let download = Download::new(Arc::new(torrent), [1u8; 20]);
+        download.add_web_seeds(&[format!("{url}/file")]);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!download.is_complete());
+        assert!(download.bitfield().is_empty());
+        download.stop();
+    }
+
+    #[tokio::test]
+    async fn test_http_seed() {
+        let data = b"pieces served by index".to_vec();
+        let torrent = Torrent::with_files(&data, 8, vec![]);
+        let query: String = torrent
+            .raw_info_hash()
+            .iter()
+            .map(|b| format!("%{b:02x}"))
+            .collect();
+        let served = data
+            .chunks(8)
+            .enumerate()
+            .map(|(i, piece)| {
+                (
+                    format!("/seed.php?info_hash={query}&piece={i}"),
+                    piece.to_vec(),
+                )
+            })
+            .collect();
+        // The seed is busy at first, and asks to come back in a second
+        let (url, _) = serve(served, 1, true).await;
+
+        let download = Download::new(Arc::new(torrent), [1u8; 20]);
+        let started = std::time::Instant::now();
+        download.add_http_seeds(&[format!("{url}/seed.php")]);
+        tokio::time::timeout(Duration::from_secs(5), download.wait_complete())
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(download.data().unwrap(), data);
+    }
+}